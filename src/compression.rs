@@ -0,0 +1,375 @@
+//! Functions relating to compressed request and response bodies. Request bodies are decoded according to
+//! the `Content-Encoding` header of the request (gzip, deflate and brotli are supported), so they can be
+//! matched against the interactions. Requests with any other encoding are rejected with a 415 Unsupported
+//! Media Type, and bodies that decode to more than the maximum size with a 413 Payload Too Large. Response
+//! bodies can optionally be compressed with the encoding the request prefers in its `Accept-Encoding` header.
+
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+
+use flate2::Compression;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use hyper::{Body, Response as HyperResponse};
+use hyper::body::Bytes;
+use maplit::hashmap;
+use pact_models::bodies::OptionalBody;
+use pact_models::content_types::JSON;
+use pact_models::v4::http_parts::HttpResponse;
+use serde_json::json;
+use tracing::{debug, warn};
+
+use crate::negotiation;
+
+/// Content encodings that request bodies can be decoded from
+pub const SUPPORTED_ENCODINGS: &str = "gzip, deflate, br";
+/// Default minimum size of a response body (in bytes) that is compressed
+pub const DEFAULT_COMPRESSION_MIN_SIZE: usize = 1024;
+/// Default maximum size of a decoded request body (in bytes)
+pub const DEFAULT_MAX_DECODED_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+// Encodings that responses can be compressed with, in the order they are preferred when the request
+// accepts more than one with the same quality
+const RESPONSE_ENCODINGS: [&str; 3] = ["br", "gzip", "deflate"];
+
+/// Error decoding a request body
+#[derive(Debug)]
+pub enum DecodeError {
+  /// The body has a content encoding that is not supported
+  Unsupported(String),
+  /// The body could not be decoded with its content encoding
+  Invalid(String, std::io::Error),
+  /// The decoded body is larger than the maximum size (in bytes)
+  TooLarge(String, usize)
+}
+
+impl Display for DecodeError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      DecodeError::Unsupported(encoding) => write!(f, "Content encoding '{}' is not supported, supported encodings are {}",
+        encoding, SUPPORTED_ENCODINGS),
+      DecodeError::Invalid(encoding, err) => write!(f, "Failed to decode the request body with content encoding '{}': {}",
+        encoding, err),
+      DecodeError::TooLarge(encoding, max_size) => write!(f,
+        "The request body decoded with content encoding '{}' is larger than the maximum size of {} bytes", encoding, max_size)
+    }
+  }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes the request body according to the `Content-Encoding` header. Encodings are removed in the reverse
+/// order they were applied in, and decoding stops with an error once the body is larger than the maximum size
+/// (in bytes). If the body was decoded, the `Content-Encoding` and `Content-Length` headers are removed, as they
+/// no longer describe the body.
+pub fn decode_request_body(headers: &mut HeaderMap, body: Bytes, max_size: usize) -> Result<Bytes, DecodeError> {
+  let encodings = headers.get_all(header::CONTENT_ENCODING).iter()
+    .map(|value| value.to_str().map(|value| value.to_string())
+      .map_err(|_| DecodeError::Unsupported(String::from_utf8_lossy(value.as_bytes()).to_string())))
+    .collect::<Result<Vec<_>, _>>()?
+    .iter()
+    .flat_map(|value| value.split(',').map(|encoding| encoding.trim().to_lowercase()).collect::<Vec<_>>())
+    .filter(|encoding| !encoding.is_empty() && encoding != "identity")
+    .collect::<Vec<_>>();
+  if encodings.is_empty() {
+    return Ok(body);
+  }
+
+  let mut decoded = body;
+  for encoding in encodings.iter().rev() {
+    if !decoded.is_empty() {
+      decoded = decode(encoding, &decoded, max_size)?;
+    }
+  }
+  headers.remove(header::CONTENT_ENCODING);
+  headers.remove(header::CONTENT_LENGTH);
+  Ok(decoded)
+}
+
+fn decode(encoding: &str, body: &[u8], max_size: usize) -> Result<Bytes, DecodeError> {
+  // Only one byte more than the maximum size is read, so compressed bodies can not expand without limit
+  let limit = max_size as u64 + 1;
+  let mut buffer = vec![];
+  let result = match encoding {
+    "gzip" | "x-gzip" => GzDecoder::new(body).take(limit).read_to_end(&mut buffer),
+    // The deflate encoding is the zlib format, but some clients send raw deflate data
+    "deflate" => ZlibDecoder::new(body).take(limit).read_to_end(&mut buffer)
+      .or_else(|_| {
+        buffer.clear();
+        DeflateDecoder::new(body).take(limit).read_to_end(&mut buffer)
+      }),
+    "br" => brotli::Decompressor::new(body, 4096).take(limit).read_to_end(&mut buffer),
+    _ => return Err(DecodeError::Unsupported(encoding.to_string()))
+  };
+  match result {
+    Ok(_) if buffer.len() > max_size => Err(DecodeError::TooLarge(encoding.to_string(), max_size)),
+    Ok(_) => Ok(Bytes::from(buffer)),
+    Err(err) => Err(DecodeError::Invalid(encoding.to_string(), err))
+  }
+}
+
+/// Compression of response bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseCompression {
+  min_size: usize
+}
+
+impl ResponseCompression {
+  /// Compression of response bodies that are at least the minimum size (in bytes)
+  pub fn new(min_size: usize) -> ResponseCompression {
+    ResponseCompression {
+      min_size
+    }
+  }
+
+  /// Compresses the response body with the encoding the request prefers in its `Accept-Encoding` header.
+  /// Responses that already have a `Content-Encoding` header, or with a body smaller than the minimum size,
+  /// are not changed.
+  pub async fn compress(&self, accept_encoding: Option<&str>, response: HyperResponse<Body>) -> HyperResponse<Body> {
+    let encoding = match accept_encoding.and_then(preferred_encoding) {
+      Some(encoding) => encoding,
+      None => return response
+    };
+    if response.headers().contains_key(header::CONTENT_ENCODING) {
+      return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+      Ok(body) => body,
+      Err(err) => {
+        warn!("Failed to read the response body to compress it: {}", err);
+        return HyperResponse::from_parts(parts, Body::empty());
+      }
+    };
+    if body.len() < self.min_size.max(1) {
+      return HyperResponse::from_parts(parts, Body::from(body));
+    }
+
+    match encode(encoding, &body) {
+      Ok(compressed) => {
+        debug!("Compressed the response body from {} to {} bytes with {}", body.len(), compressed.len(), encoding);
+        parts.headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        parts.headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+        parts.headers.remove(header::CONTENT_LENGTH);
+        HyperResponse::from_parts(parts, Body::from(compressed))
+      },
+      Err(err) => {
+        warn!("Failed to compress the response body with {}, sending it uncompressed: {}", encoding, err);
+        HyperResponse::from_parts(parts, Body::from(body))
+      }
+    }
+  }
+}
+
+impl Default for ResponseCompression {
+  fn default() -> Self {
+    ResponseCompression::new(DEFAULT_COMPRESSION_MIN_SIZE)
+  }
+}
+
+// Returns the supported encoding with the highest quality in the Accept-Encoding header, if any
+fn preferred_encoding(accept_encoding: &str) -> Option<&'static str> {
+  RESPONSE_ENCODINGS.iter()
+    .map(|encoding| (*encoding, negotiation::quality(accept_encoding, |range| {
+      if range == *encoding || (*encoding == "gzip" && range == "x-gzip") {
+        Some(1)
+      } else if range == "*" {
+        Some(0)
+      } else {
+        None
+      }
+    })))
+    .filter(|(_, quality)| *quality > 0)
+    .fold(None, |best: Option<(&str, u32)>, (encoding, quality)| match best {
+      Some((_, best_quality)) if best_quality >= quality => best,
+      _ => Some((encoding, quality))
+    })
+    .map(|(encoding, _)| encoding)
+}
+
+fn encode(encoding: &str, body: &[u8]) -> std::io::Result<Vec<u8>> {
+  match encoding {
+    "gzip" => {
+      let mut encoder = GzEncoder::new(vec![], Compression::default());
+      encoder.write_all(body)?;
+      encoder.finish()
+    },
+    "deflate" => {
+      let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+      encoder.write_all(body)?;
+      encoder.finish()
+    },
+    _ => {
+      let mut buffer = vec![];
+      {
+        let mut encoder = brotli::CompressorWriter::new(&mut buffer, 4096, 5, 22);
+        encoder.write_all(body)?;
+      }
+      Ok(buffer)
+    }
+  }
+}
+
+/// Response for a request body that could not be decoded. Unsupported encodings get a 415 Unsupported Media
+/// Type listing the supported encodings, bodies that are not valid for their encoding a 400 Bad Request, and
+/// bodies that are too large once decoded a 413 Payload Too Large.
+pub fn decode_error_response(error: &DecodeError) -> HttpResponse {
+  let mut headers = hashmap! { "Content-Type".to_string() => vec!["application/json".to_string()] };
+  let status = match error {
+    DecodeError::Unsupported(_) => {
+      headers.insert("Accept-Encoding".to_string(), vec![SUPPORTED_ENCODINGS.to_string()]);
+      StatusCode::UNSUPPORTED_MEDIA_TYPE
+    },
+    DecodeError::Invalid(_, _) => StatusCode::BAD_REQUEST,
+    DecodeError::TooLarge(_, _) => StatusCode::PAYLOAD_TOO_LARGE
+  };
+  HttpResponse {
+    status: status.as_u16(),
+    headers: Some(headers),
+    body: OptionalBody::Present(json!({ "error": error.to_string() }).to_string().into(), Some(JSON.clone()), None),
+    .. HttpResponse::default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use flate2::write::DeflateEncoder;
+  use pact_models::http_parts::HttpPart;
+
+  use super::*;
+
+  const BODY: &str = "{\"id\":1,\"name\":\"an order\"}";
+  const MAX: usize = DEFAULT_MAX_DECODED_BODY_SIZE;
+
+  fn encoded_headers(encoding: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_ENCODING, HeaderValue::from_str(encoding).unwrap());
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("10"));
+    headers
+  }
+
+  fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+  }
+
+  fn brotli(body: &[u8]) -> Vec<u8> {
+    encode("br", body).unwrap()
+  }
+
+  #[test]
+  fn bodies_without_a_content_encoding_are_not_changed() {
+    let mut headers = HeaderMap::new();
+    expect!(decode_request_body(&mut headers, Bytes::from(BODY), MAX).unwrap()).to(be_equal_to(Bytes::from(BODY)));
+    let mut headers = encoded_headers("identity");
+    expect!(decode_request_body(&mut headers, Bytes::from(BODY), MAX).unwrap()).to(be_equal_to(Bytes::from(BODY)));
+    expect!(headers.contains_key(header::CONTENT_LENGTH)).to(be_true());
+  }
+
+  #[test]
+  fn decodes_gzip_deflate_and_brotli_bodies() {
+    let mut headers = encoded_headers("gzip");
+    expect!(decode_request_body(&mut headers, Bytes::from(gzip(BODY.as_bytes())), MAX).unwrap()).to(be_equal_to(Bytes::from(BODY)));
+    expect!(headers.contains_key(header::CONTENT_ENCODING)).to(be_false());
+    expect!(headers.contains_key(header::CONTENT_LENGTH)).to(be_false());
+
+    let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+    zlib.write_all(BODY.as_bytes()).unwrap();
+    expect!(decode_request_body(&mut encoded_headers("deflate"), Bytes::from(zlib.finish().unwrap()), MAX).unwrap())
+      .to(be_equal_to(Bytes::from(BODY)));
+    let mut deflate = DeflateEncoder::new(vec![], Compression::default());
+    deflate.write_all(BODY.as_bytes()).unwrap();
+    expect!(decode_request_body(&mut encoded_headers("Deflate"), Bytes::from(deflate.finish().unwrap()), MAX).unwrap())
+      .to(be_equal_to(Bytes::from(BODY)));
+
+    expect!(decode_request_body(&mut encoded_headers("br"), Bytes::from(brotli(BODY.as_bytes())), MAX).unwrap())
+      .to(be_equal_to(Bytes::from(BODY)));
+  }
+
+  #[test]
+  fn multiple_encodings_are_removed_in_reverse_order() {
+    let body = brotli(&gzip(BODY.as_bytes()));
+    expect!(decode_request_body(&mut encoded_headers("gzip, br"), Bytes::from(body), MAX).unwrap()).to(be_equal_to(Bytes::from(BODY)));
+  }
+
+  #[test]
+  fn unsupported_and_invalid_encodings_are_errors() {
+    let result = decode_request_body(&mut encoded_headers("zstd"), Bytes::from(BODY), MAX);
+    expect!(matches!(result, Err(DecodeError::Unsupported(ref encoding)) if encoding == "zstd")).to(be_true());
+    let response = decode_error_response(&result.unwrap_err());
+    expect!(response.status).to(be_equal_to(415));
+    expect!(response.lookup_header_value("Accept-Encoding")).to(be_some().value(SUPPORTED_ENCODINGS));
+
+    let result = decode_request_body(&mut encoded_headers("gzip"), Bytes::from(BODY), MAX);
+    expect!(matches!(result, Err(DecodeError::Invalid(_, _)))).to(be_true());
+    expect!(decode_error_response(&result.unwrap_err()).status).to(be_equal_to(400));
+  }
+
+  #[test]
+  fn bodies_larger_than_the_maximum_size_once_decoded_are_errors() {
+    let bomb = vec![0; 1024 * 1024];
+    expect!(decode_request_body(&mut encoded_headers("gzip"), Bytes::from(gzip(&bomb)), bomb.len()).map(|body| body.len()))
+      .to(be_ok().value(bomb.len()));
+
+    for (encoding, body) in [("gzip", gzip(&bomb)), ("br", brotli(&bomb))] {
+      let result = decode_request_body(&mut encoded_headers(encoding), Bytes::from(body), 1000);
+      expect!(matches!(result, Err(DecodeError::TooLarge(_, 1000)))).to(be_true());
+      expect!(decode_error_response(&result.unwrap_err()).status).to(be_equal_to(413));
+    }
+  }
+  fn hyper_response(body: &str, headers: &[(&str, &str)]) -> HyperResponse<Body> {
+    let mut builder = HyperResponse::builder();
+    for (name, value) in headers {
+      builder = builder.header(*name, *value);
+    }
+    builder.body(Body::from(body.to_string())).unwrap()
+  }
+
+  #[test]
+  fn preferred_encoding_honours_quality_values() {
+    expect!(preferred_encoding("gzip, deflate, br")).to(be_some().value("br"));
+    expect!(preferred_encoding("gzip;q=1.0, br;q=0.5")).to(be_some().value("gzip"));
+    expect!(preferred_encoding("deflate")).to(be_some().value("deflate"));
+    expect!(preferred_encoding("x-gzip")).to(be_some().value("gzip"));
+    expect!(preferred_encoding("*, br;q=0")).to(be_some().value("gzip"));
+    expect!(preferred_encoding("identity")).to(be_none());
+    expect!(preferred_encoding("gzip;q=0")).to(be_none());
+  }
+
+  #[tokio::test]
+  async fn compresses_responses_above_the_minimum_size() {
+    let compression = ResponseCompression::new(10);
+    let response = compression.compress(Some("gzip"), hyper_response(BODY, &[("Content-Length", "26")])).await;
+    expect!(response.headers().get(header::CONTENT_ENCODING).unwrap().to_str().unwrap()).to(be_equal_to("gzip"));
+    expect!(response.headers().get(header::VARY).unwrap().to_str().unwrap()).to(be_equal_to("Accept-Encoding"));
+    expect!(response.headers().contains_key(header::CONTENT_LENGTH)).to(be_false());
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    expect!(decode_request_body(&mut encoded_headers("gzip"), body, MAX).unwrap()).to(be_equal_to(Bytes::from(BODY)));
+
+    let response = compression.compress(Some("br"), hyper_response(BODY, &[])).await;
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    expect!(decode_request_body(&mut encoded_headers("br"), body, MAX).unwrap()).to(be_equal_to(Bytes::from(BODY)));
+  }
+
+  #[tokio::test]
+  async fn does_not_compress_small_or_already_encoded_responses() {
+    let compression = ResponseCompression::new(100);
+    let response = compression.compress(Some("gzip"), hyper_response(BODY, &[])).await;
+    expect!(response.headers().contains_key(header::CONTENT_ENCODING)).to(be_false());
+    expect!(hyper::body::to_bytes(response.into_body()).await.unwrap()).to(be_equal_to(Bytes::from(BODY)));
+
+    let compression = ResponseCompression::new(0);
+    let response = compression.compress(Some("gzip"), hyper_response(BODY, &[("Content-Encoding", "identity")])).await;
+    expect!(response.headers().get(header::CONTENT_ENCODING).unwrap().to_str().unwrap()).to(be_equal_to("identity"));
+    expect!(hyper::body::to_bytes(response.into_body()).await.unwrap()).to(be_equal_to(Bytes::from(BODY)));
+
+    let response = compression.compress(None, hyper_response(BODY, &[])).await;
+    expect!(response.headers().contains_key(header::CONTENT_ENCODING)).to(be_false());
+    let response = compression.compress(Some("gzip"), hyper_response("", &[])).await;
+    expect!(response.headers().contains_key(header::CONTENT_ENCODING)).to(be_false());
+  }
+}
@@ -0,0 +1,320 @@
+//! Functions relating to the CORS (cross-origin resource sharing) policy. By default, responses get an
+//! `Access-Control-Allow-Origin: *` header (unless the interaction sets one). The policy can restrict the
+//! allowed origins to a list or regex (reflecting the origin of the request), allow credentials, expose
+//! headers and set the max age of pre-flight responses. Un-matched OPTIONS requests are answered with a
+//! pre-flight response if enabled, reflecting the requested method and headers.
+
+use pact_models::http_parts::HttpPart;
+use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+use regex::Regex;
+
+/// Methods allowed by pre-flight responses when the request does not ask for one
+pub const DEFAULT_ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, DELETE, CONNECT, OPTIONS, TRACE, PATCH";
+
+/// CORS policy applied to responses
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+  preflight: bool,
+  referer: bool,
+  headers: bool,
+  origins: Vec<String>,
+  origin_regex: Option<Regex>,
+  credentials: bool,
+  exposed_headers: Vec<String>,
+  max_age: Option<u64>
+}
+
+impl CorsPolicy {
+  /// Policy allowing any origin. If `preflight` is set, un-matched OPTIONS requests get a pre-flight
+  /// response. If `referer` is set, the Referer header of the request is used as its origin.
+  pub fn new(preflight: bool, referer: bool) -> CorsPolicy {
+    CorsPolicy {
+      preflight,
+      referer,
+      headers: true,
+      origins: vec![],
+      origin_regex: None,
+      credentials: false,
+      exposed_headers: vec![],
+      max_age: None
+    }
+  }
+
+  /// Only allows the given origins, or the ones that match the regex. The regex must match the whole origin.
+  /// If neither is given, any origin is allowed.
+  pub fn with_origins(self, origins: Vec<String>, origin_regex: Option<Regex>) -> CorsPolicy {
+    CorsPolicy {
+      origins,
+      origin_regex: origin_regex.map(|regex| Regex::new(&format!("^(?:{})$", regex.as_str()))
+        .expect("an anchored valid regex is also valid")),
+      .. self
+    }
+  }
+
+  /// Allows requests with credentials (cookies or authorization headers)
+  pub fn with_credentials(self, credentials: bool) -> CorsPolicy {
+    CorsPolicy {
+      credentials,
+      .. self
+    }
+  }
+
+  /// Sets the response headers that browsers expose to scripts
+  pub fn with_exposed_headers(self, exposed_headers: Vec<String>) -> CorsPolicy {
+    CorsPolicy {
+      exposed_headers,
+      .. self
+    }
+  }
+
+  /// Sets the number of seconds browsers can cache pre-flight responses for
+  pub fn with_max_age(self, max_age: Option<u64>) -> CorsPolicy {
+    CorsPolicy {
+      max_age,
+      .. self
+    }
+  }
+
+  /// Stops adding CORS headers to responses
+  pub fn without_headers(self) -> CorsPolicy {
+    CorsPolicy {
+      headers: false,
+      preflight: false,
+      .. self
+    }
+  }
+
+  /// Returns the pre-flight response for an un-matched OPTIONS request, if pre-flight responses are enabled.
+  /// The requested method and headers are reflected, and origins that are not allowed get a response
+  /// without any CORS headers.
+  pub fn preflight_response(&self, request: &HttpRequest) -> Option<HttpResponse> {
+    if !self.preflight || request.method.to_uppercase() != "OPTIONS" {
+      return None;
+    }
+
+    let mut response = HttpResponse::default();
+    if let Some(origin) = self.allowed_origin(request) {
+      let request_method = request.lookup_header_value("Access-Control-Request-Method");
+      let request_headers = request.lookup_header_value("Access-Control-Request-Headers");
+      add_header(&mut response, "Access-Control-Allow-Methods",
+        request_method.as_deref().unwrap_or(DEFAULT_ALLOWED_METHODS));
+      add_header(&mut response, "Access-Control-Allow-Headers", request_headers.as_deref().unwrap_or("*"));
+      if let Some(max_age) = self.max_age {
+        add_header(&mut response, "Access-Control-Max-Age", &max_age.to_string());
+      }
+      self.add_origin_headers(&mut response, origin);
+      if request_method.is_some() {
+        add_header(&mut response, "Vary", "Access-Control-Request-Method");
+      }
+      if request_headers.is_some() {
+        add_header(&mut response, "Vary", "Access-Control-Request-Headers");
+      }
+    }
+    Some(response)
+  }
+
+  /// Adds the CORS headers to the response, unless they are disabled or the response already has an
+  /// `Access-Control-Allow-Origin` header
+  pub fn add_headers(&self, request: &HttpRequest, response: &mut HttpResponse) {
+    if !self.headers || response.has_header("Access-Control-Allow-Origin") {
+      return;
+    }
+
+    if let Some(origin) = self.allowed_origin(request) {
+      if !self.exposed_headers.is_empty() {
+        add_header(response, "Access-Control-Expose-Headers", &self.exposed_headers.join(", "));
+      }
+      self.add_origin_headers(response, origin);
+    }
+  }
+
+  fn add_origin_headers(&self, response: &mut HttpResponse, origin: String) {
+    let reflected = origin != "*";
+    add_header(response, "Access-Control-Allow-Origin", &origin);
+    if self.credentials {
+      add_header(response, "Access-Control-Allow-Credentials", "true");
+    }
+    if reflected {
+      add_header(response, "Vary", "Origin");
+    }
+  }
+
+  // Returns the value of the Access-Control-Allow-Origin header for the request, if its origin is allowed.
+  // Browsers reject a wildcard origin for requests with credentials, so the origin is reflected instead,
+  // and requests without an origin to reflect get no CORS headers.
+  fn allowed_origin(&self, request: &HttpRequest) -> Option<String> {
+    let origin = request.lookup_header_value(if self.referer { "Referer" } else { "Origin" });
+    if self.origins.is_empty() && self.origin_regex.is_none() {
+      if self.credentials {
+        origin
+      } else if self.referer {
+        Some(origin.unwrap_or_else(|| "*".to_string()))
+      } else {
+        Some("*".to_string())
+      }
+    } else {
+      origin.filter(|origin| {
+        self.origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin))
+          || self.origin_regex.as_ref().map(|regex| regex.is_match(origin)).unwrap_or(false)
+      })
+    }
+  }
+}
+
+impl Default for CorsPolicy {
+  fn default() -> Self {
+    CorsPolicy::new(false, false)
+  }
+}
+
+// Adds a header value, keeping any values the header already has
+fn add_header(response: &mut HttpResponse, name: &str, value: &str) {
+  let headers = response.headers.get_or_insert_with(Default::default);
+  match headers.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case(name)) {
+    Some((_, values)) => values.push(value.to_string()),
+    None => {
+      headers.insert(name.to_string(), vec![value.to_string()]);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use maplit::hashmap;
+
+  use super::*;
+
+  fn request(method: &str, headers: &[(&str, &str)]) -> HttpRequest {
+    HttpRequest {
+      method: method.to_string(),
+      headers: Some(headers.iter()
+        .map(|(name, value)| (name.to_string(), vec![value.to_string()]))
+        .collect()),
+      .. HttpRequest::default()
+    }
+  }
+
+  fn header(response: &HttpResponse, name: &str) -> Option<String> {
+    response.lookup_header_value(name)
+  }
+
+  #[test]
+  fn by_default_any_origin_is_allowed() {
+    let policy = CorsPolicy::default();
+    let mut response = HttpResponse::default();
+    policy.add_headers(&request("GET", &[("Origin", "https://app.example.com")]), &mut response);
+    expect!(header(&response, "Access-Control-Allow-Origin")).to(be_some().value("*"));
+    expect!(header(&response, "Vary")).to(be_none());
+    expect!(policy.preflight_response(&request("OPTIONS", &[]))).to(be_none());
+  }
+
+  #[test]
+  fn does_not_replace_the_origin_header_of_the_interaction() {
+    let policy = CorsPolicy::default();
+    let mut response = HttpResponse {
+      headers: Some(hashmap! { "Access-Control-Allow-Origin".to_string() => vec!["dodgy.com".to_string()] }),
+      .. HttpResponse::default()
+    };
+    policy.add_headers(&request("GET", &[]), &mut response);
+    expect!(response.headers.unwrap()).to(be_equal_to(hashmap! {
+      "Access-Control-Allow-Origin".to_string() => vec!["dodgy.com".to_string()]
+    }));
+  }
+
+  #[test]
+  fn headers_can_be_disabled() {
+    let policy = CorsPolicy::new(true, false).without_headers();
+    let mut response = HttpResponse::default();
+    policy.add_headers(&request("GET", &[]), &mut response);
+    expect!(response.headers).to(be_none());
+    expect!(policy.preflight_response(&request("OPTIONS", &[]))).to(be_none());
+  }
+
+  #[test]
+  fn credentials_reflect_the_origin() {
+    let policy = CorsPolicy::default()
+      .with_credentials(true)
+      .with_exposed_headers(vec!["X-Total-Count".to_string(), "Link".to_string()]);
+    let mut response = HttpResponse {
+      headers: Some(hashmap! { "vary".to_string() => vec!["Accept".to_string()] }),
+      .. HttpResponse::default()
+    };
+    policy.add_headers(&request("GET", &[("Origin", "https://app.example.com")]), &mut response);
+    expect!(header(&response, "Access-Control-Allow-Origin")).to(be_some().value("https://app.example.com"));
+    expect!(header(&response, "Access-Control-Allow-Credentials")).to(be_some().value("true"));
+    expect!(header(&response, "Access-Control-Expose-Headers")).to(be_some().value("X-Total-Count, Link"));
+    expect!(header(&response, "Vary")).to(be_some().value("Accept, Origin"));
+
+    let mut response = HttpResponse::default();
+    policy.add_headers(&request("GET", &[]), &mut response);
+    expect!(response.headers).to(be_none());
+    let response = CorsPolicy::new(true, false).with_credentials(true)
+      .preflight_response(&request("OPTIONS", &[])).unwrap();
+    expect!(response.headers).to(be_none());
+  }
+
+  #[test]
+  fn only_allowed_origins_get_cors_headers() {
+    let policy = CorsPolicy::default()
+      .with_origins(vec!["https://app.example.com".to_string()], Some(Regex::new(r"^https://.*\.test\.com$").unwrap()));
+    let origin = |origin: &str| {
+      let mut response = HttpResponse::default();
+      policy.add_headers(&request("GET", &[("Origin", origin)]), &mut response);
+      header(&response, "Access-Control-Allow-Origin")
+    };
+    expect!(origin("https://app.example.com")).to(be_some().value("https://app.example.com"));
+    expect!(origin("https://a.test.com")).to(be_some().value("https://a.test.com"));
+    expect!(origin("https://evil.com")).to(be_none());
+    expect!(origin("https://a.test.com.evil.com")).to(be_none());
+
+    let policy = CorsPolicy::default()
+      .with_origins(vec![], Some(Regex::new(r"https://[a-z]+\.example\.com").unwrap()));
+    let mut response = HttpResponse::default();
+    policy.add_headers(&request("GET", &[("Origin", "https://app.example.com.evil.com")]), &mut response);
+    expect!(response.headers.as_ref()).to(be_none());
+    policy.add_headers(&request("GET", &[("Origin", "https://app.example.com")]), &mut response);
+    expect!(header(&response, "Access-Control-Allow-Origin")).to(be_some().value("https://app.example.com"));
+
+    let mut response = HttpResponse::default();
+    policy.add_headers(&request("GET", &[]), &mut response);
+    expect!(response.headers).to(be_none());
+  }
+
+  #[test]
+  fn preflight_responses_reflect_the_requested_method_and_headers() {
+    let policy = CorsPolicy::new(true, false).with_max_age(Some(600));
+    let response = policy.preflight_response(&request("OPTIONS", &[
+      ("Origin", "https://app.example.com"),
+      ("Access-Control-Request-Method", "PUT"),
+      ("Access-Control-Request-Headers", "content-type, x-token")
+    ])).unwrap();
+    expect!(response.status).to(be_equal_to(200));
+    expect!(header(&response, "Access-Control-Allow-Origin")).to(be_some().value("*"));
+    expect!(header(&response, "Access-Control-Allow-Methods")).to(be_some().value("PUT"));
+    expect!(header(&response, "Access-Control-Allow-Headers")).to(be_some().value("content-type, x-token"));
+    expect!(header(&response, "Access-Control-Max-Age")).to(be_some().value("600"));
+    expect!(header(&response, "Vary")).to(be_some().value("Access-Control-Request-Method, Access-Control-Request-Headers"));
+
+    let response = policy.preflight_response(&request("OPTIONS", &[])).unwrap();
+    expect!(header(&response, "Access-Control-Allow-Methods")).to(be_some().value(DEFAULT_ALLOWED_METHODS));
+    expect!(header(&response, "Access-Control-Allow-Headers")).to(be_some().value("*"));
+    expect!(policy.preflight_response(&request("GET", &[]))).to(be_none());
+  }
+
+  #[test]
+  fn preflight_responses_for_origins_that_are_not_allowed_have_no_cors_headers() {
+    let policy = CorsPolicy::new(true, false).with_origins(vec!["https://app.example.com".to_string()], None);
+    let response = policy.preflight_response(&request("OPTIONS", &[("Origin", "https://evil.com")])).unwrap();
+    expect!(response.headers).to(be_none());
+  }
+
+  #[test]
+  fn the_referer_can_be_used_as_the_origin() {
+    let policy = CorsPolicy::new(true, true);
+    let response = policy.preflight_response(&request("OPTIONS", &[("Referer", "https://app.example.com/page")])).unwrap();
+    expect!(header(&response, "Access-Control-Allow-Origin")).to(be_some().value("https://app.example.com/page"));
+    let response = policy.preflight_response(&request("OPTIONS", &[])).unwrap();
+    expect!(header(&response, "Access-Control-Allow-Origin")).to(be_some().value("*"));
+  }
+}
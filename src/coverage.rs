@@ -0,0 +1,391 @@
+//! Interaction coverage report, recording which of the HTTP interactions have been used to respond to requests
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::Context;
+use http::StatusCode;
+use hyper::{Body, Response as HyperResponse};
+use pact_models::prelude::v4::*;
+use pact_models::v4::interaction::V4Interaction;
+use pact_models::v4::V4InteractionType;
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::PactSource;
+use crate::provider_states::ProviderStateFilter;
+
+/// Path of the admin endpoint used to fetch the coverage report
+pub const COVERAGE_PATH: &str = "/__admin/coverage";
+
+/// Format of the coverage report
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoverageFormat {
+  /// JSON document
+  Json,
+  /// JUnit style XML, with a failed test case for each interaction that was not used
+  JUnit
+}
+
+impl CoverageFormat {
+  /// Format to use for the report file, based on the file extension
+  pub fn for_file(path: &Path) -> CoverageFormat {
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some(ext) if ext.eq_ignore_ascii_case("xml") => CoverageFormat::JUnit,
+      _ => CoverageFormat::Json
+    }
+  }
+}
+
+// Hit counts keyed by consumer, provider and the unique key of the interaction, then by the provider state
+// filter the interaction was served under (if any)
+type HitCounts = HashMap<(String, String, String), BTreeMap<Option<String>, usize>>;
+
+fn hit_counts() -> &'static Mutex<HitCounts> {
+  static HITS: OnceLock<Mutex<HitCounts>> = OnceLock::new();
+  HITS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn hit_key(pact: &V4Pact, interaction: &SynchronousHttp) -> (String, String, String) {
+  (pact.consumer.name.clone(), pact.provider.name.clone(), interaction.unique_key())
+}
+
+/// Records that the interaction was used to respond to a request, along with the provider state filter
+/// that was active for the request
+pub fn record_hit(pact: &V4Pact, interaction: &SynchronousHttp, provider_state: Option<&ProviderStateFilter>) {
+  let key = hit_key(pact, interaction);
+  let mut hits = hit_counts().lock().unwrap_or_else(|err| err.into_inner());
+  *hits.entry(key).or_default().entry(provider_state.map(|state| state.to_string())).or_default() += 1;
+}
+
+/// Coverage of a single interaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct InteractionCoverage {
+  /// Consumer name
+  pub consumer: String,
+  /// Provider name
+  pub provider: String,
+  /// Interaction description
+  pub description: String,
+  /// Provider states of the interaction
+  pub provider_states: Vec<String>,
+  /// Number of times the interaction was used to respond to a request
+  pub hits: usize,
+  /// Number of times the interaction was used for each provider state filter that was active for the
+  /// request (`None` if there was no filter)
+  pub hits_by_state: Vec<(Option<String>, usize)>
+}
+
+/// Builds the coverage of all the HTTP interactions in the pacts
+pub fn coverage(sources: &[(V4Pact, PactSource)]) -> Vec<InteractionCoverage> {
+  let hits = hit_counts().lock().unwrap_or_else(|err| err.into_inner());
+  sources.iter()
+    .flat_map(|(pact, _)| pact.filter_interactions(V4InteractionType::Synchronous_HTTP)
+      .iter()
+      .filter_map(|i| i.as_v4_http())
+      .map(|interaction| {
+        let hits_by_state = hits.get(&hit_key(pact, &interaction))
+          .map(|hits| hits.iter().map(|(state, hits)| (state.clone(), *hits)).collect::<Vec<_>>())
+          .unwrap_or_default();
+        InteractionCoverage {
+          consumer: pact.consumer.name.clone(),
+          provider: pact.provider.name.clone(),
+          description: interaction.description.clone(),
+          provider_states: interaction.provider_states.iter().map(|state| state.name.clone()).collect(),
+          hits: hits_by_state.iter().map(|(_, hits)| hits).sum(),
+          hits_by_state
+        }
+      })
+      .collect::<Vec<_>>())
+    .collect()
+}
+
+/// Generates the coverage report as a JSON document
+pub fn json_report(coverage: &[InteractionCoverage]) -> Value {
+  let covered = coverage.iter().filter(|c| c.hits > 0).count();
+  json!({
+    "interactionCount": coverage.len(),
+    "coveredCount": covered,
+    "coverage": if coverage.is_empty() { 1.0 } else { covered as f64 / coverage.len() as f64 },
+    "interactions": coverage.iter().map(|c| json!({
+      "consumer": c.consumer,
+      "provider": c.provider,
+      "description": c.description,
+      "providerStates": c.provider_states,
+      "hits": c.hits,
+      "hitsByProviderState": c.hits_by_state.iter().map(|(state, hits)| json!({
+        "providerState": state,
+        "hits": hits
+      })).collect::<Vec<_>>(),
+      "covered": c.hits > 0
+    })).collect::<Vec<_>>()
+  })
+}
+
+fn escape_xml(value: &str) -> String {
+  value.chars().fold(String::with_capacity(value.len()), |mut s, ch| {
+    match ch {
+      '&' => s.push_str("&amp;"),
+      '<' => s.push_str("&lt;"),
+      '>' => s.push_str("&gt;"),
+      '"' => s.push_str("&quot;"),
+      '\'' => s.push_str("&apos;"),
+      _ => s.push(ch)
+    }
+    s
+  })
+}
+
+/// Generates the coverage report as JUnit style XML. There is a test suite for each consumer and provider,
+/// and a test case for each interaction, which fails if the interaction was never used.
+pub fn junit_report(coverage: &[InteractionCoverage]) -> String {
+  let mut suites: Vec<((&str, &str), Vec<&InteractionCoverage>)> = vec![];
+  for c in coverage {
+    let key = (c.consumer.as_str(), c.provider.as_str());
+    match suites.iter_mut().find(|(suite, _)| *suite == key) {
+      Some((_, cases)) => cases.push(c),
+      None => suites.push((key, vec![c]))
+    }
+  }
+
+  let failures = coverage.iter().filter(|c| c.hits == 0).count();
+  let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  xml.push_str(&format!("<testsuites name=\"Pact interaction coverage\" tests=\"{}\" failures=\"{}\">\n",
+    coverage.len(), failures));
+  for ((consumer, provider), cases) in suites {
+    let name = escape_xml(&format!("{} - {}", consumer, provider));
+    xml.push_str(&format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">\n", name, cases.len(),
+      cases.iter().filter(|c| c.hits == 0).count()));
+    for case in cases {
+      xml.push_str(&format!("    <testcase classname=\"{}\" name=\"{}\">\n", name, escape_xml(&case.description)));
+      if case.hits == 0 {
+        xml.push_str("      <failure message=\"Interaction was never used to respond to a request\"/>\n");
+      }
+      let states = if case.provider_states.is_empty() {
+        String::new()
+      } else {
+        format!(", provider states: {}", case.provider_states.join(", "))
+      };
+      let served_states = if case.hits_by_state.iter().any(|(state, _)| state.is_some()) {
+        format!(", hits by provider state: {}", case.hits_by_state.iter()
+          .map(|(state, hits)| format!("{}: {}", state.as_deref().unwrap_or("(none)"), hits))
+          .collect::<Vec<_>>()
+          .join(", "))
+      } else {
+        String::new()
+      };
+      xml.push_str(&format!("      <system-out>hits: {}{}{}</system-out>\n", case.hits, escape_xml(&states),
+        escape_xml(&served_states)));
+      xml.push_str("    </testcase>\n");
+    }
+    xml.push_str("  </testsuite>\n");
+  }
+  xml.push_str("</testsuites>\n");
+  xml
+}
+
+/// Writes the coverage report to the file, using the format based on the file extension
+pub fn write_report(path: &Path, sources: &[(V4Pact, PactSource)]) -> anyhow::Result<()> {
+  let coverage = coverage(sources);
+  let contents = match CoverageFormat::for_file(path) {
+    CoverageFormat::Json => format!("{:#}\n", json_report(&coverage)),
+    CoverageFormat::JUnit => junit_report(&coverage)
+  };
+  fs::write(path, contents).with_context(|| format!("Failed to write the coverage report '{}'", path.display()))?;
+  info!("Wrote the interaction coverage report to {}", path.display());
+  Ok(())
+}
+
+/// Handles a request to the coverage endpoint. The JUnit format is returned if the `format=junit` query
+/// parameter is provided, or XML is requested with the Accept header.
+pub fn handle_coverage_request(
+  query: Option<&str>,
+  accept: Option<&str>,
+  sources: &[(V4Pact, PactSource)]
+) -> Result<HyperResponse<Body>, http::Error> {
+  let junit = query.map(|q| q.split('&').any(|param| param == "format=junit")).unwrap_or(false) ||
+    accept.map(|accept| accept.contains("xml")).unwrap_or(false);
+  let coverage = coverage(sources);
+  if junit {
+    HyperResponse::builder()
+      .status(StatusCode::OK)
+      .header("Content-Type", "application/xml")
+      .body(Body::from(junit_report(&coverage)))
+  } else {
+    HyperResponse::builder()
+      .status(StatusCode::OK)
+      .header("Content-Type", "application/json")
+      .body(Body::from(json_report(&coverage).to_string()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use expectest::prelude::*;
+  use pact_models::prelude::*;
+  use pact_models::prelude::v4::*;
+  use regex::Regex;
+  use serde_json::json;
+
+  use crate::PactSource;
+
+  use super::*;
+
+  fn interaction(description: &str, state: Option<&str>) -> SynchronousHttp {
+    SynchronousHttp {
+      description: description.to_string(),
+      provider_states: state.map(|s| vec![ProviderState::default(s)]).unwrap_or_default(),
+      .. SynchronousHttp::default()
+    }
+  }
+
+  fn sources(consumer: &str) -> Vec<(V4Pact, PactSource)> {
+    vec![(V4Pact {
+      consumer: Consumer { name: consumer.to_string() },
+      provider: Provider { name: "coverage-provider".to_string() },
+      interactions: vec![
+        interaction("get an order", Some("an order exists")).boxed_v4(),
+        interaction("delete an order", None).boxed_v4()
+      ],
+      .. V4Pact::default()
+    }, PactSource::Unknown)]
+  }
+
+  #[test]
+  fn coverage_counts_the_hits_for_each_interaction() {
+    let sources = sources("coverage-consumer-counts");
+    let (pact, _) = &sources[0];
+    let get_order = pact.interactions[0].as_v4_http().unwrap();
+    record_hit(pact, &get_order, None);
+    record_hit(pact, &get_order, None);
+
+    let coverage = coverage(&sources);
+    expect!(coverage.iter().map(|c| (c.description.as_str(), c.hits)).collect::<Vec<_>>())
+      .to(be_equal_to(vec![("get an order", 2), ("delete an order", 0)]));
+    expect!(coverage[0].provider_states.clone()).to(be_equal_to(vec!["an order exists".to_string()]));
+  }
+
+  #[test]
+  fn coverage_counts_the_hits_for_each_provider_state_filter() {
+    let sources = sources("coverage-consumer-states");
+    let (pact, _) = &sources[0];
+    let get_order = pact.interactions[0].as_v4_http().unwrap();
+    let filter = ProviderStateFilter::from(Regex::new("an order.*").unwrap());
+    record_hit(pact, &get_order, Some(&filter));
+    record_hit(pact, &get_order, Some(&filter));
+    record_hit(pact, &get_order, None);
+
+    let coverage = coverage(&sources);
+    expect!(coverage[0].hits).to(be_equal_to(3));
+    expect!(coverage[0].hits_by_state.clone()).to(be_equal_to(vec![
+      (None, 1),
+      (Some("regex 'an order.*'".to_string()), 2)
+    ]));
+    expect!(coverage[1].hits_by_state.clone()).to(be_equal_to(vec![]));
+  }
+
+  #[test]
+  fn json_report_summarises_the_coverage() {
+    let coverage = vec![
+      InteractionCoverage {
+        consumer: "web".to_string(),
+        provider: "orders".to_string(),
+        description: "get an order".to_string(),
+        provider_states: vec!["an order exists".to_string()],
+        hits: 3,
+        hits_by_state: vec![(None, 1), (Some("an order exists".to_string()), 2)]
+      },
+      InteractionCoverage {
+        consumer: "web".to_string(),
+        provider: "orders".to_string(),
+        description: "delete an order".to_string(),
+        provider_states: vec![],
+        hits: 0,
+        hits_by_state: vec![]
+      }
+    ];
+    expect!(json_report(&coverage)).to(be_equal_to(json!({
+      "interactionCount": 2,
+      "coveredCount": 1,
+      "coverage": 0.5,
+      "interactions": [
+        {
+          "consumer": "web",
+          "provider": "orders",
+          "description": "get an order",
+          "providerStates": ["an order exists"],
+          "hits": 3,
+          "hitsByProviderState": [
+            { "providerState": null, "hits": 1 },
+            { "providerState": "an order exists", "hits": 2 }
+          ],
+          "covered": true
+        },
+        {
+          "consumer": "web",
+          "provider": "orders",
+          "description": "delete an order",
+          "providerStates": [],
+          "hits": 0,
+          "hitsByProviderState": [],
+          "covered": false
+        }
+      ]
+    })));
+  }
+
+  #[test]
+  fn junit_report_fails_the_interactions_that_were_not_used() {
+    let coverage = vec![
+      InteractionCoverage {
+        consumer: "web".to_string(),
+        provider: "orders".to_string(),
+        description: "get an order <1>".to_string(),
+        provider_states: vec![],
+        hits: 2,
+        hits_by_state: vec![(None, 1), (Some("'an order exists'".to_string()), 1)]
+      },
+      InteractionCoverage {
+        consumer: "web".to_string(),
+        provider: "orders".to_string(),
+        description: "delete an order".to_string(),
+        provider_states: vec!["an order exists".to_string()],
+        hits: 0,
+        hits_by_state: vec![]
+      }
+    ];
+    expect!(junit_report(&coverage)).to(be_equal_to(r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="Pact interaction coverage" tests="2" failures="1">
+  <testsuite name="web - orders" tests="2" failures="1">
+    <testcase classname="web - orders" name="get an order &lt;1&gt;">
+      <system-out>hits: 2, hits by provider state: (none): 1, &apos;an order exists&apos;: 1</system-out>
+    </testcase>
+    <testcase classname="web - orders" name="delete an order">
+      <failure message="Interaction was never used to respond to a request"/>
+      <system-out>hits: 0, provider states: an order exists</system-out>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#.to_string()));
+  }
+
+  #[test]
+  fn format_is_based_on_the_file_extension() {
+    expect!(CoverageFormat::for_file(&PathBuf::from("coverage.xml"))).to(be_equal_to(CoverageFormat::JUnit));
+    expect!(CoverageFormat::for_file(&PathBuf::from("coverage.json"))).to(be_equal_to(CoverageFormat::Json));
+    expect!(CoverageFormat::for_file(&PathBuf::from("coverage"))).to(be_equal_to(CoverageFormat::Json));
+  }
+
+  #[tokio::test]
+  async fn coverage_request_returns_junit_when_requested() {
+    let sources = sources("coverage-consumer-request");
+    let response = handle_coverage_request(Some("format=junit"), None, &sources).unwrap();
+    expect!(response.headers().get("Content-Type").unwrap().to_str().unwrap()).to(be_equal_to("application/xml"));
+    let response = handle_coverage_request(None, Some("application/json"), &sources).unwrap();
+    expect!(response.headers().get("Content-Type").unwrap().to_str().unwrap()).to(be_equal_to("application/json"));
+  }
+}
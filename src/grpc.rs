@@ -0,0 +1,454 @@
+//! gRPC stub server that returns responses from V4 synchronous message interactions
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::process::ExitCode;
+
+use futures::task::{Context, Poll};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use hyper::{Body, Request as HyperRequest, Response as HyperResponse, Server};
+use hyper::body::Bytes;
+use hyper::service::make_service_fn;
+use maplit::hashmap;
+use pact_matching::generators::apply_generators_to_sync_message;
+use pact_matching::Mismatch;
+use pact_models::generators::GeneratorTestMode;
+use pact_models::prelude::*;
+use pact_models::prelude::v4::*;
+use pact_models::v4::message_parts::MessageContents;
+use pact_models::v4::sync_message::SynchronousMessage;
+use pact_models::v4::V4InteractionType;
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use serde_json::Value;
+use tower_service::Service;
+use tracing::{debug, error, info, warn};
+
+use crate::{server, PactSource};
+use crate::shutdown::Shutdown;
+
+// Characters that must be percent-encoded in the grpc-message header. Non-ASCII characters are always encoded.
+const GRPC_MESSAGE_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'%');
+
+/// gRPC status codes used by the stub server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GrpcStatus {
+  /// Not an error; returned on success
+  Ok = 0,
+  /// The client specified an invalid argument
+  InvalidArgument = 3,
+  /// Some requested entity was not found
+  NotFound = 5,
+  /// The operation is not implemented or is not supported
+  Unimplemented = 12,
+  /// Internal errors
+  Internal = 13
+}
+
+// Maps a gRPC status from interaction metadata (either the code or the canonical name) to a code
+fn grpc_status_from_value(value: &Value) -> Option<u32> {
+  match value {
+    Value::Number(n) => n.as_u64().map(|n| n as u32),
+    Value::String(s) => s.parse::<u32>().ok().or_else(|| {
+      let names = [
+        "OK", "CANCELLED", "UNKNOWN", "INVALID_ARGUMENT", "DEADLINE_EXCEEDED", "NOT_FOUND",
+        "ALREADY_EXISTS", "PERMISSION_DENIED", "RESOURCE_EXHAUSTED", "FAILED_PRECONDITION", "ABORTED",
+        "OUT_OF_RANGE", "UNIMPLEMENTED", "INTERNAL", "UNAVAILABLE", "DATA_LOSS", "UNAUTHENTICATED"
+      ];
+      names.iter().position(|name| name.eq_ignore_ascii_case(s)).map(|code| code as u32)
+    }),
+    _ => None
+  }
+}
+
+/// Returns the gRPC method path (`package.Service/Method`) configured for the interaction
+pub fn grpc_method_path(message: &SynchronousMessage) -> Option<String> {
+  message.plugin_config.values()
+    .find_map(|config| config.get("service"))
+    .and_then(|service| service.as_str())
+    .map(|service| service.trim_start_matches('.').to_string())
+}
+
+// Checks if the path of the request (/package.Service/Method) is for the configured service method.
+// The configured method may not include the package.
+fn method_path_matches(configured: &str, request_path: &str) -> bool {
+  let request_path = request_path.trim_start_matches('/');
+  request_path == configured || request_path.ends_with(format!(".{}", configured).as_str())
+}
+
+/// Decodes a gRPC length-prefixed message
+pub fn decode_grpc_message(bytes: &Bytes) -> Result<Bytes, String> {
+  if bytes.len() < 5 {
+    return Err(format!("gRPC message frame is too short ({} bytes)", bytes.len()));
+  }
+  if bytes[0] != 0 {
+    return Err("Compressed gRPC messages are not supported".to_string());
+  }
+  let len = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) as usize;
+  if bytes.len() < 5 + len {
+    return Err(format!("gRPC message frame is truncated (expected {} bytes, got {})", len, bytes.len() - 5));
+  }
+  Ok(bytes.slice(5..5 + len))
+}
+
+/// Encodes a message as an uncompressed gRPC length-prefixed message
+pub fn encode_grpc_message(message: &[u8]) -> Bytes {
+  let mut buffer = Vec::with_capacity(message.len() + 5);
+  buffer.push(0);
+  buffer.extend_from_slice(&(message.len() as u32).to_be_bytes());
+  buffer.extend_from_slice(message);
+  Bytes::from(buffer)
+}
+
+// Percent-encodes a status message for the grpc-message header, as required by the gRPC spec
+fn grpc_message_value(message: &str) -> String {
+  utf8_percent_encode(message, GRPC_MESSAGE_ENCODE_SET).to_string()
+}
+
+// Trailers-only response used for errors
+fn grpc_error_response(status: GrpcStatus, message: &str) -> Result<HyperResponse<Body>, http::Error> {
+  warn!("Returning gRPC status {:?}: {}", status, message);
+  HyperResponse::builder()
+    .status(StatusCode::OK)
+    .header("content-type", "application/grpc")
+    .header("grpc-status", (status as u32).to_string())
+    .header("grpc-message", grpc_message_value(message))
+    .body(Body::empty())
+}
+
+fn grpc_response(contents: &MessageContents) -> Result<HyperResponse<Body>, http::Error> {
+  let status = contents.metadata.get("grpc-status")
+    .and_then(grpc_status_from_value)
+    .unwrap_or(GrpcStatus::Ok as u32);
+  if status != GrpcStatus::Ok as u32 {
+    let message = contents.metadata.get("grpc-message")
+      .map(|v| v.as_str().map(|s| s.to_string()).unwrap_or_else(|| v.to_string()))
+      .unwrap_or_default();
+    return HyperResponse::builder()
+      .status(StatusCode::OK)
+      .header("content-type", "application/grpc")
+      .header("grpc-status", status.to_string())
+      .header("grpc-message", grpc_message_value(message.as_str()))
+      .body(Body::empty());
+  }
+
+  let (mut sender, body) = Body::channel();
+  let data = encode_grpc_message(&contents.contents.value().unwrap_or_default());
+  tokio::spawn(async move {
+    if let Err(err) = sender.send_data(data).await {
+      warn!("Failed to send gRPC response message: {}", err);
+      return;
+    }
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(GrpcStatus::Ok as u32));
+    if let Err(err) = sender.send_trailers(trailers).await {
+      warn!("Failed to send gRPC response trailers: {}", err);
+    }
+  });
+  HyperResponse::builder()
+    .status(StatusCode::OK)
+    .header("content-type", "application/grpc")
+    .body(body)
+}
+
+/// Handler for gRPC requests
+#[derive(Clone)]
+pub struct GrpcHandler {
+  interactions: Vec<(String, SynchronousMessage, V4Pact)>
+}
+
+impl GrpcHandler {
+  /// Creates a handler from all the synchronous message interactions that are configured with a gRPC method
+  pub fn new(sources: &[(V4Pact, PactSource)]) -> GrpcHandler {
+    let interactions = sources.iter()
+      .flat_map(|(pact, _)| {
+        pact.filter_interactions(V4InteractionType::Synchronous_Messages)
+          .iter()
+          .filter_map(|i| i.as_v4_sync_message())
+          .filter_map(|message| grpc_method_path(&message).map(|path| (path, message, pact.clone())))
+          .collect::<Vec<_>>()
+      })
+      .collect();
+    GrpcHandler { interactions }
+  }
+
+  /// Number of gRPC interactions the handler can respond to
+  pub fn interaction_count(&self) -> usize {
+    self.interactions.len()
+  }
+
+  /// Binds the gRPC server to the given port on each of the host addresses, returning the future that runs
+  /// the servers
+  pub fn start_server(
+    self,
+    hosts: &[IpAddr],
+    port: u16,
+    shutdown: Shutdown
+  ) -> Result<impl Future<Output = Result<(), ExitCode>>, ExitCode> {
+    let mut servers = vec![];
+    let mut port = port;
+    for host in hosts {
+      let addr = SocketAddr::new(*host, port);
+      let incoming = server::tcp_listener(addr).map_err(|err| {
+        error!("could not start gRPC server on {}: {}", addr, err);
+        ExitCode::FAILURE
+      })?;
+      // Any random port assigned by the OS is used for the remaining addresses
+      port = incoming.local_addr().port();
+      info!("gRPC server listening on {}", incoming.local_addr());
+      let handler = self.clone();
+      servers.push(Server::builder(incoming)
+        .http2_only(true)
+        .serve(make_service_fn(move |_| {
+          let handler = handler.clone();
+          async move { Ok::<_, Infallible>(handler) }
+        }))
+        .with_graceful_shutdown(shutdown.wait()));
+    }
+    if servers.is_empty() {
+      error!("could not start gRPC server: no addresses to listen on");
+      return Err(ExitCode::FAILURE);
+    }
+
+    info!("gRPC server started on port {}", port);
+    Ok(async move {
+      futures::future::try_join_all(servers).await
+        .map(|_| ())
+        .map_err(|err| {
+          error!("error occurred running the gRPC server: {}", err);
+          ExitCode::from(2)
+        })
+    })
+  }
+
+  async fn handle_request(&self, path: &str, body: Bytes) -> Result<HyperResponse<Body>, http::Error> {
+    info!("===> Received gRPC request {}", path);
+    let candidates = self.interactions.iter()
+      .filter(|(method_path, _, _)| method_path_matches(method_path, path))
+      .collect::<Vec<_>>();
+    if candidates.is_empty() {
+      return grpc_error_response(GrpcStatus::Unimplemented,
+        format!("No interaction was found for gRPC method {}", path).as_str());
+    }
+
+    let message = match decode_grpc_message(&body) {
+      Ok(message) => message,
+      Err(err) => return grpc_error_response(GrpcStatus::InvalidArgument, err.as_str())
+    };
+
+    let mut mismatches = vec![];
+    for (_, interaction, pact) in candidates {
+      let actual = SynchronousMessage {
+        request: MessageContents {
+          contents: OptionalBody::Present(message.clone(), interaction.request.message_content_type(), None),
+          .. MessageContents::default()
+        },
+        .. interaction.clone()
+      };
+      let result = pact_matching::match_sync_message_request(interaction, &actual, &pact.boxed()).await;
+      let body_mismatches = result.iter()
+        .filter(|mismatch| matches!(mismatch, Mismatch::BodyMismatch { .. } | Mismatch::BodyTypeMismatch { .. }))
+        .collect::<Vec<_>>();
+      if body_mismatches.is_empty() {
+        debug!("gRPC request matched interaction '{}'", interaction.description);
+        let (_, responses) = apply_generators_to_sync_message(interaction, &GeneratorTestMode::Provider,
+          &hashmap!{}, &pact.plugin_data(), &interaction.plugin_config).await;
+        return match responses.first() {
+          Some(response) => grpc_response(response),
+          None => grpc_error_response(GrpcStatus::Internal,
+            format!("Interaction '{}' does not have a response message", interaction.description).as_str())
+        };
+      }
+      mismatches.extend(body_mismatches.iter().map(|m| m.description()));
+    }
+
+    grpc_error_response(GrpcStatus::NotFound,
+      format!("No interaction matched the request message for gRPC method {}: {}", path, mismatches.join(", ")).as_str())
+  }
+}
+
+impl Service<HyperRequest<Body>> for GrpcHandler {
+  type Response = HyperResponse<Body>;
+  type Error = http::Error;
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+  fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, req: HyperRequest<Body>) -> Self::Future {
+    let handler = self.clone();
+    Box::pin(async move {
+      let (parts, body) = req.into_parts();
+      let content_type = parts.headers.get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+      if !content_type.starts_with("application/grpc") {
+        return HyperResponse::builder()
+          .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+          .body(Body::from("Only gRPC requests (application/grpc) are supported"));
+      }
+      match hyper::body::to_bytes(body).await {
+        Ok(bytes) => handler.handle_request(parts.uri.path(), bytes).await,
+        Err(err) => grpc_error_response(GrpcStatus::Internal, format!("Failed to read the request: {}", err).as_str())
+      }
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use hyper::{Body, Client, Request};
+  use maplit::hashmap;
+  use pact_models::prelude::*;
+  use pact_models::prelude::v4::*;
+  use pact_models::v4::interaction::V4Interaction;
+  use pact_models::v4::message_parts::MessageContents;
+  use pact_models::v4::sync_message::SynchronousMessage;
+  use serde_json::json;
+
+  use crate::PactSource;
+
+  use super::*;
+
+  fn json_contents(json: &str) -> MessageContents {
+    MessageContents {
+      contents: OptionalBody::Present(Bytes::from(json.to_string()), Some("application/json".into()), None),
+      .. MessageContents::default()
+    }
+  }
+
+  fn interaction(service: &str, request: &str, response: &str) -> SynchronousMessage {
+    SynchronousMessage {
+      description: format!("{} request", service),
+      request: json_contents(request),
+      response: vec![json_contents(response)],
+      plugin_config: hashmap!{
+        "protobuf".to_string() => hashmap!{ "service".to_string() => json!(service) }
+      },
+      .. SynchronousMessage::default()
+    }
+  }
+
+  fn handler() -> GrpcHandler {
+    let pact = V4Pact {
+      interactions: vec![
+        interaction(".calculator.Calculator/calculate", "{\"a\": 1}", "{\"result\": 1}").boxed_v4(),
+        interaction("Calculator/calculate", "{\"a\": 2}", "{\"result\": 2}").boxed_v4(),
+        SynchronousMessage::default().boxed_v4(),
+        SynchronousHttp::default().boxed_v4()
+      ],
+      .. V4Pact::default()
+    };
+    GrpcHandler::new(&[(pact, PactSource::Unknown)])
+  }
+
+  #[test]
+  fn grpc_message_framing() {
+    let encoded = encode_grpc_message(b"hello");
+    expect!(encoded.to_vec()).to(be_equal_to(vec![0, 0, 0, 0, 5, b'h', b'e', b'l', b'l', b'o']));
+    expect!(decode_grpc_message(&encoded)).to(be_ok().value(Bytes::from("hello")));
+    expect!(decode_grpc_message(&Bytes::from_static(&[0, 0, 0]))).to(be_err());
+    expect!(decode_grpc_message(&Bytes::from_static(&[0, 0, 0, 0, 5, 1]))).to(be_err());
+    expect!(decode_grpc_message(&Bytes::from_static(&[1, 0, 0, 0, 1, 1]))).to(be_err());
+  }
+
+  #[test]
+  fn method_path_matching() {
+    expect!(method_path_matches("calculator.Calculator/calculate", "/calculator.Calculator/calculate")).to(be_true());
+    expect!(method_path_matches("Calculator/calculate", "/calculator.Calculator/calculate")).to(be_true());
+    expect!(method_path_matches("Calculator/calculate", "/calculator.OtherCalculator/calculate")).to(be_false());
+    expect!(method_path_matches("calculator.Calculator/calculate", "/calculator.Calculator/other")).to(be_false());
+  }
+
+  #[test]
+  fn grpc_status_values() {
+    expect!(grpc_status_from_value(&json!(5))).to(be_some().value(5));
+    expect!(grpc_status_from_value(&json!("5"))).to(be_some().value(5));
+    expect!(grpc_status_from_value(&json!("NOT_FOUND"))).to(be_some().value(5));
+    expect!(grpc_status_from_value(&json!("unimplemented"))).to(be_some().value(12));
+    expect!(grpc_status_from_value(&json!("NOT_A_STATUS"))).to(be_none());
+  }
+
+  #[test]
+  fn grpc_messages_are_percent_encoded() {
+    expect!(grpc_message_value("Not found")).to(be_equal_to("Not found"));
+    expect!(grpc_message_value("100% failed\r\nnext line")).to(be_equal_to("100%25 failed%0D%0Anext line"));
+    expect!(grpc_message_value("caf\u{e9}")).to(be_equal_to("caf%C3%A9"));
+
+    let contents = MessageContents {
+      metadata: hashmap!{
+        "grpc-status".to_string() => json!("NOT_FOUND"),
+        "grpc-message".to_string() => json!("line one\nline two")
+      },
+      .. MessageContents::default()
+    };
+    let response = grpc_response(&contents).unwrap();
+    expect!(response.headers().get("grpc-message").cloned())
+      .to(be_some().value(HeaderValue::from_static("line one%0Aline two")));
+  }
+
+  #[test]
+  fn only_indexes_interactions_with_a_grpc_method() {
+    expect!(handler().interaction_count()).to(be_equal_to(2));
+  }
+
+  async fn call(port: u16, path: &str, message: &str) -> (HeaderMap, Bytes, Option<HeaderMap>) {
+    let client = Client::builder().http2_only(true).build_http::<Body>();
+    let request = Request::post(format!("http://127.0.0.1:{}{}", port, path))
+      .header("content-type", "application/grpc")
+      .body(Body::from(encode_grpc_message(message.as_bytes())))
+      .unwrap();
+    let response = client.request(request).await.unwrap();
+    let headers = response.headers().clone();
+    let mut body = response.into_body();
+    let data = hyper::body::HttpBody::data(&mut body).await
+      .map(|d| d.unwrap())
+      .unwrap_or_default();
+    let trailers = hyper::body::HttpBody::trailers(&mut body).await.unwrap();
+    (headers, data, trailers)
+  }
+
+  #[tokio::test]
+  async fn binds_to_the_given_hosts() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let shutdown = Shutdown::new();
+    let server = handler().start_server(&[IpAddr::from([127, 0, 0, 1])], port, shutdown.clone()).unwrap();
+    let server = tokio::spawn(server);
+
+    let (_, data, _) = call(port, "/calculator.Calculator/calculate", "{\"a\": 1}").await;
+    expect!(decode_grpc_message(&data)).to(be_ok().value(Bytes::from("{\"result\": 1}")));
+    shutdown.trigger();
+    expect!(server.await.unwrap()).to(be_ok());
+
+    expect!(handler().start_server(&[], 0, Shutdown::new()).is_err()).to(be_true());
+  }
+
+  #[tokio::test]
+  async fn responds_to_grpc_requests_over_http2() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handler = handler();
+    tokio::spawn(async move {
+      Server::from_tcp(listener).unwrap()
+        .http2_only(true)
+        .serve(make_service_fn(move |_| {
+          let handler = handler.clone();
+          async move { Ok::<_, Infallible>(handler) }
+        }))
+        .await
+    });
+
+    let (_, data, trailers) = call(port, "/calculator.Calculator/calculate", "{\"a\": 2}").await;
+    expect!(decode_grpc_message(&data)).to(be_ok().value(Bytes::from("{\"result\": 2}")));
+    expect!(trailers.unwrap().get("grpc-status").cloned()).to(be_some().value(HeaderValue::from_static("0")));
+
+    let (headers, _, _) = call(port, "/calculator.Calculator/calculate", "{\"a\": 3}").await;
+    expect!(headers.get("grpc-status").cloned()).to(be_some().value(HeaderValue::from_static("5")));
+
+    let (headers, _, _) = call(port, "/calculator.Calculator/other", "{\"a\": 1}").await;
+    expect!(headers.get("grpc-status").cloned()).to(be_some().value(HeaderValue::from_static("12")));
+  }
+}
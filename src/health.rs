@@ -0,0 +1,199 @@
+//! Functions relating to the health (liveness and readiness) endpoints and the healthcheck command
+
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use http::{Method, StatusCode};
+use hyper::{Body, Response as HyperResponse};
+use pact_models::prelude::v4::*;
+use serde_json::{json, Value};
+use tracing::{debug, error};
+
+use crate::PactSource;
+
+/// Default base path of the health endpoints
+pub const DEFAULT_HEALTH_PATH: &str = "/__health";
+
+/// State used to respond to the health endpoints. The base path reports the readiness, with the
+/// liveness at `<path>/live` and the readiness at `<path>/ready`.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+  path: String,
+  ready: Arc<AtomicBool>
+}
+
+impl HealthCheck {
+  /// Creates the health check state for the base path. It will not be ready until `set_ready` is called.
+  pub fn new(path: &str) -> Self {
+    HealthCheck {
+      path: path.trim_end_matches('/').to_string(),
+      ready: Arc::new(AtomicBool::new(false))
+    }
+  }
+
+  /// Sets if the server is ready to respond to requests (the pacts have been loaded and the index built)
+  pub fn set_ready(&self, ready: bool) {
+    self.ready.store(ready, Ordering::SeqCst);
+  }
+
+  /// If the server is ready to respond to requests
+  pub fn is_ready(&self) -> bool {
+    self.ready.load(Ordering::SeqCst)
+  }
+
+  /// Returns the response for a request to one of the health endpoints, or None if the request is not
+  /// for a health endpoint. The server is not ready once it is shutting down.
+  pub fn handle_request(
+    &self,
+    method: &Method,
+    path: &str,
+    sources: &[(V4Pact, PactSource)],
+    shutting_down: bool
+  ) -> Option<Result<HyperResponse<Body>, http::Error>> {
+    if method != Method::GET && method != Method::HEAD {
+      return None;
+    }
+    let endpoint = path.strip_prefix(self.path.as_str())?;
+    match endpoint {
+      "/live" => Some(health_response(StatusCode::OK, json!({ "status": "UP" }))),
+      "" | "/" | "/ready" => {
+        let ready = self.is_ready() && !shutting_down;
+        let status = if ready { "READY" } else if shutting_down { "SHUTTING_DOWN" } else { "NOT_READY" };
+        debug!("Health check requested, status is {}", status);
+        Some(health_response(
+          if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE },
+          json!({
+            "status": status,
+            "interactionCount": sources.iter().map(|(pact, _)| pact.interactions.len()).sum::<usize>(),
+            "pacts": pact_versions(sources)
+          })
+        ))
+      },
+      _ => None
+    }
+  }
+}
+
+impl Default for HealthCheck {
+  fn default() -> Self {
+    HealthCheck::new(DEFAULT_HEALTH_PATH)
+  }
+}
+
+fn pact_versions(sources: &[(V4Pact, PactSource)]) -> Vec<Value> {
+  sources.iter()
+    .map(|(pact, _)| json!({
+      "consumer": pact.consumer.name,
+      "provider": pact.provider.name,
+      "specification": pact.metadata.get("pactSpecification")
+        .and_then(|spec| spec.get("version"))
+        .and_then(|version| version.as_str())
+        .unwrap_or("4.0"),
+      "interactionCount": pact.interactions.len()
+    }))
+    .collect()
+}
+
+fn health_response(status: StatusCode, body: Value) -> Result<HyperResponse<Body>, http::Error> {
+  HyperResponse::builder()
+    .status(status)
+    .header("Content-Type", "application/json")
+    .header("Cache-Control", "no-store")
+    .body(Body::from(body.to_string()))
+}
+
+/// Checks the readiness endpoint of a running stub server, returning a failure exit code if it is not ready
+pub async fn run_healthcheck(url: &str, timeout: Duration) -> ExitCode {
+  let client = match reqwest::Client::builder().timeout(timeout).build() {
+    Ok(client) => client,
+    Err(err) => {
+      error!("Failed to create the HTTP client: {}", err);
+      return ExitCode::FAILURE;
+    }
+  };
+  match client.get(url).send().await {
+    Ok(response) if response.status().is_success() => {
+      println!("{} is ready", url);
+      ExitCode::SUCCESS
+    },
+    Ok(response) => {
+      eprintln!("{} is not ready: {}", url, response.status());
+      ExitCode::FAILURE
+    },
+    Err(err) => {
+      eprintln!("Health check request to {} failed: {}", url, err);
+      ExitCode::FAILURE
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use pact_models::prelude::*;
+  use pact_models::prelude::v4::*;
+  use pact_models::v4::interaction::V4Interaction;
+  use serde_json::{json, Value};
+
+  use crate::PactSource;
+
+  use super::*;
+
+  fn sources() -> Vec<(V4Pact, PactSource)> {
+    vec![(V4Pact {
+      consumer: Consumer { name: "web".to_string() },
+      provider: Provider { name: "orders".to_string() },
+      interactions: vec![SynchronousHttp::default().boxed_v4()],
+      .. V4Pact::default()
+    }, PactSource::Unknown)]
+  }
+
+  async fn body_json(response: HyperResponse<Body>) -> Value {
+    serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap()
+  }
+
+  #[test]
+  fn ignores_requests_that_are_not_for_the_health_endpoints() {
+    let health = HealthCheck::default();
+    expect!(health.handle_request(&Method::GET, "/test", &sources(), false)).to(be_none());
+    expect!(health.handle_request(&Method::GET, "/__health/other", &sources(), false)).to(be_none());
+    expect!(health.handle_request(&Method::POST, "/__health", &sources(), false)).to(be_none());
+  }
+
+  #[tokio::test]
+  async fn liveness_is_always_up() {
+    let health = HealthCheck::default();
+    let response = health.handle_request(&Method::GET, "/__health/live", &sources(), true).unwrap().unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::OK));
+    expect!(body_json(response).await).to(be_equal_to(json!({ "status": "UP" })));
+  }
+
+  #[tokio::test]
+  async fn readiness_reports_the_loaded_pacts_once_ready() {
+    let health = HealthCheck::new("/health/");
+    let response = health.handle_request(&Method::GET, "/health/ready", &sources(), false).unwrap().unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::SERVICE_UNAVAILABLE));
+
+    health.set_ready(true);
+    let response = health.handle_request(&Method::GET, "/health", &sources(), false).unwrap().unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::OK));
+    expect!(body_json(response).await).to(be_equal_to(json!({
+      "status": "READY",
+      "interactionCount": 1,
+      "pacts": [
+        { "consumer": "web", "provider": "orders", "specification": "4.0", "interactionCount": 1 }
+      ]
+    })));
+  }
+
+  #[tokio::test]
+  async fn not_ready_when_shutting_down() {
+    let health = HealthCheck::default();
+    health.set_ready(true);
+    let response = health.handle_request(&Method::GET, "/__health/ready", &sources(), true).unwrap().unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::SERVICE_UNAVAILABLE));
+    expect!(body_json(response).await["status"].clone()).to(be_equal_to(json!("SHUTTING_DOWN")));
+  }
+}
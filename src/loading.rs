@@ -18,6 +18,10 @@ use serde_json::Value;
 use tracing::{debug, warn};
 
 use crate::PactSource;
+use crate::redaction;
+
+/// Result of loading a single pact, along with the source it was loaded from
+pub type PactLoadResult = Result<(Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactSource), PactError>;
 
 #[derive(Debug, Clone)]
 pub struct PactError {
@@ -26,7 +30,7 @@ pub struct PactError {
 }
 
 impl PactError {
-  fn new(str: String) -> PactError {
+  pub fn new(str: String) -> PactError {
     PactError { message: str, path: None }
   }
 
@@ -49,6 +53,11 @@ impl Display for PactError {
 
 impl From<reqwest::Error> for PactError {
   fn from(err: reqwest::Error) -> Self {
+    // The URL in the error may include the user credentials
+    let err = match err.url().cloned() {
+      Some(url) => err.with_url(redaction::without_credentials(url)),
+      None => err
+    };
     PactError { message: format!("Request failed: {}", err), path: None }
   }
 }
@@ -75,7 +84,7 @@ fn walkdir(
   dir: &Path,
   ext: &str,
   s: &PactSource
-) -> Result<Vec<Result<(Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactSource), PactError>>, PactError> {
+) -> Result<Vec<PactLoadResult>, PactError> {
   let mut pacts = vec![];
   debug!("Scanning {:?}", dir);
   for entry in fs::read_dir(dir)? {
@@ -117,7 +126,7 @@ async fn pact_from_url(
       _ => req.header("Authorization", "undefined"),
     };
   }
-  debug!("Executing Request to fetch pact from URL: {}", url);
+  debug!("Executing Request to fetch pact from URL: {}", redaction::redact_url(url));
   let pact_json: Value = req.send().await?.json().await?;
   debug!("Fetched Pact: {}", pact_json);
   load_pact_from_json(url, &pact_json).map_err(|err| err.into())
@@ -128,7 +137,7 @@ pub async fn load_pacts(
   sources: Vec<PactSource>,
   insecure_tls: bool,
   ext: Option<&String>
-) -> Vec<Result<(Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactSource), PactError>> {
+) -> Vec<PactLoadResult> {
   futures::stream::iter(sources)
     .then(| s| async move {
       let values = match &s {
@@ -186,7 +195,7 @@ pub async fn load_pacts(
     .await
 }
 
-fn filter_providers(providers: &Vec<Regex>, result: &Result<Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactError>) -> Ready<bool> {
+fn filter_providers(providers: &[Regex], result: &Result<Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactError>) -> Ready<bool> {
   match result {
     Ok(pact) => {
       if providers.is_empty() {
@@ -200,7 +209,7 @@ fn filter_providers(providers: &Vec<Regex>, result: &Result<Box<dyn Pact + Send
   }
 }
 
-fn filter_consumers(consumers: &Vec<Regex>, result: &Result<Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactError>) -> Ready<bool> {
+fn filter_consumers(consumers: &[Regex], result: &Result<Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactError>) -> Ready<bool> {
   match result {
     Ok(pact) => {
       if consumers.is_empty() {
@@ -225,14 +234,14 @@ mod tests {
   #[tokio::test]
   async fn filter_consumers_with_error_result() {
     let result = Err(PactError::new("test".to_string()));
-    let filter_result = filter_consumers(&vec![Regex::new("one").unwrap()], &result).await;
+    let filter_result = filter_consumers(&[Regex::new("one").unwrap()], &result).await;
     expect!(filter_result).to(be_true());
   }
 
   #[tokio::test]
   async fn filter_consumers_with_no_consumers() {
     let result = Ok(RequestResponsePact::default().boxed());
-    let filter_result = filter_consumers(&vec![], &result).await;
+    let filter_result = filter_consumers(&[], &result).await;
     expect!(filter_result).to(be_true());
   }
 
@@ -275,14 +284,14 @@ mod tests {
   #[tokio::test]
   async fn filter_providers_with_error_result() {
     let result = Err(PactError::new("test".to_string()));
-    let filter_result = filter_providers(&vec![Regex::new("one").unwrap()], &result).await;
+    let filter_result = filter_providers(&[Regex::new("one").unwrap()], &result).await;
     expect!(filter_result).to(be_true());
   }
 
   #[tokio::test]
   async fn filter_providers_with_no_providers() {
     let result = Ok(RequestResponsePact::default().boxed());
-    let filter_result = filter_providers(&vec![], &result).await;
+    let filter_result = filter_providers(&[], &result).await;
     expect!(filter_result).to(be_true());
   }
 
@@ -0,0 +1,446 @@
+//! Functions relating to the log output: the log format, size-rotated log files and the access log
+
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat};
+use futures::future::Future;
+use http::{Request, Response};
+use hyper::Body;
+use hyper::body::HttpBody;
+use pact_models::prelude::v4::*;
+use serde_json::json;
+use tracing::{error, Subscriber};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Default maximum size of a log file, in megabytes, before it is rotated
+pub const DEFAULT_LOG_MAX_SIZE: u64 = 10;
+
+/// Default number of rotated log files to keep
+pub const DEFAULT_LOG_MAX_FILES: usize = 5;
+
+/// Format of the log output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+  /// Compact, human readable output
+  Compact,
+  /// Multi-line, human readable output
+  Pretty,
+  /// One JSON object per log event
+  Json
+}
+
+impl LogFormat {
+  /// Returns the log format for the command line value, defaulting to compact
+  pub fn from_name(name: &str) -> LogFormat {
+    match name {
+      "pretty" => LogFormat::Pretty,
+      "json" => LogFormat::Json,
+      _ => LogFormat::Compact
+    }
+  }
+}
+
+/// Creates the formatting layer for the log events, writing to the log file if one is given,
+/// otherwise to standard output
+pub fn fmt_layer<S>(format: LogFormat, log_file: Option<RotatingFile>) -> Box<dyn Layer<S> + Send + Sync>
+  where S: Subscriber + for<'a> LookupSpan<'a> {
+  let layer = tracing_subscriber::fmt::layer().with_thread_names(true);
+  let layer = match log_file {
+    Some(file) => layer.with_ansi(false).with_writer(BoxMakeWriter::new(Mutex::new(file))),
+    None => layer.with_writer(BoxMakeWriter::new(io::stdout))
+  };
+  match format {
+    LogFormat::Compact => layer.compact().boxed(),
+    LogFormat::Pretty => layer.pretty().boxed(),
+    LogFormat::Json => layer.json().boxed()
+  }
+}
+
+/// Log file that is rotated once it reaches a maximum size. Rotated files have the index appended
+/// to the file name (`<file>.1` being the most recent), and only the configured number are kept.
+#[derive(Debug)]
+pub struct RotatingFile {
+  path: PathBuf,
+  file: File,
+  size: u64,
+  max_size: u64,
+  max_files: usize
+}
+
+impl RotatingFile {
+  /// Opens the log file for appending. A maximum size of zero disables the rotation.
+  pub fn open<P: AsRef<Path>>(path: P, max_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+    let path = path.as_ref().to_path_buf();
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let size = file.metadata()?.len();
+    Ok(RotatingFile {
+      path,
+      file,
+      size,
+      max_size,
+      max_files
+    })
+  }
+
+  fn rotate(&mut self) -> io::Result<()> {
+    self.file.flush()?;
+    if self.max_files == 0 {
+      self.file = File::create(&self.path)?;
+    } else {
+      for index in (1..self.max_files).rev() {
+        let rotated = rotated_path(&self.path, index);
+        if rotated.exists() {
+          fs::rename(&rotated, rotated_path(&self.path, index + 1))?;
+        }
+      }
+      fs::rename(&self.path, rotated_path(&self.path, 1))?;
+      self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+    }
+    self.size = 0;
+    Ok(())
+  }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+  let mut name = OsString::from(path.as_os_str());
+  name.push(format!(".{}", index));
+  PathBuf::from(name)
+}
+
+impl Write for RotatingFile {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if self.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+      self.rotate()?;
+    }
+    let written = self.file.write(buf)?;
+    self.size += written as u64;
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.file.flush()
+  }
+}
+
+/// Format of the access log entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+  /// One JSON object per request
+  Json,
+  /// Apache/NCSA Combined Log Format
+  Combined
+}
+
+impl AccessLogFormat {
+  /// Returns the access log format for the command line value, defaulting to JSON
+  pub fn from_name(name: &str) -> AccessLogFormat {
+    match name {
+      "combined" => AccessLogFormat::Combined,
+      _ => AccessLogFormat::Json
+    }
+  }
+}
+
+/// Interaction that was used to respond to a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchedInteraction {
+  pub consumer: String,
+  pub provider: String,
+  pub description: String,
+  /// Match score of the interaction, if it was ranked against the other candidates
+  pub score: Option<i64>
+}
+
+tokio::task_local! {
+  static MATCHED_INTERACTION: RefCell<Option<MatchedInteraction>>;
+}
+
+/// Runs the future that responds to a request, returning the response along with the interaction
+/// that matched the request (if any)
+pub async fn capture_matched_interaction<F: Future>(response: F) -> (F::Output, Option<MatchedInteraction>) {
+  MATCHED_INTERACTION.scope(RefCell::new(None), async move {
+    let result = response.await;
+    (result, MATCHED_INTERACTION.with(|matched| matched.borrow_mut().take()))
+  }).await
+}
+
+/// Records the interaction that matched the request being handled, so it can be included in the
+/// access log and session journal
+pub fn record_matched_interaction(pact: &V4Pact, interaction: &SynchronousHttp, score: Option<i64>) {
+  let _ = MATCHED_INTERACTION.try_with(|matched| {
+    *matched.borrow_mut() = Some(MatchedInteraction {
+      consumer: pact.consumer.name.clone(),
+      provider: pact.provider.name.clone(),
+      description: interaction.description.clone(),
+      score
+    });
+  });
+}
+
+/// Details of a request and its response written to the access log
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry {
+  timestamp: DateTime<FixedOffset>,
+  client_address: Option<SocketAddr>,
+  method: String,
+  uri: String,
+  path: String,
+  version: String,
+  referer: Option<String>,
+  user_agent: Option<String>,
+  status: u16,
+  bytes: Option<u64>,
+  latency: Duration,
+  interaction: Option<MatchedInteraction>
+}
+
+impl AccessLogEntry {
+  /// Creates the entry for the request received from the client address (which will be None for
+  /// Unix domain sockets)
+  pub fn new<B>(request: &Request<B>, client_address: Option<SocketAddr>) -> AccessLogEntry {
+    let header = |name: http::header::HeaderName| request.headers().get(name)
+      .and_then(|value| value.to_str().ok())
+      .map(|value| value.to_string());
+    AccessLogEntry {
+      timestamp: Local::now().fixed_offset(),
+      client_address,
+      method: request.method().to_string(),
+      uri: request.uri().path_and_query().map(|pq| pq.to_string()).unwrap_or_else(|| "/".to_string()),
+      path: request.uri().path().to_string(),
+      version: format!("{:?}", request.version()),
+      referer: header(http::header::REFERER),
+      user_agent: header(http::header::USER_AGENT),
+      status: 0,
+      bytes: None,
+      latency: Duration::default(),
+      interaction: None
+    }
+  }
+
+  fn json(&self) -> String {
+    json!({
+      "timestamp": self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, false),
+      "clientAddress": self.client_address.map(|addr| addr.to_string()),
+      "method": self.method,
+      "path": self.path,
+      "uri": self.uri,
+      "protocol": self.version,
+      "status": self.status,
+      "bytes": self.bytes,
+      "latencyMs": self.latency.as_secs_f64() * 1000.0,
+      "matched": self.interaction.is_some(),
+      "consumer": self.interaction.as_ref().map(|i| i.consumer.as_str()),
+      "provider": self.interaction.as_ref().map(|i| i.provider.as_str()),
+      "interaction": self.interaction.as_ref().map(|i| i.description.as_str()),
+      "score": self.interaction.as_ref().and_then(|i| i.score)
+    }).to_string()
+  }
+
+  fn combined(&self) -> String {
+    format!("{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+      self.client_address.map(|addr| addr.ip().to_string()).unwrap_or_else(|| "-".to_string()),
+      self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+      self.method,
+      self.uri,
+      self.version,
+      self.status,
+      self.bytes.filter(|bytes| *bytes > 0).map(|bytes| bytes.to_string()).unwrap_or_else(|| "-".to_string()),
+      self.referer.as_deref().unwrap_or("-"),
+      self.user_agent.as_deref().unwrap_or("-"))
+  }
+}
+
+/// Writes an entry for each request to the access log
+#[derive(Clone)]
+pub struct AccessLog {
+  format: AccessLogFormat,
+  writer: Arc<Mutex<Box<dyn Write + Send>>>
+}
+
+impl AccessLog {
+  /// Creates an access log that writes the entries to the writer
+  pub fn new(format: AccessLogFormat, writer: Box<dyn Write + Send>) -> AccessLog {
+    AccessLog {
+      format,
+      writer: Arc::new(Mutex::new(writer))
+    }
+  }
+
+  /// Opens the access log file, which is rotated in the same way as the log file. A file of `-`
+  /// writes the entries to standard output.
+  pub fn open(file: &str, format: AccessLogFormat, max_size: u64, max_files: usize) -> io::Result<AccessLog> {
+    if file == "-" {
+      Ok(AccessLog::new(format, Box::new(io::stdout())))
+    } else {
+      Ok(AccessLog::new(format, Box::new(RotatingFile::open(file, max_size, max_files)?)))
+    }
+  }
+
+  /// Writes the entry for the request with the response, the time taken and the interaction that matched
+  pub fn log_response(
+    &self,
+    mut entry: AccessLogEntry,
+    response: &Result<Response<Body>, http::Error>,
+    latency: Duration,
+    interaction: Option<MatchedInteraction>
+  ) {
+    entry.latency = latency;
+    entry.interaction = interaction;
+    match response {
+      Ok(response) => {
+        entry.status = response.status().as_u16();
+        entry.bytes = response.body().size_hint().exact();
+      },
+      Err(_) => entry.status = 500
+    }
+    self.write(&entry);
+  }
+
+  fn write(&self, entry: &AccessLogEntry) {
+    let line = match self.format {
+      AccessLogFormat::Json => entry.json(),
+      AccessLogFormat::Combined => entry.combined()
+    };
+    let mut writer = self.writer.lock().unwrap_or_else(|err| err.into_inner());
+    if let Err(err) = writeln!(writer, "{}", line).and_then(|_| writer.flush()) {
+      error!("Failed to write to the access log: {}", err);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+  use std::io::Write;
+  use std::sync::{Arc, Mutex};
+
+  use chrono::{DateTime, FixedOffset};
+  use expectest::prelude::*;
+  use http::{Request, Response};
+  use hyper::Body;
+  use pact_models::prelude::*;
+  use pact_models::prelude::v4::*;
+  use serde_json::{json, Value};
+
+  use super::*;
+
+  #[derive(Clone, Default)]
+  struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl Buffer {
+    fn contents(&self) -> String {
+      String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+  }
+
+  fn entry() -> AccessLogEntry {
+    let request = Request::get("/orders/1?page=2")
+      .header("User-Agent", "curl/8.0")
+      .body(())
+      .unwrap();
+    AccessLogEntry {
+      timestamp: DateTime::<FixedOffset>::parse_from_rfc3339("2000-10-10T13:55:36.123-07:00").unwrap(),
+      status: 200,
+      bytes: Some(12),
+      latency: Duration::from_millis(3),
+      interaction: Some(MatchedInteraction {
+        consumer: "web".to_string(),
+        provider: "orders".to_string(),
+        description: "get an order".to_string(),
+        score: Some(7)
+      }),
+      .. AccessLogEntry::new(&request, Some("127.0.0.1:52000".parse().unwrap()))
+    }
+  }
+
+  #[test]
+  fn combined_log_format() {
+    expect!(entry().combined()).to(be_equal_to(
+      "127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] \"GET /orders/1?page=2 HTTP/1.1\" 200 12 \"-\" \"curl/8.0\""));
+  }
+
+  #[test]
+  fn json_log_format() {
+    let json: Value = serde_json::from_str(&entry().json()).unwrap();
+    expect!(json).to(be_equal_to(json!({
+      "timestamp": "2000-10-10T13:55:36.123-07:00",
+      "clientAddress": "127.0.0.1:52000",
+      "method": "GET",
+      "path": "/orders/1",
+      "uri": "/orders/1?page=2",
+      "protocol": "HTTP/1.1",
+      "status": 200,
+      "bytes": 12,
+      "latencyMs": 3.0,
+      "matched": true,
+      "consumer": "web",
+      "provider": "orders",
+      "interaction": "get an order",
+      "score": 7
+    })));
+  }
+
+  #[tokio::test]
+  async fn access_log_includes_the_response_and_matched_interaction() {
+    let buffer = Buffer::default();
+    let access_log = AccessLog::new(AccessLogFormat::Json, Box::new(buffer.clone()));
+    let pact = V4Pact {
+      consumer: Consumer { name: "web".to_string() },
+      provider: Provider { name: "orders".to_string() },
+      .. V4Pact::default()
+    };
+    let interaction = SynchronousHttp { description: "get an order".to_string(), .. SynchronousHttp::default() };
+    let request = Request::post("/orders").body(Body::empty()).unwrap();
+
+    let (response, matched) = capture_matched_interaction(async move {
+      record_matched_interaction(&pact, &interaction, None);
+      Response::builder().status(201).body(Body::from("created"))
+    }).await;
+    access_log.log_response(AccessLogEntry::new(&request, None), &response, Duration::from_millis(1), matched);
+    expect!(response.unwrap().status().as_u16()).to(be_equal_to(201));
+
+    let json: Value = serde_json::from_str(buffer.contents().trim_end()).unwrap();
+    expect!(json["method"].clone()).to(be_equal_to(json!("POST")));
+    expect!(json["status"].clone()).to(be_equal_to(json!(201)));
+    expect!(json["bytes"].clone()).to(be_equal_to(json!(7)));
+    expect!(json["clientAddress"].clone()).to(be_equal_to(Value::Null));
+    expect!(json["interaction"].clone()).to(be_equal_to(json!("get an order")));
+  }
+
+  #[test]
+  fn log_file_is_rotated_once_it_reaches_the_maximum_size() {
+    let dir = std::env::temp_dir().join(format!("pact-stub-server-logs-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("stub.log");
+    let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+    for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+      file.write_all(line.as_bytes()).unwrap();
+    }
+    file.flush().unwrap();
+
+    expect!(fs::read_to_string(&path).unwrap()).to(be_equal_to("fourth\n"));
+    expect!(fs::read_to_string(rotated_path(&path, 1)).unwrap()).to(be_equal_to("third\n"));
+    expect!(fs::read_to_string(rotated_path(&path, 2)).unwrap()).to(be_equal_to("second\n"));
+    expect!(rotated_path(&path, 3).exists()).to(be_false());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
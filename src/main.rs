@@ -85,28 +85,76 @@
 #![warn(missing_docs)]
 
 use std::env;
+use std::fmt::{Debug, Formatter};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::process::ExitCode;
 use std::str::FromStr;
+use std::time::Duration;
 
 use clap::{Command, Arg, ArgMatches, ArgAction, command, crate_version};
 use clap::error::ErrorKind;
+use itertools::Itertools;
+use pact_models::path_exp::DocPath;
 use pact_models::prelude::*;
+use pact_models::v4::V4InteractionType;
 use regex::Regex;
 use tracing::{debug, error, info, warn};
 use tracing_core::LevelFilter;
-use tracing_subscriber::FmtSubscriber;
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::SubscriberExt;
+use crate::compression::{ResponseCompression, DEFAULT_COMPRESSION_MIN_SIZE, DEFAULT_MAX_DECODED_BODY_SIZE};
+use crate::cors::CorsPolicy;
+use crate::grpc::GrpcHandler;
+use crate::health::{HealthCheck, DEFAULT_HEALTH_PATH};
 use crate::loading::load_pacts;
+use crate::logging::{AccessLog, AccessLogFormat, LogFormat, RotatingFile, DEFAULT_LOG_MAX_FILES, DEFAULT_LOG_MAX_SIZE};
+use crate::matching::{MatchCategory, MatchSettings};
+use crate::messages::MessageTarget;
+use crate::metrics::DEFAULT_METRICS_PATH;
+use crate::negotiation::{Negotiation, DEFAULT_VERSION_HEADER};
+use crate::paths::{BasePath, PathNormalisation};
+use crate::provider_states::ProviderStateSelector;
+use crate::redaction::Redaction;
+use crate::registration::RegisteredInteractions;
+use crate::report::StartupReport;
+use crate::sessions::{Sessions, DEFAULT_SESSION_COOKIE, DEFAULT_SESSION_HEADER, DEFAULT_SESSION_TIMEOUT};
+use crate::shutdown::{Shutdown, DEFAULT_DRAIN_TIMEOUT};
 
-use crate::server::ServerHandler;
+use crate::server::{HttpProtocol, ListenAddresses, ServerHandler, DEFAULT_INTERACTION_HEADER};
 
 mod pact_support;
 mod server;
+mod tls;
+mod compression;
+mod cors;
+mod coverage;
+mod grpc;
+mod health;
 mod loading;
+mod logging;
+mod matching;
+mod messages;
+mod metrics;
+mod negotiation;
+mod paths;
+mod plugins;
+mod provider_states;
+mod redaction;
+mod registration;
+mod report;
+mod scoring;
+mod sessions;
+mod shutdown;
+mod telemetry;
 
 #[tokio::main]
-async fn main() -> Result<(), ExitCode> {
+async fn main() -> ExitCode {
   let args: Vec<String> = env::args().collect();
-  handle_command_args(args).await
+  match handle_command_args(args).await {
+    Ok(_) => ExitCode::SUCCESS,
+    Err(code) => code
+  }
 }
 
 fn print_version() {
@@ -118,6 +166,19 @@ fn integer_value(v: &str) -> Result<u16, String> {
     v.parse::<u16>().map_err(|e| format!("'{}' is not a valid port value: {}", v, e) )
 }
 
+fn ip_address_value(v: &str) -> Result<IpAddr, String> {
+  v.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>()
+    .map_err(|e| format!("'{}' is not a valid IP address: {}", v, e))
+}
+
+fn path_value(v: &str) -> Result<String, String> {
+  if v.starts_with('/') {
+    Ok(v.trim_end_matches('/').to_string())
+  } else {
+    Err(format!("'{}' is not a valid path, it must start with a '/'", v))
+  }
+}
+
 fn regex_value(v: &str) -> Result<Regex, String> {
   if v.is_empty() {
     Err("Regular expression is empty".to_string())
@@ -126,8 +187,12 @@ fn regex_value(v: &str) -> Result<Regex, String> {
   }
 }
 
+fn doc_path_value(v: &str) -> Result<DocPath, String> {
+  DocPath::new(v).map_err(|e| format!("'{}' is not a valid body path: {}", v, e))
+}
+
 /// Source for loading pacts
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub enum PactSource {
   /// Load the pact from a pact file
   File(String),
@@ -150,6 +215,25 @@ pub enum PactSource {
   Unknown
 }
 
+// The auth details are not included, so the credentials do not end up in the debug output
+impl Debug for PactSource {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let auth = |auth: &Option<HttpAuth>| auth.as_ref().map(|_| redaction::REDACTED);
+    match self {
+      PactSource::File(file) => f.debug_tuple("File").field(file).finish(),
+      PactSource::Dir(dir) => f.debug_tuple("Dir").field(dir).finish(),
+      PactSource::URL(url, a) => f.debug_tuple("URL").field(&redaction::redact_url(url)).field(&auth(a)).finish(),
+      PactSource::Broker { url, auth: a, consumers, providers } => f.debug_struct("Broker")
+        .field("url", &redaction::redact_url(url))
+        .field("auth", &auth(a))
+        .field("consumers", consumers)
+        .field("providers", providers)
+        .finish(),
+      PactSource::Unknown => f.write_str("Unknown")
+    }
+  }
+}
+
 fn pact_source(matches: &ArgMatches) -> Vec<PactSource> {
   let mut sources = vec![];
 
@@ -185,25 +269,90 @@ fn pact_source(matches: &ArgMatches) -> Vec<PactSource> {
     sources.push(PactSource::Broker {
       url: url.to_string(),
       auth,
-      consumers: matches.get_many::<Regex>("consumer-name").unwrap_or_default().into_iter().cloned().collect(),
-      providers: matches.get_many::<Regex>("provider-name").unwrap_or_default().into_iter().cloned().collect()
+      consumers: matches.get_many::<Regex>("consumer-name").unwrap_or_default().cloned().collect(),
+      providers: matches.get_many::<Regex>("provider-name").unwrap_or_default().cloned().collect()
     });
   }
 
   sources
 }
 
+fn listen_addresses(matches: &ArgMatches) -> ListenAddresses {
+  let port = matches.get_one::<u16>("port").copied();
+  let unix_socket = matches.get_one::<String>("unix-socket").map(PathBuf::from);
+  let hosts = match matches.get_many::<IpAddr>("host") {
+    Some(hosts) => hosts.copied().unique().collect(),
+    None if unix_socket.is_some() && port.is_none() => vec![],
+    None => vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)]
+  };
+  ListenAddresses {
+    hosts,
+    port: port.unwrap_or(0),
+    unix_socket
+  }
+}
+
+async fn healthcheck(matches: &ArgMatches) -> Result<(), ExitCode> {
+  let host = matches.get_one::<IpAddr>("host").copied().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+  let port = *matches.get_one::<u16>("port").unwrap();
+  let path = matches.get_one::<String>("health-path").unwrap();
+  let url = format!("http://{}{}/ready", SocketAddr::new(host, port), path);
+  let timeout = Duration::from_secs(*matches.get_one::<u64>("timeout").unwrap());
+  match health::run_healthcheck(&url, timeout).await {
+    code if code == ExitCode::SUCCESS => Ok(()),
+    code => Err(code)
+  }
+}
+
 async fn handle_command_args(args: Vec<String>) -> Result<(), ExitCode> {
   let app = build_args();
   match app.try_get_matches_from(args) {
     Ok(ref matches) => {
+      if let Some(("healthcheck", sub_matches)) = matches.subcommand() {
+        return healthcheck(sub_matches).await;
+      }
       let level = matches.get_one::<String>("loglevel").cloned()
         .unwrap_or_else(|| "info".to_string());
-      setup_logger(level.as_str());
+      let log_max_size = matches.get_one::<u64>("log-max-size").copied()
+        .unwrap_or(DEFAULT_LOG_MAX_SIZE) * 1024 * 1024;
+      let log_max_files = matches.get_one::<usize>("log-max-files").copied()
+        .unwrap_or(DEFAULT_LOG_MAX_FILES);
+      let log_file = match matches.get_one::<String>("log-file") {
+        Some(path) => match RotatingFile::open(path, log_max_size, log_max_files) {
+          Ok(file) => Some(file),
+          Err(err) => {
+            eprintln!("ERROR: Failed to open the log file {} - {}", path, err);
+            return Err(ExitCode::FAILURE);
+          }
+        },
+        None => None
+      };
+      let log_format = LogFormat::from_name(matches.get_one::<String>("log-format").unwrap());
+      setup_logger(level.as_str(), log_format, log_file, matches.get_one::<String>("otlp-endpoint"));
+      redaction::configure(Redaction::new(
+        &matches.get_many::<String>("redact-header").unwrap_or_default().cloned().collect::<Vec<_>>(),
+        &matches.get_many::<DocPath>("redact-body-path").unwrap_or_default().cloned().collect::<Vec<_>>()));
+      let access_log = match matches.get_one::<String>("access-log") {
+        Some(file) => {
+          let format = AccessLogFormat::from_name(matches.get_one::<String>("access-log-format").unwrap());
+          match AccessLog::open(file, format, log_max_size, log_max_files) {
+            Ok(access_log) => Some(access_log),
+            Err(err) => {
+              error!("Failed to open the access log {} - {}", file, err);
+              return Err(ExitCode::FAILURE);
+            }
+          }
+        },
+        None => None
+      };
       let sources = pact_source(matches);
 
       let pacts = load_pacts(sources, matches.get_flag("insecure-tls"),
         matches.get_one("ext")).await;
+      metrics::record_pact_load(
+        pacts.iter().filter(|p| p.is_ok()).count(),
+        pacts.iter().flatten().map(|(pact, _)| pact.interactions().len()).sum(),
+        pacts.iter().filter(|p| p.is_err()).count());
       if pacts.iter().any(|p| p.is_err()) {
         error!("There were errors loading the pact files.");
         for error in pacts.iter()
@@ -216,9 +365,37 @@ async fn handle_command_args(args: Vec<String>) -> Result<(), ExitCode> {
         }
         Err(ExitCode::from(3))
       } else {
-        let port = *matches.get_one::<u16>("port").unwrap_or(&0);
+        let addresses = listen_addresses(matches);
+        let drain_timeout = matches.get_one::<u64>("drain-timeout")
+          .map(|secs| Duration::from_secs(*secs))
+          .unwrap_or(DEFAULT_DRAIN_TIMEOUT);
         let provider_state = matches.get_one::<Regex>("provider-state").cloned();
         let provider_state_header_name = matches.get_one::<String>("provider-state-header-name").cloned();
+        let match_settings = if matches.get_flag("strict") {
+          MatchSettings::strict()
+        } else {
+          MatchSettings::with_categories(&matches.get_many::<String>("match").unwrap_or_default()
+            .filter_map(|category| MatchCategory::from_name(category))
+            .collect::<Vec<_>>())
+        };
+        info!("Matching requests using {}", match_settings);
+        let negotiation = Negotiation::new(&matches.get_many::<String>("version-header").unwrap_or_default()
+          .cloned()
+          .collect::<Vec<_>>(), matches.get_flag("strict-negotiation"));
+        info!("Negotiating between interactions using {}", negotiation);
+        let match_settings = match_settings.with_negotiation(negotiation);
+        let path_normalisation = PathNormalisation::default()
+          .with_base_path(matches.get_one::<String>("strip-base-path").map(|path| BasePath::Strip(path.clone()))
+            .or_else(|| matches.get_one::<String>("add-base-path").map(|path| BasePath::Add(path.clone()))))
+          .with_trailing_slash_tolerance(matches.get_flag("ignore-trailing-slash"))
+          .with_percent_decoding(matches.get_flag("decode-paths"))
+          .with_case_insensitive_paths(matches.get_flag("case-insensitive-paths"));
+        if path_normalisation.is_enabled() {
+          info!("Normalising paths by {}", path_normalisation);
+        }
+        let provider_state_selector = ProviderStateSelector::new(provider_state_header_name.clone(),
+          matches.get_one::<String>("provider-state-query-param").cloned(),
+          matches.get_one::<String>("provider-state-cookie").cloned());
         let empty_provider_states = matches.get_flag("empty-provider-state");
         let pacts = pacts.iter()
           .map(|result| {
@@ -229,18 +406,119 @@ async fn handle_command_args(args: Vec<String>) -> Result<(), ExitCode> {
           .collect::<Vec<_>>();
         let interactions: usize = pacts.iter().map(|(p, _)| p.interactions.len()).sum();
         info!("Loaded {} pacts ({} total interactions)", pacts.len(), interactions);
+        let plugin_errors = plugins::load_required_plugins(&pacts).await;
+        if !plugin_errors.is_empty() {
+          error!("There were errors loading the plugins required by the pact files.");
+          for error in plugin_errors {
+            error!("  - {}", error);
+          }
+          return Err(ExitCode::from(3));
+        }
+        let shutdown = Shutdown::new();
+        tokio::spawn(shutdown::shutdown_on_signal(shutdown.clone()));
+        if let Some(grpc_port) = matches.get_one::<u16>("grpc-port") {
+          let grpc_handler = GrpcHandler::new(&pacts);
+          info!("Loaded {} gRPC interactions", grpc_handler.interaction_count());
+          // The gRPC server binds to the same hosts as the stub server, or all IPv4 interfaces if it only
+          // listens on a Unix domain socket
+          let grpc_hosts = if addresses.hosts.is_empty() {
+            vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)]
+          } else {
+            addresses.hosts.clone()
+          };
+          let grpc_server = grpc_handler.start_server(&grpc_hosts, *grpc_port, shutdown.clone())?;
+          tokio::spawn(grpc_server);
+        }
+        let protocol = if matches.get_flag("http1-only") {
+          HttpProtocol::Http1Only
+        } else if matches.get_flag("http2-only") {
+          HttpProtocol::Http2Only
+        } else {
+          HttpProtocol::Auto
+        };
+        let tls_config = match (matches.get_one::<String>("tls-cert"), matches.get_one::<String>("tls-key")) {
+          (Some(cert), Some(key)) => match tls::load_tls_config(cert, key, protocol) {
+            Ok(config) => Some(config),
+            Err(err) => {
+              error!("Failed to load the TLS certificate and key: {:#}", err);
+              return Err(ExitCode::FAILURE);
+            }
+          },
+          _ => None
+        };
         let auto_cors = matches.get_flag("cors");
         let referer = matches.get_flag("cors-referer");
+        let cors_policy = CorsPolicy::new(auto_cors, referer)
+          .with_origins(matches.get_many::<String>("cors-origin").unwrap_or_default().cloned().collect(),
+            matches.get_one::<Regex>("cors-origin-regex").cloned())
+          .with_credentials(matches.get_flag("cors-credentials"))
+          .with_exposed_headers(matches.get_many::<String>("cors-expose-headers").unwrap_or_default().cloned().collect())
+          .with_max_age(matches.get_one::<u64>("cors-max-age").copied());
+        let cors_policy = if matches.get_flag("no-cors-headers") {
+          cors_policy.without_headers()
+        } else {
+          cors_policy
+        };
+        let message_target = matches.get_one::<String>("message-webhook")
+          .map(|url| MessageTarget::Webhook(url.clone()))
+          .or_else(|| matches.get_one::<String>("message-tcp").map(|address| MessageTarget::Tcp(address.clone())));
+        let mut served_types = vec![V4InteractionType::Synchronous_HTTP];
+        if matches.contains_id("grpc-port") {
+          served_types.push(V4InteractionType::Synchronous_Messages);
+        }
+        if message_target.is_some() {
+          served_types.push(V4InteractionType::Asynchronous_Messages);
+        }
+        let report = StartupReport::new(
+          matches.get_one::<String>("port-file").map(PathBuf::from),
+          matches.get_one::<String>("ready-json").map(PathBuf::from),
+          &pacts,
+          &served_types);
+        let coverage_report = matches.get_one::<String>("coverage-report")
+          .map(|path| (PathBuf::from(path), pacts.clone()));
         let server_handler = ServerHandler::new(
           pacts,
           auto_cors,
           referer,
           provider_state,
           provider_state_header_name,
-          empty_provider_states);
-        tokio::task::spawn_blocking(move || {
-          server_handler.start_server(port)
-        }).await.unwrap()
+          empty_provider_states,
+          message_target)
+          .with_cors_policy(cors_policy)
+          .with_path_normalisation(path_normalisation)
+          .with_graceful_shutdown(shutdown, drain_timeout, matches.get_flag("shutdown-endpoint"))
+          .with_health_check(HealthCheck::new(matches.get_one::<String>("health-path").unwrap()))
+          .with_metrics_path(matches.get_one::<String>("metrics-path").unwrap())
+          .with_access_log(access_log)
+          .with_provider_state_selector(provider_state_selector)
+          .with_match_settings(match_settings)
+          .with_interaction_header(matches.get_one::<String>("interaction-header-name").unwrap())
+          .with_sessions(Sessions::new(
+            matches.get_one::<String>("session-header").unwrap(),
+            matches.get_one::<String>("session-cookie").unwrap(),
+            matches.get_one::<u64>("session-timeout")
+              .map(|secs| Duration::from_secs(*secs))
+              .unwrap_or(DEFAULT_SESSION_TIMEOUT)))
+          .with_admin_api(matches.get_flag("admin-api"))
+          .with_registered_interactions(RegisteredInteractions::new(
+            *matches.get_one::<i32>("registered-priority").unwrap()))
+          .with_response_compression(matches.get_flag("compress-responses").then(|| ResponseCompression::new(
+            matches.get_one::<usize>("compression-min-size").copied().unwrap_or(DEFAULT_COMPRESSION_MIN_SIZE))))
+          .with_max_decoded_body_size(matches.get_one::<usize>("max-decoded-body-size").copied()
+            .unwrap_or(DEFAULT_MAX_DECODED_BODY_SIZE));
+        let result = tokio::task::spawn_blocking(move || {
+          server_handler.start_server(&addresses, protocol, tls_config, &report)
+        }).await.unwrap();
+        if let Some((path, pacts)) = coverage_report {
+          if let Err(err) = coverage::write_report(&path, &pacts) {
+            error!("{:#}", err);
+          }
+        }
+        pact_plugin_driver::plugin_manager::shutdown_plugins();
+        if matches.contains_id("otlp-endpoint") {
+          let _ = tokio::task::spawn_blocking(telemetry::shutdown).await;
+        }
+        result
       }
     },
     Err(ref err) => {
@@ -265,23 +543,84 @@ fn build_args() -> Command {
     .about(format!("Pact Stub Server {}", crate_version!()))
     .arg_required_else_help(true)
     .disable_version_flag(true)
+    .subcommand_negates_reqs(true)
+    .args_conflicts_with_subcommands(true)
+    .subcommand(Command::new("healthcheck")
+      .about("Checks the readiness of a running stub server, exiting with a non-zero exit code if it is not ready")
+      .arg(Arg::new("port")
+        .short('p')
+        .long("port")
+        .default_value("8080")
+        .value_parser(integer_value)
+        .help("Port the stub server is running on"))
+      .arg(Arg::new("host")
+        .long("host")
+        .value_parser(ip_address_value)
+        .help("IP address the stub server is running on (defaults to 127.0.0.1)"))
+      .arg(Arg::new("health-path")
+        .long("health-path")
+        .default_value(DEFAULT_HEALTH_PATH)
+        .value_parser(path_value)
+        .help("Base path of the health endpoints"))
+      .arg(Arg::new("timeout")
+        .long("timeout")
+        .default_value("5")
+        .value_parser(clap::value_parser!(u64))
+        .help("Seconds to wait for a response")))
     .arg(Arg::new("loglevel")
       .short('l')
       .long("loglevel")
       .default_value("info")
       .value_parser(["error", "warn", "info", "debug", "trace", "none"])
       .help("Log level (defaults to info)"))
+    .arg(Arg::new("log-format")
+      .long("log-format")
+      .default_value("compact")
+      .value_parser(["compact", "pretty", "json"])
+      .help("Format of the log output. json writes one JSON object per log event"))
+    .arg(Arg::new("log-file")
+      .long("log-file")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("File to write the log output to instead of standard output. It is rotated once it reaches the maximum size"))
+    .arg(Arg::new("log-max-size")
+      .long("log-max-size")
+      .value_parser(clap::value_parser!(u64))
+      .help("Size in megabytes that the log and access log files are rotated at (defaults to 10, 0 disables the rotation)"))
+    .arg(Arg::new("log-max-files")
+      .long("log-max-files")
+      .value_parser(clap::value_parser!(usize))
+      .help("Number of rotated log and access log files to keep (defaults to 5)"))
+    .arg(Arg::new("access-log")
+      .long("access-log")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("File to write an entry for each request to (method, path, status, matched interaction, latency and client address). Use - for standard output"))
+    .arg(Arg::new("access-log-format")
+      .long("access-log-format")
+      .default_value("json")
+      .value_parser(["json", "combined"])
+      .help("Format of the access log entries: JSON or the Combined Log Format"))
+    .arg(Arg::new("redact-header")
+      .long("redact-header")
+      .action(ArgAction::Append)
+      .value_delimiter(',')
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Header to redact the value of in the log and trace output, in addition to the default sensitive headers (can be repeated)"))
+    .arg(Arg::new("redact-body-path")
+      .long("redact-body-path")
+      .action(ArgAction::Append)
+      .value_parser(doc_path_value)
+      .help("Path of a value in JSON bodies to redact in the log output, e.g. $.password or $.users[*].token (can be repeated)"))
     .arg(Arg::new("file")
       .short('f')
       .long("file")
-      .required_unless_present_any(&["dir", "url", "broker-url"])
+      .required_unless_present_any(["dir", "url", "broker-url"])
       .action(ArgAction::Append)
       .value_parser(clap::builder::NonEmptyStringValueParser::new())
       .help("Pact file to load (can be repeated)"))
     .arg(Arg::new("dir")
       .short('d')
       .long("dir")
-      .required_unless_present_any(&["file", "url", "broker-url"])
+      .required_unless_present_any(["file", "url", "broker-url"])
       .action(ArgAction::Append)
       .value_parser(clap::builder::NonEmptyStringValueParser::new())
       .help("Directory of pact files to load (can be repeated)"))
@@ -294,7 +633,7 @@ fn build_args() -> Command {
     .arg(Arg::new("url")
       .short('u')
       .long("url")
-      .required_unless_present_any(&["file", "dir", "broker-url"])
+      .required_unless_present_any(["file", "dir", "broker-url"])
       .action(ArgAction::Append)
       .value_parser(clap::builder::NonEmptyStringValueParser::new())
       .help("URL of pact file to fetch (can be repeated)"))
@@ -302,7 +641,7 @@ fn build_args() -> Command {
       .short('b')
       .long("broker-url")
       .env("PACT_BROKER_BASE_URL")
-      .required_unless_present_any(&["file", "dir", "url"])
+      .required_unless_present_any(["file", "dir", "url"])
       .value_parser(clap::builder::NonEmptyStringValueParser::new())
       .help("URL of the pact broker to fetch pacts from"))
     .arg(Arg::new("user")
@@ -322,6 +661,101 @@ fn build_args() -> Command {
       .use_value_delimiter(false)
       .help("Port to run on (defaults to random port assigned by the OS)")
       .value_parser(integer_value))
+    .arg(Arg::new("host")
+      .long("host")
+      .alias("bind")
+      .action(ArgAction::Append)
+      .value_parser(ip_address_value)
+      .help("IPv4 or IPv6 address to bind the server to (can be repeated, defaults to 0.0.0.0 for all IPv4 interfaces)"))
+    .arg(Arg::new("unix-socket")
+      .long("unix-socket")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Path of a Unix domain socket to listen on. If no port or host is given, the server will not listen on TCP"))
+    .arg(Arg::new("port-file")
+      .long("port-file")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("File to write the port the server is bound to once it has started"))
+    .arg(Arg::new("ready-json")
+      .long("ready-json")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("File to write a JSON report to once the server has started, with the bound addresses and a summary of the loaded interactions"))
+    .arg(Arg::new("http1-only")
+      .long("http1-only")
+      .action(ArgAction::SetTrue)
+      .conflicts_with("http2-only")
+      .help("Only accept HTTP/1 connections (disables HTTP/2 and h2c)"))
+    .arg(Arg::new("http2-only")
+      .long("http2-only")
+      .action(ArgAction::SetTrue)
+      .conflicts_with("http1-only")
+      .help("Only accept HTTP/2 connections (h2 via ALPN with TLS, otherwise prior knowledge h2c)"))
+    .arg(Arg::new("tls-cert")
+      .long("tls-cert")
+      .requires("tls-key")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("PEM file with the certificate chain to use to serve requests over TLS (HTTPS)"))
+    .arg(Arg::new("tls-key")
+      .long("tls-key")
+      .requires("tls-cert")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("PEM file with the private key to use to serve requests over TLS (HTTPS)"))
+    .arg(Arg::new("grpc-port")
+      .long("grpc-port")
+      .use_value_delimiter(false)
+      .help("Port to run a gRPC (HTTP/2) server on that responds using the V4 synchronous message interactions")
+      .value_parser(integer_value))
+    .arg(Arg::new("drain-timeout")
+      .long("drain-timeout")
+      .value_parser(clap::value_parser!(u64))
+      .help("Seconds to wait for in-flight requests to complete when shutting down (defaults to 5). \
+      If they do not complete in time, the server exits with exit code 4"))
+    .arg(Arg::new("shutdown-endpoint")
+      .long("shutdown-endpoint")
+      .action(ArgAction::SetTrue)
+      .help("Enables the /__admin/shutdown endpoint that gracefully shuts down the server when sent a POST request"))
+    .arg(Arg::new("health-path")
+      .long("health-path")
+      .default_value(DEFAULT_HEALTH_PATH)
+      .value_parser(path_value)
+      .help("Base path of the health endpoints. Liveness is reported at <path>/live and readiness at <path> and <path>/ready"))
+    .arg(Arg::new("metrics-path")
+      .long("metrics-path")
+      .default_value(DEFAULT_METRICS_PATH)
+      .value_parser(path_value)
+      .help("Path of the Prometheus metrics endpoint"))
+    .arg(Arg::new("coverage-report")
+      .long("coverage-report")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("File to write the interaction coverage report to when the server shuts down (JUnit XML if the file has a .xml extension, otherwise JSON)"))
+    .arg(Arg::new("otlp-endpoint")
+      .long("otlp-endpoint")
+      .env("OTEL_EXPORTER_OTLP_ENDPOINT")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("URL of an OpenTelemetry collector to export request traces to using OTLP over HTTP (e.g. http://localhost:4318)"))
+    .arg(Arg::new("admin-api")
+      .long("admin-api")
+      .action(ArgAction::SetTrue)
+      .help("Enables the /__admin/sessions and /__admin/interactions endpoints that manage the sessions and the interactions registered at runtime, and the /__messages/publish endpoint"))
+    .arg(Arg::new("session-header")
+      .long("session-header")
+      .default_value(DEFAULT_SESSION_HEADER)
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Request header used to select the session for a request"))
+    .arg(Arg::new("session-cookie")
+      .long("session-cookie")
+      .default_value(DEFAULT_SESSION_COOKIE)
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Cookie used to select the session for a request, if the session header is not set"))
+    .arg(Arg::new("session-timeout")
+      .long("session-timeout")
+      .value_parser(clap::value_parser!(u64))
+      .help("Seconds a session can be idle for before it expires (defaults to 600)"))
+    .arg(Arg::new("registered-priority")
+      .long("registered-priority")
+      .default_value("1")
+      .allow_negative_numbers(true)
+      .value_parser(clap::value_parser!(i32))
+      .help("Priority of interactions registered at runtime over the interactions loaded from pact files (which have a priority of 0)"))
     .arg(Arg::new("cors")
       .short('o')
       .long("cors")
@@ -332,6 +766,35 @@ fn build_args() -> Command {
       .requires("cors")
       .action(ArgAction::SetTrue)
       .help("Set the CORS Access-Control-Allow-Origin header to the Referer"))
+    .arg(Arg::new("cors-origin")
+      .long("cors-origin")
+      .action(ArgAction::Append)
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Origin allowed by the CORS policy, which is returned in the Access-Control-Allow-Origin header instead of * (can be repeated)"))
+    .arg(Arg::new("cors-origin-regex")
+      .long("cors-origin-regex")
+      .value_parser(regex_value)
+      .help("Regular expression that must match the whole origin for the CORS policy to allow it"))
+    .arg(Arg::new("cors-credentials")
+      .long("cors-credentials")
+      .action(ArgAction::SetTrue)
+      .help("Allow CORS requests with credentials, returning the Access-Control-Allow-Credentials header and the origin of the request instead of *"))
+    .arg(Arg::new("cors-expose-headers")
+      .long("cors-expose-headers")
+      .action(ArgAction::Append)
+      .value_delimiter(',')
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Response headers to expose to browsers with the Access-Control-Expose-Headers header (can be repeated or comma separated)"))
+    .arg(Arg::new("cors-max-age")
+      .long("cors-max-age")
+      .requires("cors")
+      .value_parser(clap::value_parser!(u64))
+      .help("Seconds browsers can cache CORS pre-flight responses for, returned in the Access-Control-Max-Age header"))
+    .arg(Arg::new("no-cors-headers")
+      .long("no-cors-headers")
+      .action(ArgAction::SetTrue)
+      .conflicts_with_all(["cors", "cors-origin", "cors-origin-regex", "cors-credentials", "cors-expose-headers"])
+      .help("Do not add CORS headers to responses (interactions can still return their own)"))
     .arg(Arg::new("insecure-tls")
       .long("insecure-tls")
       .action(ArgAction::SetTrue)
@@ -346,11 +809,80 @@ fn build_args() -> Command {
       .value_parser(clap::builder::NonEmptyStringValueParser::new())
       .help("Name of the header parameter containing the provider state to be used in case \
       multiple matching interactions are found"))
+    .arg(Arg::new("provider-state-query-param")
+      .long("provider-state-query-param")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Name of the query parameter containing the provider state to be used in case \
+      multiple matching interactions are found"))
+    .arg(Arg::new("provider-state-cookie")
+      .long("provider-state-cookie")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Name of the cookie containing the provider state to be used in case \
+      multiple matching interactions are found"))
     .arg(Arg::new("empty-provider-state")
       .long("empty-provider-state")
       .requires("provider-state")
       .action(ArgAction::SetTrue)
       .help("Include empty provider states when filtering with --provider-state"))
+    .arg(Arg::new("interaction-header-name")
+      .long("interaction-header-name")
+      .default_value(DEFAULT_INTERACTION_HEADER)
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Name of the header containing the key, description or index of the interaction to respond with"))
+    .arg(Arg::new("strict")
+      .long("strict")
+      .action(ArgAction::SetTrue)
+      .help("Strict matching, where any mismatch with a request (including headers and bodies) rejects an interaction"))
+    .arg(Arg::new("match")
+      .long("match")
+      .action(ArgAction::Append)
+      .value_delimiter(',')
+      .value_parser(["query", "headers", "body"])
+      .help("Request categories to enforce when matching, in addition to the method, path and query parameters (can be repeated or comma separated)"))
+    .arg(Arg::new("version-header")
+      .long("version-header")
+      .action(ArgAction::Append)
+      .default_value(DEFAULT_VERSION_HEADER)
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Name of a request header that selects the version of an API, used to negotiate between interactions (can be repeated)"))
+    .arg(Arg::new("strict-negotiation")
+      .long("strict-negotiation")
+      .action(ArgAction::SetTrue)
+      .help("Respond with a 406 Not Acceptable when none of the matching interactions are acceptable to the Accept, Accept-Language or version headers of the request"))
+    .arg(Arg::new("compress-responses")
+      .long("compress-responses")
+      .action(ArgAction::SetTrue)
+      .help("Compress response bodies with the encoding (gzip, br or deflate) the request prefers in its Accept-Encoding header"))
+    .arg(Arg::new("compression-min-size")
+      .long("compression-min-size")
+      .requires("compress-responses")
+      .value_parser(clap::value_parser!(usize))
+      .help("Minimum size of a response body (in bytes) that is compressed with --compress-responses (defaults to 1024)"))
+    .arg(Arg::new("max-decoded-body-size")
+      .long("max-decoded-body-size")
+      .value_parser(clap::value_parser!(usize))
+      .help("Maximum size of a compressed request body (in bytes) once it is decoded, larger bodies get a 413 Payload Too Large response (defaults to 10485760)"))
+    .arg(Arg::new("strip-base-path")
+      .long("strip-base-path")
+      .conflicts_with("add-base-path")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Base path to remove from the start of request and interaction paths before matching, so paths with and without it are the same"))
+    .arg(Arg::new("add-base-path")
+      .long("add-base-path")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Base path to add to the start of request and interaction paths that do not have it before matching, so paths with and without it are the same (except for interactions with path matching rules)"))
+    .arg(Arg::new("ignore-trailing-slash")
+      .long("ignore-trailing-slash")
+      .action(ArgAction::SetTrue)
+      .help("Ignore trailing slashes in request and interaction paths"))
+    .arg(Arg::new("decode-paths")
+      .long("decode-paths")
+      .action(ArgAction::SetTrue)
+      .help("Percent-decode request and interaction paths before matching"))
+    .arg(Arg::new("case-insensitive-paths")
+      .long("case-insensitive-paths")
+      .action(ArgAction::SetTrue)
+      .help("Ignore the case of request and interaction paths"))
     .arg(Arg::new("consumer-name")
       .long("consumer-name")
       .alias("consumer-names")
@@ -365,6 +897,16 @@ fn build_args() -> Command {
       .action(ArgAction::Append)
       .value_parser(regex_value)
       .help("Provider name or regex to use to filter the Pacts fetched from the Pact broker (can be repeated)"))
+    .arg(Arg::new("message-webhook")
+      .long("message-webhook")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .conflicts_with("message-tcp")
+      .help("URL of a webhook to POST V4 message interactions to when requested via the /__messages/publish endpoint"))
+    .arg(Arg::new("message-tcp")
+      .long("message-tcp")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .conflicts_with("message-webhook")
+      .help("Address (host:port) of a TCP socket to write V4 message interactions to when requested via the /__messages/publish endpoint"))
     .arg(Arg::new("version")
       .short('v')
       .long("version")
@@ -372,16 +914,23 @@ fn build_args() -> Command {
       .help("Print version information"))
 }
 
-fn setup_logger(level: &str) {
+fn setup_logger(level: &str, format: LogFormat, log_file: Option<RotatingFile>, otlp_endpoint: Option<&String>) {
   let log_level = match level {
     "none" => LevelFilter::OFF,
     _ => LevelFilter::from_str(level).unwrap_or(LevelFilter::INFO)
   };
-  let subscriber = FmtSubscriber::builder()
-    .compact()
-    .with_max_level(log_level)
-    .with_thread_names(true)
-    .finish();
+  let fmt_layer = logging::fmt_layer(format, log_file)
+    .with_filter(log_level);
+  let otlp_layer = otlp_endpoint.and_then(|endpoint| match telemetry::otlp_layer(endpoint) {
+    Ok(layer) => Some(layer),
+    Err(err) => {
+      eprintln!("ERROR: Failed to set up the OTLP trace exporter - {err}");
+      None
+    }
+  });
+  let subscriber = tracing_subscriber::registry()
+    .with(fmt_layer)
+    .with(otlp_layer);
   if let Err(err) = tracing::subscriber::set_global_default(subscriber) {
     eprintln!("ERROR: Failed to initialise global tracing subscriber - {err}");
   };
@@ -0,0 +1,217 @@
+//! Functions relating to which mismatches with a request reject a candidate interaction. By default,
+//! only method, path and query parameter mismatches reject an interaction (and body mismatches, for
+//! requests with a body that support one), so the stub server will try to return a response even when
+//! the request does not exactly match. Header and body matching can be enforced for each category,
+//! or for everything with strict matching.
+
+use std::fmt::{Display, Formatter};
+
+use pact_matching::Mismatch;
+use pact_models::v4::http_parts::HttpRequest;
+
+use crate::negotiation::Negotiation;
+
+/// Parts of the request that can be enforced when matching
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchCategory {
+  /// Query parameters (always enforced)
+  Query,
+  /// Headers that the interaction expects
+  Headers,
+  /// Request bodies for all methods, including a body sent when the interaction does not expect one
+  Body
+}
+
+impl MatchCategory {
+  /// Returns the category with the given name
+  pub fn from_name(name: &str) -> Option<MatchCategory> {
+    match name.to_lowercase().as_str() {
+      "query" => Some(MatchCategory::Query),
+      "headers" => Some(MatchCategory::Headers),
+      "body" => Some(MatchCategory::Body),
+      _ => None
+    }
+  }
+}
+
+/// Which mismatches reject a candidate interaction, and how candidates are negotiated with the request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MatchSettings {
+  headers: bool,
+  body: bool,
+  strict: bool,
+  negotiation: Negotiation
+}
+
+impl MatchSettings {
+  /// Settings where any mismatch rejects a candidate interaction
+  pub fn strict() -> MatchSettings {
+    MatchSettings {
+      headers: true,
+      body: true,
+      strict: true,
+      negotiation: Negotiation::default()
+    }
+  }
+
+  /// Settings that enforce the given categories, in addition to the method, path and query parameters
+  pub fn with_categories(categories: &[MatchCategory]) -> MatchSettings {
+    MatchSettings {
+      headers: categories.contains(&MatchCategory::Headers),
+      body: categories.contains(&MatchCategory::Body),
+      strict: false,
+      negotiation: Negotiation::default()
+    }
+  }
+
+  /// Sets the content negotiation between candidate interactions
+  pub fn with_negotiation(self, negotiation: Negotiation) -> MatchSettings {
+    MatchSettings {
+      negotiation,
+      .. self
+    }
+  }
+
+  /// Content negotiation between candidate interactions
+  pub fn negotiation(&self) -> &Negotiation {
+    &self.negotiation
+  }
+
+  /// Returns the mismatches with the request that reject the interaction, including a body sent when
+  /// the interaction does not expect one (if bodies are enforced)
+  pub fn rejecting_mismatches(
+    &self,
+    expected: &HttpRequest,
+    request: &HttpRequest,
+    mismatches: &[Mismatch]
+  ) -> Vec<Mismatch> {
+    let mut rejecting = mismatches.iter()
+      .filter(|mismatch| self.rejects(mismatch, request))
+      .cloned()
+      .collect::<Vec<_>>();
+    if self.body && !expected.body.is_present() && has_body(request) {
+      rejecting.push(Mismatch::BodyMismatch {
+        path: "$".to_string(),
+        expected: None,
+        actual: request.body.value(),
+        mismatch: format!("Expected no body but received a body of {} bytes",
+          request.body.value().map(|body| body.len()).unwrap_or_default())
+      });
+    }
+    rejecting
+  }
+
+  /// If the mismatch rejects the candidate interaction
+  pub fn rejects(&self, mismatch: &Mismatch, request: &HttpRequest) -> bool {
+    match mismatch {
+      Mismatch::MethodMismatch { .. } => true,
+      Mismatch::PathMismatch { .. } => true,
+      Mismatch::QueryMismatch { .. } => true,
+      Mismatch::HeaderMismatch { .. } => self.headers,
+      Mismatch::BodyMismatch { .. } => self.body || (method_supports_payload(request) && request.body.is_present()),
+      Mismatch::BodyTypeMismatch { .. } => self.body,
+      _ => self.strict
+    }
+  }
+}
+
+impl Display for MatchSettings {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    if self.strict {
+      write!(f, "strict")
+    } else {
+      let mut categories = vec!["method", "path", "query"];
+      if self.headers {
+        categories.push("headers");
+      }
+      if self.body {
+        categories.push("body");
+      }
+      write!(f, "{}", categories.join(", "))
+    }
+  }
+}
+
+fn method_supports_payload(request: &HttpRequest) -> bool {
+  matches!(request.method.to_uppercase().as_str(), "POST" | "PUT" | "PATCH")
+}
+
+fn has_body(request: &HttpRequest) -> bool {
+  request.body.value().map(|body| !body.is_empty()).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use pact_matching::Mismatch;
+  use pact_models::bodies::OptionalBody;
+  use pact_models::v4::http_parts::HttpRequest;
+
+  use super::*;
+
+  fn header_mismatch() -> Mismatch {
+    Mismatch::HeaderMismatch {
+      key: "Accept".to_string(),
+      expected: "application/json".to_string(),
+      actual: "text/html".to_string(),
+      mismatch: String::default()
+    }
+  }
+
+  fn body_mismatch() -> Mismatch {
+    Mismatch::BodyMismatch { path: "$.id".to_string(), expected: None, actual: None, mismatch: String::default() }
+  }
+
+  fn request(method: &str, body: &str) -> HttpRequest {
+    HttpRequest {
+      method: method.to_string(),
+      body: if body.is_empty() { OptionalBody::Missing } else { OptionalBody::Present(body.to_string().into(), None, None) },
+      .. HttpRequest::default()
+    }
+  }
+
+  #[test]
+  fn default_settings_tolerate_header_and_most_body_mismatches() {
+    let settings = MatchSettings::default();
+    expect!(settings.rejects(&header_mismatch(), &request("GET", ""))).to(be_false());
+    expect!(settings.rejects(&body_mismatch(), &request("GET", "{}"))).to(be_false());
+    expect!(settings.rejects(&body_mismatch(), &request("POST", "{}"))).to(be_true());
+    expect!(settings.rejects(&Mismatch::PathMismatch {
+      expected: "/a".to_string(), actual: "/b".to_string(), mismatch: String::default()
+    }, &request("GET", ""))).to(be_true());
+    expect!(settings.rejecting_mismatches(&request("GET", ""), &request("GET", "{}"), &[])).to(be_equal_to(vec![]));
+  }
+
+  #[test]
+  fn categories_enforce_headers_and_bodies() {
+    let settings = MatchSettings::with_categories(&[MatchCategory::Headers]);
+    expect!(settings.rejects(&header_mismatch(), &request("GET", ""))).to(be_true());
+    expect!(settings.rejects(&body_mismatch(), &request("GET", "{}"))).to(be_false());
+
+    let settings = MatchSettings::with_categories(&[MatchCategory::Body]);
+    expect!(settings.rejects(&header_mismatch(), &request("GET", ""))).to(be_false());
+    expect!(settings.rejects(&body_mismatch(), &request("DELETE", "{}"))).to(be_true());
+    let rejecting = settings.rejecting_mismatches(&request("GET", ""), &request("GET", "{}"), &[]);
+    expect!(rejecting.len()).to(be_equal_to(1));
+    expect!(settings.rejecting_mismatches(&request("POST", "{}"), &request("POST", "{}"), &[]).is_empty()).to(be_true());
+  }
+
+  #[test]
+  fn strict_settings_reject_any_mismatch() {
+    let settings = MatchSettings::strict();
+    expect!(settings.rejects(&header_mismatch(), &request("GET", ""))).to(be_true());
+    expect!(settings.rejects(&body_mismatch(), &request("GET", ""))).to(be_true());
+    expect!(settings.rejects(&Mismatch::MetadataMismatch {
+      key: "a".to_string(), expected: "1".to_string(), actual: "2".to_string(), mismatch: String::default()
+    }, &request("GET", ""))).to(be_true());
+    expect!(settings.to_string()).to(be_equal_to("strict"));
+    expect!(MatchSettings::with_categories(&[MatchCategory::Body]).to_string()).to(be_equal_to("method, path, query, body"));
+  }
+
+  #[test]
+  fn category_names() {
+    expect!(MatchCategory::from_name("Headers")).to(be_some().value(MatchCategory::Headers));
+    expect!(MatchCategory::from_name("body")).to(be_some().value(MatchCategory::Body));
+    expect!(MatchCategory::from_name("cookies")).to(be_none());
+  }
+}
@@ -0,0 +1,284 @@
+//! Functions relating to publishing V4 asynchronous message interactions to a target
+
+use std::fmt::{Display, Formatter};
+
+use anyhow::anyhow;
+use http::StatusCode;
+use hyper::{Body, Response as HyperResponse};
+use hyper::body::Bytes;
+use maplit::hashmap;
+use pact_matching::generators::apply_generators_to_async_message;
+use pact_models::generators::GeneratorTestMode;
+use pact_models::prelude::*;
+use pact_models::prelude::v4::*;
+use pact_models::v4::async_message::AsynchronousMessage;
+use pact_models::v4::message_parts::MessageContents;
+use pact_models::v4::V4InteractionType;
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+use crate::PactSource;
+
+/// Path of the admin endpoint used to publish messages
+pub const PUBLISH_PATH: &str = "/__messages/publish";
+
+/// Target that generated messages are delivered to
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageTarget {
+  /// POST the message contents to a HTTP webhook
+  Webhook(String),
+  /// Write the message as a line of JSON to a TCP socket (host:port)
+  Tcp(String)
+}
+
+impl Display for MessageTarget {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      MessageTarget::Webhook(url) => write!(f, "webhook {}", url),
+      MessageTarget::Tcp(address) => write!(f, "TCP socket {}", address)
+    }
+  }
+}
+
+// Finds the first asynchronous message that matches the description and provider state
+fn find_message(
+  sources: &[(V4Pact, PactSource)],
+  description: Option<&str>,
+  provider_state: Option<&str>
+) -> Option<AsynchronousMessage> {
+  sources.iter()
+    .flat_map(|(pact, _)| pact.filter_interactions(V4InteractionType::Asynchronous_Messages))
+    .filter_map(|i| i.as_v4_async_message())
+    .find(|message| {
+      description.map(|d| message.description == d).unwrap_or(true) &&
+        provider_state.map(|state| message.provider_states.iter().any(|ps| ps.name == state))
+          .unwrap_or(true)
+    })
+}
+
+fn metadata_value_to_string(value: &Value) -> String {
+  match value {
+    Value::String(s) => s.clone(),
+    _ => value.to_string()
+  }
+}
+
+async fn send_to_webhook(url: &str, contents: &MessageContents) -> anyhow::Result<()> {
+  let client = reqwest::Client::builder().build()?;
+  let mut req = client.post(url);
+  if let Some(content_type) = contents.message_content_type() {
+    req = req.header("Content-Type", content_type.to_string());
+  }
+  for (key, value) in &contents.metadata {
+    if key.eq_ignore_ascii_case("contentType") || key.eq_ignore_ascii_case("content-type") {
+      continue;
+    }
+    match http::header::HeaderName::from_bytes(key.as_bytes()) {
+      Ok(name) => req = req.header(name, metadata_value_to_string(value)),
+      Err(_) => warn!("Message metadata key '{}' is not a valid HTTP header name, not sending it", key)
+    }
+  }
+  let body = contents.contents.value().unwrap_or_default();
+  let response = req.body(body).send().await?;
+  if response.status().is_success() {
+    Ok(())
+  } else {
+    Err(anyhow!("Webhook responded with status {}", response.status()))
+  }
+}
+
+fn message_json(message: &AsynchronousMessage, contents: &MessageContents) -> Value {
+  let body = match &contents.contents {
+    OptionalBody::Present(bytes, _, _) => if contents.message_content_type().map(|ct| ct.is_json()).unwrap_or(false) {
+      serde_json::from_slice(bytes).unwrap_or_else(|_| Value::String(contents.contents.display_string()))
+    } else {
+      Value::String(contents.contents.display_string())
+    },
+    _ => Value::Null
+  };
+  json!({
+    "description": message.description,
+    "contents": body,
+    "metadata": contents.metadata
+  })
+}
+
+async fn send_to_tcp(address: &str, message: &AsynchronousMessage, contents: &MessageContents) -> anyhow::Result<()> {
+  let mut stream = TcpStream::connect(address).await?;
+  let mut line = message_json(message, contents).to_string();
+  line.push('\n');
+  stream.write_all(line.as_bytes()).await?;
+  stream.shutdown().await?;
+  Ok(())
+}
+
+/// Generates the contents of the message and delivers it to the target
+pub async fn publish_message(message: &AsynchronousMessage, target: &MessageTarget) -> anyhow::Result<MessageContents> {
+  let contents = apply_generators_to_async_message(message, &GeneratorTestMode::Provider,
+    &hashmap!{}, &vec![], &hashmap!{}).await;
+  debug!("Publishing message '{}' to {}", message.description, target);
+  match target {
+    MessageTarget::Webhook(url) => send_to_webhook(url, &contents).await?,
+    MessageTarget::Tcp(address) => send_to_tcp(address, message, &contents).await?
+  }
+  Ok(contents)
+}
+
+fn json_response(status: StatusCode, body: Value) -> Result<HyperResponse<Body>, http::Error> {
+  HyperResponse::builder()
+    .status(status)
+    .header("Content-Type", "application/json")
+    .body(Body::from(body.to_string()))
+}
+
+/// Handles a request to the message publish endpoint. The request body must be a JSON document
+/// with a `description` and/or `providerState` attribute used to select the message to publish.
+pub async fn handle_publish_request(
+  body: &Bytes,
+  sources: &[(V4Pact, PactSource)],
+  target: &MessageTarget
+) -> Result<HyperResponse<Body>, http::Error> {
+  let json: Value = match serde_json::from_slice(body) {
+    Ok(json) => json,
+    Err(err) => return json_response(StatusCode::BAD_REQUEST,
+      json!({ "error": format!("Request body is not valid JSON: {}", err) }))
+  };
+  let description = json.get("description").and_then(|v| v.as_str());
+  let provider_state = json.get("providerState").and_then(|v| v.as_str());
+  if description.is_none() && provider_state.is_none() {
+    return json_response(StatusCode::BAD_REQUEST,
+      json!({ "error": "Either a description or providerState is required to select the message" }));
+  }
+
+  match find_message(sources, description, provider_state) {
+    Some(message) => match publish_message(&message, target).await {
+      Ok(_) => {
+        info!("Published message '{}' to {}", message.description, target);
+        json_response(StatusCode::OK, json!({
+          "description": message.description,
+          "target": target.to_string()
+        }))
+      },
+      Err(err) => {
+        warn!("Failed to publish message '{}' to {}: {}", message.description, target, err);
+        json_response(StatusCode::BAD_GATEWAY, json!({
+          "error": format!("Failed to publish message '{}' to {}: {}", message.description, target, err)
+        }))
+      }
+    },
+    None => json_response(StatusCode::NOT_FOUND, json!({ "error": "No matching message interaction was found" }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use pact_models::prelude::*;
+  use pact_models::prelude::v4::*;
+  use pact_models::v4::async_message::AsynchronousMessage;
+  use pact_models::v4::interaction::V4Interaction;
+  use pact_models::v4::message_parts::MessageContents;
+  use serde_json::{json, Value};
+  use tokio::io::AsyncReadExt;
+  use tokio::net::TcpListener;
+
+  use crate::PactSource;
+
+  use super::*;
+
+  fn message(description: &str, state: Option<&str>, contents: &str) -> AsynchronousMessage {
+    AsynchronousMessage {
+      description: description.to_string(),
+      provider_states: state.map(|s| vec![ProviderState::default(s)]).unwrap_or_default(),
+      contents: MessageContents {
+        contents: OptionalBody::Present(contents.as_bytes().to_vec().into(), Some("application/json".into()), None),
+        .. MessageContents::default()
+      },
+      .. AsynchronousMessage::default()
+    }
+  }
+
+  fn sources() -> Vec<(V4Pact, PactSource)> {
+    let pact = V4Pact {
+      interactions: vec![
+        SynchronousHttp::default().boxed_v4(),
+        message("order created", Some("an order exists"), "{\"id\": 1}").boxed_v4(),
+        message("order cancelled", Some("an order exists"), "{\"id\": 2}").boxed_v4()
+      ],
+      .. V4Pact::default()
+    };
+    vec![(pact, PactSource::Unknown)]
+  }
+
+  #[test]
+  fn find_message_by_description() {
+    let result = find_message(&sources(), Some("order cancelled"), None);
+    expect!(result.map(|m| m.description)).to(be_some().value("order cancelled"));
+  }
+
+  #[test]
+  fn find_message_by_provider_state() {
+    let result = find_message(&sources(), None, Some("an order exists"));
+    expect!(result.map(|m| m.description)).to(be_some().value("order created"));
+    expect!(find_message(&sources(), None, Some("no orders"))).to(be_none());
+  }
+
+  #[test]
+  fn find_message_by_description_and_provider_state() {
+    expect!(find_message(&sources(), Some("order cancelled"), Some("an order exists"))).to(be_some());
+    expect!(find_message(&sources(), Some("order cancelled"), Some("no orders"))).to(be_none());
+  }
+
+  #[tokio::test]
+  async fn publish_request_requires_a_selector() {
+    let target = MessageTarget::Tcp("127.0.0.1:1".to_string());
+    let response = handle_publish_request(&Bytes::from("{}"), &sources(), &target).await.unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::BAD_REQUEST));
+    let response = handle_publish_request(&Bytes::from("not json"), &sources(), &target).await.unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::BAD_REQUEST));
+  }
+
+  #[tokio::test]
+  async fn publish_request_returns_not_found_if_there_is_no_matching_message() {
+    let target = MessageTarget::Tcp("127.0.0.1:1".to_string());
+    let response = handle_publish_request(&Bytes::from("{\"description\": \"unknown\"}"), &sources(), &target)
+      .await.unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::NOT_FOUND));
+  }
+
+  #[tokio::test]
+  async fn publish_message_writes_the_message_to_a_tcp_socket() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = MessageTarget::Tcp(listener.local_addr().unwrap().to_string());
+    let server = tokio::spawn(async move {
+      let (mut socket, _) = listener.accept().await.unwrap();
+      let mut buffer = String::new();
+      socket.read_to_string(&mut buffer).await.unwrap();
+      buffer
+    });
+
+    let response = handle_publish_request(&Bytes::from("{\"description\": \"order created\"}"), &sources(), &target)
+      .await.unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::OK));
+
+    let received: Value = serde_json::from_str(server.await.unwrap().trim()).unwrap();
+    expect!(received).to(be_equal_to(json!({
+      "description": "order created",
+      "contents": { "id": 1 },
+      "metadata": {}
+    })));
+  }
+
+  #[tokio::test]
+  async fn publish_request_returns_bad_gateway_if_delivery_fails() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let target = MessageTarget::Tcp(address);
+    let response = handle_publish_request(&Bytes::from("{\"description\": \"order created\"}"), &sources(), &target)
+      .await.unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::BAD_GATEWAY));
+  }
+}
@@ -0,0 +1,202 @@
+//! Prometheus metrics for the requests handled by the stub server
+
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http::StatusCode;
+use hyper::{Body, Response as HyperResponse};
+use pact_models::prelude::v4::*;
+use prometheus::{
+  Encoder,
+  Gauge,
+  Histogram,
+  HistogramOpts,
+  IntCounterVec,
+  IntGauge,
+  Opts,
+  Registry,
+  TextEncoder
+};
+use tracing::error;
+
+/// Default path of the metrics endpoint
+pub const DEFAULT_METRICS_PATH: &str = "/__metrics";
+
+struct Metrics {
+  registry: Registry,
+  requests: IntCounterVec,
+  match_duration: Histogram,
+  match_candidates: Histogram,
+  interaction_hits: IntCounterVec,
+  pacts_loaded: IntGauge,
+  interactions_loaded: IntGauge,
+  pact_load_errors: IntGauge,
+  last_load_timestamp: Gauge
+}
+
+impl Metrics {
+  fn new() -> prometheus::Result<Metrics> {
+    let registry = Registry::new_custom(Some("pact_stub".to_string()), None)?;
+    let requests = IntCounterVec::new(
+      Opts::new("requests_total", "Requests received, by response status and if they matched an interaction (or were CORS pre-flight requests)"),
+      &["status", "result"])?;
+    let match_duration = Histogram::with_opts(HistogramOpts::new("match_duration_seconds",
+      "Time taken to match a request against the candidate interactions"))?;
+    let match_candidates = Histogram::with_opts(HistogramOpts::new("match_candidates",
+      "Number of candidate interactions a request was matched against")
+      .buckets(vec![0.0, 1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0]))?;
+    let interaction_hits = IntCounterVec::new(
+      Opts::new("interaction_hits_total", "Requests that were responded to with the interaction"),
+      &["consumer", "provider", "description"])?;
+    let pacts_loaded = IntGauge::new("pacts_loaded", "Number of pacts loaded")?;
+    let interactions_loaded = IntGauge::new("interactions_loaded", "Number of interactions loaded")?;
+    let pact_load_errors = IntGauge::new("pact_load_errors", "Number of errors from the last time the pacts were loaded")?;
+    let last_load_timestamp = Gauge::new("pact_load_timestamp_seconds", "Time the pacts were last loaded")?;
+
+    registry.register(Box::new(requests.clone()))?;
+    registry.register(Box::new(match_duration.clone()))?;
+    registry.register(Box::new(match_candidates.clone()))?;
+    registry.register(Box::new(interaction_hits.clone()))?;
+    registry.register(Box::new(pacts_loaded.clone()))?;
+    registry.register(Box::new(interactions_loaded.clone()))?;
+    registry.register(Box::new(pact_load_errors.clone()))?;
+    registry.register(Box::new(last_load_timestamp.clone()))?;
+
+    Ok(Metrics {
+      registry,
+      requests,
+      match_duration,
+      match_candidates,
+      interaction_hits,
+      pacts_loaded,
+      interactions_loaded,
+      pact_load_errors,
+      last_load_timestamp
+    })
+  }
+}
+
+fn metrics() -> &'static Metrics {
+  static METRICS: OnceLock<Metrics> = OnceLock::new();
+  METRICS.get_or_init(|| Metrics::new().expect("metrics are registered with unique names"))
+}
+
+/// Result of handling a request, used to label the request metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestResult {
+  /// The request matched an interaction
+  Match,
+  /// The request did not match any interaction
+  Miss,
+  /// The request was a CORS pre-flight request answered by the stub server
+  Preflight
+}
+
+impl RequestResult {
+  fn label(&self) -> &'static str {
+    match self {
+      RequestResult::Match => "match",
+      RequestResult::Miss => "miss",
+      RequestResult::Preflight => "preflight"
+    }
+  }
+}
+
+/// Records a request that was handled, and its result
+pub fn record_request(status: u16, result: RequestResult) {
+  metrics().requests
+    .with_label_values(&[&status.to_string(), result.label()])
+    .inc();
+}
+
+/// Records the number of candidate interactions a request was matched against, and the time it took
+pub fn record_match(candidates: usize, duration: Duration) {
+  let metrics = metrics();
+  metrics.match_candidates.observe(candidates as f64);
+  metrics.match_duration.observe(duration.as_secs_f64());
+}
+
+/// Records that the interaction was used to respond to a request
+pub fn record_interaction_hit(pact: &V4Pact, interaction: &SynchronousHttp) {
+  metrics().interaction_hits
+    .with_label_values(&[&pact.consumer.name, &pact.provider.name, &interaction.description])
+    .inc();
+}
+
+/// Records the result of loading the pacts
+pub fn record_pact_load(pacts: usize, interactions: usize, errors: usize) {
+  let metrics = metrics();
+  metrics.pacts_loaded.set(pacts as i64);
+  metrics.interactions_loaded.set(interactions as i64);
+  metrics.pact_load_errors.set(errors as i64);
+  metrics.last_load_timestamp.set(SystemTime::now().duration_since(UNIX_EPOCH)
+    .map(|time| time.as_secs_f64())
+    .unwrap_or_default());
+}
+
+/// Returns the metrics in the Prometheus text format
+pub fn metrics_response() -> Result<HyperResponse<Body>, http::Error> {
+  let encoder = TextEncoder::new();
+  let mut buffer = vec![];
+  match encoder.encode(&metrics().registry.gather(), &mut buffer) {
+    Ok(_) => HyperResponse::builder()
+      .status(StatusCode::OK)
+      .header("Content-Type", encoder.format_type())
+      .body(Body::from(buffer)),
+    Err(err) => {
+      error!("Failed to encode the metrics: {}", err);
+      HyperResponse::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(Body::empty())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use pact_models::prelude::*;
+  use pact_models::prelude::v4::*;
+
+  use super::*;
+
+  async fn metrics_text() -> String {
+    let response = metrics_response().unwrap();
+    expect!(response.headers().get("Content-Type").unwrap().to_str().unwrap().starts_with("text/plain")).to(be_true());
+    String::from_utf8(hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn metrics_include_the_recorded_requests_and_interaction_hits() {
+    let pact = V4Pact {
+      consumer: Consumer { name: "metrics-consumer".to_string() },
+      provider: Provider { name: "metrics-provider".to_string() },
+      .. V4Pact::default()
+    };
+    let interaction = SynchronousHttp { description: "a request for metrics".to_string(), .. SynchronousHttp::default() };
+    record_interaction_hit(&pact, &interaction);
+    record_interaction_hit(&pact, &interaction);
+    record_request(299, RequestResult::Match);
+    record_request(499, RequestResult::Miss);
+    record_request(298, RequestResult::Preflight);
+    record_match(3, Duration::from_millis(2));
+
+    let text = metrics_text().await;
+    expect!(text.contains("pact_stub_interaction_hits_total{consumer=\"metrics-consumer\",description=\"a request for metrics\",provider=\"metrics-provider\"} 2"))
+      .to(be_true());
+    expect!(text.contains("pact_stub_requests_total{result=\"match\",status=\"299\"} 1")).to(be_true());
+    expect!(text.contains("pact_stub_requests_total{result=\"miss\",status=\"499\"} 1")).to(be_true());
+    expect!(text.contains("pact_stub_requests_total{result=\"preflight\",status=\"298\"} 1")).to(be_true());
+    expect!(text.contains("pact_stub_match_candidates_bucket{le=\"5\"}")).to(be_true());
+    expect!(text.contains("pact_stub_match_duration_seconds_count")).to(be_true());
+  }
+
+  #[tokio::test]
+  async fn metrics_include_the_pact_load_results() {
+    record_pact_load(2, 7, 0);
+    let text = metrics_text().await;
+    expect!(text.contains("pact_stub_pacts_loaded 2")).to(be_true());
+    expect!(text.contains("pact_stub_interactions_loaded 7")).to(be_true());
+    expect!(text.contains("pact_stub_pact_load_errors 0")).to(be_true());
+  }
+}
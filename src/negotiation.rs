@@ -0,0 +1,279 @@
+//! Functions relating to content negotiation between interactions that share a method and path, but
+//! return different representations. The `Accept` and `Accept-Language` headers of the request (with their
+//! quality values) are compared to the content type and language of each interaction's response, and any
+//! version headers of the request are compared to the same header of the interaction. Interactions that
+//! the request does not accept are ranked below the ones it does, and are only used if strict negotiation
+//! is not enabled.
+
+use std::fmt::{Display, Formatter};
+
+use pact_models::http_parts::HttpPart;
+use pact_models::prelude::v4::*;
+use pact_models::v4::http_parts::HttpRequest;
+
+/// Default header used to select the version of an API
+pub const DEFAULT_VERSION_HEADER: &str = "Accept-Version";
+
+// Quality values are kept as thousandths, which is the precision allowed by RFC 9110
+const MAX_QUALITY: u32 = 1000;
+
+/// Headers used to negotiate between interactions
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Negotiation {
+  version_headers: Vec<String>,
+  strict: bool
+}
+
+impl Negotiation {
+  /// Negotiation using the given version headers. With strict negotiation, interactions the request does
+  /// not accept are never used.
+  pub fn new(version_headers: &[String], strict: bool) -> Negotiation {
+    Negotiation {
+      version_headers: version_headers.to_vec(),
+      strict
+    }
+  }
+
+  /// If interactions the request does not accept are never used
+  pub fn is_strict(&self) -> bool {
+    self.strict
+  }
+
+  /// If the request has any headers used for negotiation
+  pub fn applies_to(&self, request: &HttpRequest) -> bool {
+    request_header(request, "Accept").is_some()
+      || request_header(request, "Accept-Language").is_some()
+      || self.version_headers.iter().any(|header| request_header(request, header).is_some())
+  }
+
+  /// Negotiates the representation of the interaction's response with the request
+  pub fn negotiate(&self, request: &HttpRequest, interaction: &SynchronousHttp) -> NegotiationResult {
+    let mut result = NegotiationResult::default();
+
+    if let Some(accept) = request_header(request, "Accept") {
+      if let Some(content_type) = interaction.response.content_type() {
+        let media_type = format!("{}/{}", content_type.main_type, content_type.sub_type).to_lowercase();
+        result.add(quality(&accept, |range| media_range_specificity(range, &media_type)));
+      }
+    }
+
+    if let Some(accept_language) = request_header(request, "Accept-Language") {
+      if let Some(content_language) = interaction.response.lookup_header_value("Content-Language") {
+        let qualities = content_language.split(',')
+          .map(|tag| tag.trim().to_lowercase())
+          .filter(|tag| !tag.is_empty())
+          .map(|tag| quality(&accept_language, |range| language_range_specificity(range, &tag)))
+          .collect::<Vec<_>>();
+        if !qualities.is_empty() {
+          result.add(qualities.into_iter().max().unwrap_or_default());
+        }
+      }
+    }
+
+    for header in &self.version_headers {
+      if let Some(version) = request_header(request, header) {
+        let interaction_version = interaction.request.lookup_header_value(header)
+          .or_else(|| interaction.response.lookup_header_value(header));
+        if let Some(interaction_version) = interaction_version {
+          let matches = interaction_version.trim().eq_ignore_ascii_case(version.trim());
+          result.add(if matches { MAX_QUALITY } else { 0 });
+        }
+      }
+    }
+
+    result
+  }
+}
+
+impl Display for Negotiation {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let mut headers = vec!["Accept".to_string(), "Accept-Language".to_string()];
+    headers.extend(self.version_headers.iter().cloned());
+    write!(f, "{}{}", headers.join(", "), if self.strict { " (strict)" } else { "" })
+  }
+}
+
+/// Result of negotiating the representation of an interaction with a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegotiationResult {
+  /// If the request accepts the representation (which it does if it has no negotiation headers)
+  pub acceptable: bool,
+  /// Sum of the quality values of the negotiated headers, in thousandths
+  pub quality: u32
+}
+
+impl NegotiationResult {
+  fn add(&mut self, quality: u32) {
+    if quality == 0 {
+      self.acceptable = false;
+    }
+    self.quality += quality;
+  }
+}
+
+impl Default for NegotiationResult {
+  fn default() -> Self {
+    NegotiationResult {
+      acceptable: true,
+      quality: 0
+    }
+  }
+}
+
+impl Display for NegotiationResult {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    if self.acceptable {
+      write!(f, "acceptable with quality {}", self.quality)
+    } else {
+      write!(f, "not acceptable")
+    }
+  }
+}
+
+fn request_header(request: &HttpRequest, name: &str) -> Option<String> {
+  request.lookup_header_value(name).filter(|value| !value.trim().is_empty())
+}
+
+/// Returns the quality (in thousandths) of the most specific range in a header with quality values that
+/// matches, or zero if none do. The specificity function returns how specific a range is, if it matches.
+pub fn quality<F>(header: &str, specificity: F) -> u32 where F: Fn(&str) -> Option<usize> {
+  header.split(',')
+    .filter_map(parse_range)
+    .filter_map(|(range, quality)| specificity(&range).map(|specificity| (specificity, quality)))
+    .max_by_key(|(specificity, _)| *specificity)
+    .map(|(_, quality)| quality)
+    .unwrap_or_default()
+}
+
+// Parses a range with its quality value (defaulting to 1). Ranges with an invalid quality are ignored.
+fn parse_range(value: &str) -> Option<(String, u32)> {
+  let mut parts = value.split(';').map(|part| part.trim());
+  let range = parts.next().filter(|range| !range.is_empty())?.to_lowercase();
+  let quality = match parts.find_map(|param| param.strip_prefix("q=").or_else(|| param.strip_prefix("Q="))) {
+    Some(q) => {
+      let q = q.trim().parse::<f64>().ok().filter(|q| (0.0..=1.0).contains(q))?;
+      (q * MAX_QUALITY as f64).round() as u32
+    },
+    None => MAX_QUALITY
+  };
+  Some((range, quality))
+}
+
+fn media_range_specificity(range: &str, media_type: &str) -> Option<usize> {
+  if range == "*/*" {
+    Some(0)
+  } else if let Some(main_type) = range.strip_suffix("/*") {
+    media_type.split('/').next().filter(|t| *t == main_type).map(|_| 1)
+  } else if range == media_type {
+    Some(2)
+  } else {
+    None
+  }
+}
+
+fn language_range_specificity(range: &str, tag: &str) -> Option<usize> {
+  if range == "*" {
+    Some(0)
+  } else if tag == range || tag.starts_with(&format!("{}-", range)) {
+    Some(range.len())
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use maplit::hashmap;
+  use pact_models::v4::http_parts::HttpResponse;
+
+  use super::*;
+
+  fn request(headers: &[(&str, &str)]) -> HttpRequest {
+    HttpRequest {
+      headers: Some(headers.iter()
+        .map(|(name, value)| (name.to_string(), vec![value.to_string()]))
+        .collect()),
+      .. HttpRequest::default()
+    }
+  }
+
+  fn interaction(content_type: &str, language: Option<&str>, version: Option<&str>) -> SynchronousHttp {
+    let mut headers = hashmap! { "Content-Type".to_string() => vec![content_type.to_string()] };
+    if let Some(language) = language {
+      headers.insert("Content-Language".to_string(), vec![language.to_string()]);
+    }
+    SynchronousHttp {
+      request: HttpRequest {
+        headers: version.map(|version| hashmap! { "Accept-Version".to_string() => vec![version.to_string()] }),
+        .. HttpRequest::default()
+      },
+      response: HttpResponse {
+        headers: Some(headers),
+        .. HttpResponse::default()
+      },
+      .. SynchronousHttp::default()
+    }
+  }
+
+  #[test]
+  fn requests_without_negotiation_headers_accept_everything() {
+    let negotiation = Negotiation::new(&[DEFAULT_VERSION_HEADER.to_string()], false);
+    let request = request(&[]);
+    expect!(negotiation.applies_to(&request)).to(be_false());
+    expect!(negotiation.negotiate(&request, &interaction("text/csv", Some("en"), Some("2"))))
+      .to(be_equal_to(NegotiationResult { acceptable: true, quality: 0 }));
+  }
+
+  #[test]
+  fn accept_header_quality_values_rank_content_types() {
+    let negotiation = Negotiation::default();
+    let request = request(&[("Accept", "text/csv;q=0.9, application/json;q=0.5, */*;q=0")]);
+    expect!(negotiation.applies_to(&request)).to(be_true());
+    let csv = negotiation.negotiate(&request, &interaction("text/csv", None, None));
+    let json = negotiation.negotiate(&request, &interaction("application/json; charset=UTF-8", None, None));
+    let xml = negotiation.negotiate(&request, &interaction("application/xml", None, None));
+    expect!(csv).to(be_equal_to(NegotiationResult { acceptable: true, quality: 900 }));
+    expect!(json).to(be_equal_to(NegotiationResult { acceptable: true, quality: 500 }));
+    expect!(xml.acceptable).to(be_false());
+  }
+
+  #[test]
+  fn the_most_specific_media_range_sets_the_quality() {
+    expect!(quality("text/*;q=0.3, text/html;q=0.7, */*;q=0.1", |range| media_range_specificity(range, "text/html")))
+      .to(be_equal_to(700));
+    expect!(quality("text/*;q=0.3, text/html;q=0.7, */*;q=0.1", |range| media_range_specificity(range, "text/plain")))
+      .to(be_equal_to(300));
+    expect!(quality("text/*;q=0.3, text/html;q=0.7, */*;q=0.1", |range| media_range_specificity(range, "image/png")))
+      .to(be_equal_to(100));
+    expect!(quality("text/html;q=abc", |range| media_range_specificity(range, "text/html"))).to(be_equal_to(0));
+  }
+
+  #[test]
+  fn accept_language_matches_language_prefixes() {
+    let negotiation = Negotiation::default();
+    let request = request(&[("Accept-Language", "de-CH, en;q=0.8")]);
+    expect!(negotiation.negotiate(&request, &interaction("application/json", Some("en-US"), None)))
+      .to(be_equal_to(NegotiationResult { acceptable: true, quality: 800 }));
+    expect!(negotiation.negotiate(&request, &interaction("application/json", Some("de"), None)).acceptable)
+      .to(be_false());
+    expect!(negotiation.negotiate(&request, &interaction("application/json", Some("fr, de-CH"), None)))
+      .to(be_equal_to(NegotiationResult { acceptable: true, quality: 1000 }));
+    expect!(negotiation.negotiate(&request, &interaction("application/json", None, None)))
+      .to(be_equal_to(NegotiationResult { acceptable: true, quality: 0 }));
+  }
+
+  #[test]
+  fn version_headers_must_match_the_interaction() {
+    let negotiation = Negotiation::new(&["Accept-Version".to_string()], true);
+    let request = request(&[("accept-version", "2")]);
+    expect!(negotiation.applies_to(&request)).to(be_true());
+    expect!(negotiation.negotiate(&request, &interaction("application/json", None, Some("2"))))
+      .to(be_equal_to(NegotiationResult { acceptable: true, quality: 1000 }));
+    expect!(negotiation.negotiate(&request, &interaction("application/json", None, Some("1"))).acceptable)
+      .to(be_false());
+    expect!(negotiation.negotiate(&request, &interaction("application/json", None, None)))
+      .to(be_equal_to(NegotiationResult { acceptable: true, quality: 0 }));
+    expect!(negotiation.to_string()).to(be_equal_to("Accept, Accept-Language, Accept-Version (strict)"));
+  }
+}
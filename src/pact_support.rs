@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use http::{Error, HeaderMap, Uri};
-use http::header::{ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_TYPE};
+use http::header::CONTENT_TYPE;
 use http::header::HeaderValue;
 use http::request::Parts;
 use hyper::{Body, Response as HyperResponse};
@@ -12,6 +12,8 @@ use pact_models::query_strings::parse_query_string;
 use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
 use tracing::{debug, info, warn};
 
+use crate::redaction;
+
 fn extract_query_string(uri: &Uri) -> Option<HashMap<String, Vec<String>>> {
     match uri.query() {
         Some(q) => parse_query_string(q),
@@ -19,7 +21,8 @@ fn extract_query_string(uri: &Uri) -> Option<HashMap<String, Vec<String>>> {
     }
 }
 
-fn extract_headers(headers: &HeaderMap<HeaderValue>) -> Option<HashMap<String, Vec<String>>> {
+/// Converts the request headers into the headers of a Pact request
+pub fn extract_headers(headers: &HeaderMap<HeaderValue>) -> Option<HashMap<String, Vec<String>>> {
   if !headers.is_empty() {
     let result: HashMap<String, Vec<String>> = headers.keys()
       .map(|name| {
@@ -31,8 +34,9 @@ fn extract_headers(headers: &HeaderMap<HeaderValue>) -> Option<HashMap<String, V
               warn!("Failed to parse HTTP header value: {}", err);
             })
           ).collect();
-        (name.as_str().into(), parsed_vals.iter().cloned()
+        (name.as_str().into(), parsed_vals.iter()
           .filter(|val| val.is_ok())
+          .cloned()
           .map(|val| val.unwrap_or_default())
           .collect())
       })
@@ -55,8 +59,9 @@ pub fn hyper_request_to_pact_request(req: Parts, body: OptionalBody) -> HttpRequ
 }
 
 pub fn pact_response_to_hyper_response(response: &HttpResponse) -> Result<HyperResponse<Body>, Error> {
-  info!("<=== Sending {}", response);
-  debug!("     body: '{}'", response.body.display_string());
+  let redacted = redaction::redact_response(response);
+  info!("<=== Sending {}", redacted);
+  debug!("     body: '{}'", redacted.body.display_string());
   debug!("     matching_rules: {:?}", response.matching_rules);
   debug!("     generators: {:?}", response.generators);
   let mut res = HyperResponse::builder().status(response.status);
@@ -69,11 +74,6 @@ pub fn pact_response_to_hyper_response(response: &HttpResponse) -> Result<HyperR
     }
   }
 
-  let allow_origin = ACCESS_CONTROL_ALLOW_ORIGIN;
-  if !response.has_header(allow_origin.as_str()) {
-    res = res.header(allow_origin, "*");
-  }
-
   match &response.body {
     OptionalBody::Present(ref body, content_type, _) => {
       let content_type_header = CONTENT_TYPE;
@@ -108,8 +108,7 @@ mod test {
       let hyper_response = pact_response_to_hyper_response(&response).unwrap();
 
       expect!(hyper_response.status()).to(be_equal_to(StatusCode::CREATED));
-      expect!(hyper_response.headers().len()).to(be_equal_to(1));
-      expect!(hyper_response.headers().get("Access-Control-Allow-Origin")).to(be_some().value(HeaderValue::from_static("*")));
+      expect!(hyper_response.headers().is_empty()).to(be_true());
   }
 
   #[test]
@@ -140,7 +139,7 @@ mod test {
   }
 
   #[test]
-  fn only_add_a_cors_origin_header_if_one_has_not_already_been_provided() {
+  fn passes_through_a_cors_origin_header_provided_by_the_interaction() {
       let response = HttpResponse {
           headers: Some(hashmap! { "Access-Control-Allow-Origin".to_string() => vec!["dodgy.com".to_string()] }),
           .. HttpResponse::default()
@@ -0,0 +1,224 @@
+//! Functions relating to normalising request paths. The same normalisation is applied to the paths of
+//! incoming requests and to the paths of the interactions when they are indexed, so requests can match
+//! interactions with a different base path, trailing slash, percent-encoding or case. Interactions with path
+//! matching rules are not lower-cased or given a base path, as the rules can not be transformed.
+
+use std::fmt::{Display, Formatter};
+
+use percent_encoding::percent_decode_str;
+
+/// Base path of the requests that is different to the one of the interactions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BasePath {
+  /// Removed from the start of paths that have it
+  Strip(String),
+  /// Added to the start of paths that do not have it
+  Add(String)
+}
+
+impl BasePath {
+  fn path(&self) -> &str {
+    match self {
+      BasePath::Strip(path) => path,
+      BasePath::Add(path) => path
+    }
+  }
+}
+
+/// How request and interaction paths are normalised before matching
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathNormalisation {
+  base_path: Option<BasePath>,
+  trailing_slash: bool,
+  decode: bool,
+  case_insensitive: bool
+}
+
+impl PathNormalisation {
+  /// Sets the base path to strip from, or add to, paths
+  pub fn with_base_path(self, base_path: Option<BasePath>) -> PathNormalisation {
+    PathNormalisation {
+      // Base paths are stored with a leading slash and without a trailing one
+      base_path: base_path
+        .map(|base_path| {
+          let path = format!("/{}", base_path.path().trim_matches('/'));
+          match base_path {
+            BasePath::Strip(_) => BasePath::Strip(path),
+            BasePath::Add(_) => BasePath::Add(path)
+          }
+        })
+        .filter(|base_path| base_path.path() != "/"),
+      .. self
+    }
+  }
+
+  /// Ignores trailing slashes, so `/users/` and `/users` are the same path
+  pub fn with_trailing_slash_tolerance(self, trailing_slash: bool) -> PathNormalisation {
+    PathNormalisation {
+      trailing_slash,
+      .. self
+    }
+  }
+
+  /// Percent-decodes paths, so `/files/a%2Fb` and `/files/a/b` are the same path
+  pub fn with_percent_decoding(self, decode: bool) -> PathNormalisation {
+    PathNormalisation {
+      decode,
+      .. self
+    }
+  }
+
+  /// Ignores the case of paths
+  pub fn with_case_insensitive_paths(self, case_insensitive: bool) -> PathNormalisation {
+    PathNormalisation {
+      case_insensitive,
+      .. self
+    }
+  }
+
+  /// Normalisation applied to the paths of interactions with path matching rules, and to the paths of the
+  /// requests matched against them. The matching rules are not transformed, so the base path is not added
+  /// and the case of the path is not changed.
+  pub fn for_path_matchers(&self) -> PathNormalisation {
+    PathNormalisation {
+      base_path: self.base_path.clone().filter(|base_path| matches!(base_path, BasePath::Strip(_))),
+      case_insensitive: false,
+      .. self.clone()
+    }
+  }
+
+  /// If any normalisation is applied to paths
+  pub fn is_enabled(&self) -> bool {
+    self.base_path.is_some() || self.trailing_slash || self.decode || self.case_insensitive
+  }
+
+  /// Normalises the path. Paths are percent-decoded first, then lower-cased, then the base path is stripped
+  /// or added, and finally any trailing slash is removed.
+  pub fn normalise(&self, path: &str) -> String {
+    let mut path = if self.decode {
+      percent_decode_str(path).decode_utf8_lossy().to_string()
+    } else {
+      path.to_string()
+    };
+    if self.case_insensitive {
+      path = path.to_lowercase();
+    }
+
+    if let Some(base_path) = &self.base_path {
+      let base = if self.case_insensitive { base_path.path().to_lowercase() } else { base_path.path().to_string() };
+      let has_base_path = path == base || path.starts_with(&format!("{}/", base));
+      match base_path {
+        BasePath::Strip(_) => if has_base_path {
+          path = path[base.len()..].to_string();
+          if path.is_empty() {
+            path = "/".to_string();
+          }
+        },
+        BasePath::Add(_) => if !has_base_path {
+          path = if path.starts_with('/') { format!("{}{}", base, path) } else { format!("{}/{}", base, path) };
+        }
+      }
+    }
+
+    if self.trailing_slash && path.len() > 1 && path.ends_with('/') {
+      path = path.trim_end_matches('/').to_string();
+      if path.is_empty() {
+        path = "/".to_string();
+      }
+    }
+    path
+  }
+}
+
+impl Display for PathNormalisation {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let mut options = vec![];
+    match &self.base_path {
+      Some(BasePath::Strip(path)) => options.push(format!("stripping base path {}", path)),
+      Some(BasePath::Add(path)) => options.push(format!("adding base path {}", path)),
+      None => {}
+    }
+    if self.trailing_slash {
+      options.push("ignoring trailing slashes".to_string());
+    }
+    if self.decode {
+      options.push("percent-decoding".to_string());
+    }
+    if self.case_insensitive {
+      options.push("ignoring case".to_string());
+    }
+    write!(f, "{}", options.join(", "))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+
+  use super::*;
+
+  #[test]
+  fn paths_are_not_changed_by_default() {
+    let normalisation = PathNormalisation::default();
+    expect!(normalisation.is_enabled()).to(be_false());
+    expect!(normalisation.normalise("/API/users/%20/")).to(be_equal_to("/API/users/%20/"));
+  }
+
+  #[test]
+  fn trailing_slashes() {
+    let normalisation = PathNormalisation::default().with_trailing_slash_tolerance(true);
+    expect!(normalisation.normalise("/api/users/")).to(be_equal_to("/api/users"));
+    expect!(normalisation.normalise("/api/users//")).to(be_equal_to("/api/users"));
+    expect!(normalisation.normalise("/api/users")).to(be_equal_to("/api/users"));
+    expect!(normalisation.normalise("/")).to(be_equal_to("/"));
+    expect!(normalisation.normalise("//")).to(be_equal_to("/"));
+  }
+
+  #[test]
+  fn percent_decoding_and_case() {
+    let normalisation = PathNormalisation::default().with_percent_decoding(true);
+    expect!(normalisation.normalise("/files/a%2Fb%20c")).to(be_equal_to("/files/a/b c"));
+    expect!(normalisation.normalise("/files/%zz")).to(be_equal_to("/files/%zz"));
+    let normalisation = PathNormalisation::default().with_case_insensitive_paths(true);
+    expect!(normalisation.normalise("/API/Users")).to(be_equal_to("/api/users"));
+  }
+
+  #[test]
+  fn stripping_a_base_path() {
+    let normalisation = PathNormalisation::default()
+      .with_base_path(Some(BasePath::Strip("v1/".to_string())))
+      .with_case_insensitive_paths(true);
+    expect!(normalisation.normalise("/v1/users")).to(be_equal_to("/users"));
+    expect!(normalisation.normalise("/V1/users")).to(be_equal_to("/users"));
+    expect!(normalisation.normalise("/v1")).to(be_equal_to("/"));
+    expect!(normalisation.normalise("/users")).to(be_equal_to("/users"));
+    expect!(normalisation.normalise("/v10/users")).to(be_equal_to("/v10/users"));
+    expect!(normalisation.to_string()).to(be_equal_to("stripping base path /v1, ignoring case"));
+  }
+
+  #[test]
+  fn adding_a_base_path() {
+    let normalisation = PathNormalisation::default()
+      .with_base_path(Some(BasePath::Add("/api".to_string())))
+      .with_trailing_slash_tolerance(true);
+    expect!(normalisation.normalise("/users")).to(be_equal_to("/api/users"));
+    expect!(normalisation.normalise("/api/users/")).to(be_equal_to("/api/users"));
+    expect!(normalisation.normalise("/")).to(be_equal_to("/api"));
+    expect!(PathNormalisation::default().with_base_path(Some(BasePath::Add("/".to_string()))).is_enabled())
+      .to(be_false());
+  }
+
+  #[test]
+  fn paths_with_matching_rules_are_not_lower_cased_or_given_a_base_path() {
+    let normalisation = PathNormalisation::default()
+      .with_base_path(Some(BasePath::Add("/api".to_string())))
+      .with_trailing_slash_tolerance(true)
+      .with_case_insensitive_paths(true)
+      .for_path_matchers();
+    expect!(normalisation.normalise("/Users/")).to(be_equal_to("/Users"));
+    let normalisation = PathNormalisation::default()
+      .with_base_path(Some(BasePath::Strip("/v1".to_string())))
+      .for_path_matchers();
+    expect!(normalisation.normalise("/v1/Users")).to(be_equal_to("/Users"));
+  }
+}
@@ -0,0 +1,137 @@
+//! Functions relating to loading and using Pact plugins
+
+use itertools::Itertools;
+use maplit::hashmap;
+use pact_matching::generators::bodies::generators_process_body;
+use pact_matching::generators::DefaultVariantMatcher;
+use pact_models::generators::{GeneratorCategory, GeneratorTestMode};
+use pact_models::http_parts::HttpPart;
+use pact_models::prelude::*;
+use pact_models::prelude::v4::*;
+use pact_models::v4::http_parts::HttpResponse;
+use pact_plugin_driver::catalogue_manager::find_content_generator;
+use pact_plugin_driver::plugin_manager::{load_plugin, load_plugin_manifest};
+use pact_plugin_driver::plugin_models::{PluginDependency, PluginDependencyType};
+use tracing::{debug, info, warn};
+
+use crate::loading::PactError;
+use crate::PactSource;
+
+// Returns the unique set of plugins required by the pacts
+fn required_plugins(pacts: &[(V4Pact, PactSource)]) -> Vec<PluginDependency> {
+  pacts.iter()
+    .flat_map(|(pact, _)| pact.plugin_data())
+    .map(|plugin| PluginDependency {
+      name: plugin.name.clone(),
+      version: Some(plugin.version.clone()),
+      dependency_type: PluginDependencyType::Plugin
+    })
+    .unique_by(|plugin| plugin.to_string())
+    .collect()
+}
+
+/// Loads all the plugins required by the pacts. Plugins must already be installed locally, and an
+/// error will be returned for any that can not be found or started.
+pub async fn load_required_plugins(pacts: &[(V4Pact, PactSource)]) -> Vec<PactError> {
+  let mut errors = vec![];
+  for plugin in required_plugins(pacts) {
+    debug!("Pact files require plugin {}", plugin);
+    if let Err(err) = load_plugin_manifest(&plugin) {
+      errors.push(PactError::new(format!("Plugin {} is required by the pact files, but is not installed - {}",
+        plugin, err)));
+      continue;
+    }
+    match load_plugin(&plugin).await {
+      Ok(_) => info!("Loaded plugin {}", plugin),
+      Err(err) => errors.push(PactError::new(format!("Failed to start plugin {} - {}", plugin, err)))
+    }
+  }
+  errors
+}
+
+/// Generates the response for the interaction, delegating the generation of the body to a plugin
+/// if the response contents are provided by one.
+pub async fn generate_response(interaction: &SynchronousHttp, pact: &V4Pact) -> HttpResponse {
+  let content_generator = interaction.response.content_type()
+    .filter(|_| pact.requires_plugins())
+    .and_then(|content_type| find_content_generator(&content_type).map(|_| content_type));
+  match content_generator {
+    Some(content_type) => {
+      let mut response = interaction.response.clone();
+      let body_generators = response.build_generators(&GeneratorCategory::BODY);
+      response.generators.categories.remove(&GeneratorCategory::BODY);
+      let mut response = pact_matching::generate_response(&response, &GeneratorTestMode::Provider, &hashmap!{}).await;
+      if response.body.is_present() {
+        debug!("Generating response contents for {} with a plugin", content_type);
+        match generators_process_body(&GeneratorTestMode::Provider, &response.body, Some(content_type),
+          &hashmap!{}, &body_generators, &DefaultVariantMatcher {}, &pact.plugin_data(),
+          &interaction.plugin_config).await {
+          Ok(body) => response.body = body,
+          Err(err) => warn!("Failed to generate the response contents with a plugin, will use the original: {}", err)
+        }
+      }
+      response
+    }
+    None => pact_matching::generate_response(&interaction.response, &GeneratorTestMode::Provider, &hashmap!{}).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use maplit::hashmap;
+  use pact_models::plugins::PluginData;
+  use pact_models::prelude::v4::*;
+  use pact_models::v4::http_parts::HttpResponse;
+
+  use crate::PactSource;
+
+  use super::*;
+
+  fn pact_with_plugins(plugins: Vec<(&str, &str)>) -> V4Pact {
+    V4Pact {
+      plugin_data: plugins.iter()
+        .map(|(name, version)| PluginData {
+          name: name.to_string(),
+          version: version.to_string(),
+          configuration: hashmap!{}
+        })
+        .collect(),
+      .. V4Pact::default()
+    }
+  }
+
+  #[test]
+  fn required_plugins_returns_the_unique_set_of_plugins() {
+    let pacts = vec![
+      (pact_with_plugins(vec![("protobuf", "0.3.0")]), PactSource::Unknown),
+      (pact_with_plugins(vec![("protobuf", "0.3.0"), ("csv", "0.0.3")]), PactSource::Unknown),
+      (V4Pact::default(), PactSource::Unknown)
+    ];
+    let plugins = required_plugins(&pacts).iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    expect!(plugins).to(be_equal_to(vec!["protobuf:0.3.0".to_string(), "csv:0.0.3".to_string()]));
+  }
+
+  #[tokio::test]
+  async fn load_required_plugins_does_nothing_if_no_plugins_are_required() {
+    expect!(load_required_plugins(&[(V4Pact::default(), PactSource::Unknown)]).await.is_empty()).to(be_true());
+  }
+
+  #[tokio::test]
+  async fn load_required_plugins_returns_an_error_if_the_plugin_is_not_installed() {
+    let pacts = vec![(pact_with_plugins(vec![("not-a-real-plugin", "0.0.1")]), PactSource::Unknown)];
+    let errors = load_required_plugins(&pacts).await;
+    expect!(errors.len()).to(be_equal_to(1));
+    expect!(errors[0].to_string().starts_with("Plugin not-a-real-plugin:0.0.1 is required by the pact files, but is not installed"))
+      .to(be_true());
+  }
+
+  #[tokio::test]
+  async fn generate_response_without_plugins() {
+    let interaction = SynchronousHttp {
+      response: HttpResponse { status: 204, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    expect!(generate_response(&interaction, &V4Pact::default()).await).to(be_equal_to(interaction.response));
+  }
+}
@@ -0,0 +1,387 @@
+//! Functions relating to filtering the interactions by provider state, including the provider state
+//! selection made by a request (with a header, query parameter or cookie)
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+use http::{HeaderMap, StatusCode};
+use hyper::{Body, Response as HyperResponse};
+use itertools::Itertools;
+use pact_models::provider_states::ProviderState;
+use pact_models::query_strings::{decode_query, parse_query_string};
+use pact_models::v4::http_parts::HttpRequest;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+
+/// Maximum number of compiled regular expressions that are cached. The cache is cleared once it is full.
+const MAX_CACHED_REGEXES: usize = 256;
+
+// How a provider state name is matched
+#[derive(Debug, Clone)]
+enum NameMatcher {
+  Regex(Regex),
+  Exact(String)
+}
+
+// Matches an interaction provider state by name, and optionally its parameters
+#[derive(Debug, Clone)]
+struct StateMatcher {
+  name: NameMatcher,
+  params: Map<String, Value>
+}
+
+impl StateMatcher {
+  fn matches(&self, state: &ProviderState) -> bool {
+    let name_matches = match &self.name {
+      NameMatcher::Regex(regex) => regex.is_match(&state.name),
+      NameMatcher::Exact(name) => *name == state.name
+    };
+    name_matches && self.params.iter().all(|(key, value)| state.params.get(key) == Some(value))
+  }
+}
+
+impl Display for StateMatcher {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match &self.name {
+      NameMatcher::Regex(regex) => write!(f, "regex '{}'", regex)?,
+      NameMatcher::Exact(name) => write!(f, "'{}'", name)?
+    }
+    if !self.params.is_empty() {
+      write!(f, " with {}", Value::Object(self.params.clone()))?;
+    }
+    Ok(())
+  }
+}
+
+/// Filter that selects the interactions with matching provider states. Interactions match if any of
+/// the states match (or all of the states, if the filter requires all of them).
+#[derive(Debug, Clone)]
+pub struct ProviderStateFilter {
+  states: Vec<StateMatcher>,
+  all: bool
+}
+
+impl ProviderStateFilter {
+  /// If the interaction provider states match the filter. If `empty_provider_states` is true, interactions
+  /// without a provider state also match.
+  pub fn matches(&self, provider_states: &[ProviderState], empty_provider_states: bool) -> bool {
+    if empty_provider_states && (provider_states.is_empty() || provider_states.iter().any(|state| state.name.is_empty())) {
+      return true;
+    }
+    let state_matches = |matcher: &StateMatcher| provider_states.iter().any(|state| matcher.matches(state));
+    if self.all {
+      self.states.iter().all(state_matches)
+    } else {
+      self.states.iter().any(state_matches)
+    }
+  }
+
+  /// Returns the number of the interaction provider states that are selected by the filter
+  pub fn matching_states(&self, provider_states: &[ProviderState]) -> usize {
+    provider_states.iter()
+      .filter(|state| self.states.iter().any(|matcher| matcher.matches(state)))
+      .count()
+  }
+}
+
+impl From<Regex> for ProviderStateFilter {
+  fn from(regex: Regex) -> Self {
+    ProviderStateFilter {
+      states: vec![StateMatcher { name: NameMatcher::Regex(regex), params: Map::default() }],
+      all: false
+    }
+  }
+}
+
+impl Display for ProviderStateFilter {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self.states.as_slice() {
+      [state] => write!(f, "{}", state),
+      states => write!(f, "{} of {}", if self.all { "all" } else { "any" }, states.iter().join(", "))
+    }
+  }
+}
+
+/// Reads the provider state selection from a request header, query parameter or cookie (in that order).
+///
+/// The value is either a regular expression that is matched against the provider state names, or a JSON
+/// document with the states to match:
+///
+/// ```json
+/// {
+///   "states": ["an order exists", { "name": "a user exists", "params": { "id": 10 } }],
+///   "match": "exact",
+///   "mode": "all"
+/// }
+/// ```
+///
+/// `match` is either `regex` (the default) or `exact`, and `mode` is either `any` (the default) or `all`.
+/// A JSON array is the same as a document with only the `states`.
+#[derive(Debug, Clone, Default)]
+pub struct ProviderStateSelector {
+  header: Option<String>,
+  query_param: Option<String>,
+  cookie: Option<String>,
+  regexes: Arc<Mutex<HashMap<String, Regex>>>
+}
+
+impl ProviderStateSelector {
+  /// Creates the selector with the header, query parameter and cookie the selection can be read from
+  pub fn new(header: Option<String>, query_param: Option<String>, cookie: Option<String>) -> ProviderStateSelector {
+    ProviderStateSelector {
+      header,
+      query_param,
+      cookie,
+      regexes: Arc::default()
+    }
+  }
+
+  /// Returns the provider state filter selected by the request, if any. Returns an error describing the
+  /// problem if the selection is not valid.
+  pub fn select(&self, headers: &HeaderMap, query: Option<&str>) -> Result<Option<ProviderStateFilter>, String> {
+    if let Some(header) = &self.header {
+      if let Some(value) = headers.get(header.as_str()) {
+        let value = value.to_str()
+          .map_err(|_| format!("The provider state header '{}' is not valid UTF-8", header))?;
+        return self.parse(value).map(Some);
+      }
+    }
+
+    if let Some(param) = &self.query_param {
+      let value = query.and_then(parse_query_string)
+        .and_then(|params| params.get(param).and_then(|values| values.first().cloned()));
+      if let Some(value) = value {
+        return self.parse(&value).map(Some);
+      }
+    }
+
+    if let Some(cookie) = &self.cookie {
+      let value = headers.get_all(http::header::COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|value| value.trim().split_once('='))
+        .find(|(name, _)| name == cookie)
+        .map(|(_, value)| value.trim().to_string());
+      if let Some(value) = value {
+        let value = decode_query(&value)
+          .map_err(|err| format!("The provider state cookie '{}' is not correctly encoded: {}", cookie, err))?;
+        return self.parse(&value).map(Some);
+      }
+    }
+
+    Ok(None)
+  }
+
+  /// Removes the provider state query parameter from the request, so that it is not matched against
+  /// the query parameters of the interactions
+  pub fn remove_query_param(&self, request: &mut HttpRequest) {
+    if let (Some(param), Some(query)) = (&self.query_param, &mut request.query) {
+      query.remove(param);
+      if query.is_empty() {
+        request.query = None;
+      }
+    }
+  }
+
+  /// Parses the provider state selection
+  pub fn parse(&self, value: &str) -> Result<ProviderStateFilter, String> {
+    let value = value.trim();
+    if value.starts_with('{') || value.starts_with('[') {
+      let json: Value = serde_json::from_str(value)
+        .map_err(|err| format!("The provider state selection is not valid JSON: {}", err))?;
+      self.parse_json(&json)
+    } else {
+      Ok(ProviderStateFilter::from(self.regex(value)?))
+    }
+  }
+
+  fn parse_json(&self, json: &Value) -> Result<ProviderStateFilter, String> {
+    let (states, exact, all) = match json {
+      Value::Array(states) => (states, false, false),
+      Value::Object(map) => {
+        let states = match map.get("states") {
+          Some(Value::Array(states)) => states,
+          _ => return Err("The provider state selection must have a 'states' array".to_string())
+        };
+        let exact = match map.get("match").map(|value| value.as_str()) {
+          None | Some(Some("regex")) => false,
+          Some(Some("exact")) => true,
+          Some(_) => return Err("The provider state 'match' must be either 'regex' or 'exact'".to_string())
+        };
+        let all = match map.get("mode").map(|value| value.as_str()) {
+          None | Some(Some("any")) => false,
+          Some(Some("all")) => true,
+          Some(_) => return Err("The provider state 'mode' must be either 'any' or 'all'".to_string())
+        };
+        (states, exact, all)
+      },
+      _ => return Err("The provider state selection must be a JSON object or array".to_string())
+    };
+    if states.is_empty() {
+      return Err("The provider state selection must have at least one state".to_string());
+    }
+
+    let states = states.iter()
+      .map(|state| {
+        let (name, params) = match state {
+          Value::String(name) => (name.as_str(), Map::default()),
+          Value::Object(state) => match (state.get("name"), state.get("params")) {
+            (Some(Value::String(name)), None) => (name.as_str(), Map::default()),
+            (Some(Value::String(name)), Some(Value::Object(params))) => (name.as_str(), params.clone()),
+            (Some(Value::String(_)), Some(_)) => return Err("The provider state 'params' must be a JSON object".to_string()),
+            _ => return Err("Each provider state must have a 'name'".to_string())
+          },
+          _ => return Err("Each provider state must be a name or a JSON object".to_string())
+        };
+        let name = if exact {
+          NameMatcher::Exact(name.to_string())
+        } else {
+          NameMatcher::Regex(self.regex(name)?)
+        };
+        Ok(StateMatcher { name, params })
+      })
+      .collect::<Result<Vec<_>, String>>()?;
+    Ok(ProviderStateFilter { states, all })
+  }
+
+  // Compiles the regular expression, using the cached one if it has already been compiled
+  fn regex(&self, pattern: &str) -> Result<Regex, String> {
+    let mut regexes = self.regexes.lock().unwrap();
+    if let Some(regex) = regexes.get(pattern) {
+      return Ok(regex.clone());
+    }
+    let regex = Regex::new(pattern)
+      .map_err(|err| format!("'{}' is not a valid provider state regular expression: {}", pattern, err))?;
+    if regexes.len() >= MAX_CACHED_REGEXES {
+      regexes.clear();
+    }
+    regexes.insert(pattern.to_string(), regex.clone());
+    Ok(regex)
+  }
+}
+
+/// Returns the 400 response for an invalid provider state selection
+pub fn invalid_selection_response(error: &str) -> Result<HyperResponse<Body>, http::Error> {
+  HyperResponse::builder()
+    .status(StatusCode::BAD_REQUEST)
+    .header("Content-Type", "application/json")
+    .body(Body::from(json!({ "error": error }).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use http::{HeaderMap, HeaderValue};
+  use maplit::hashmap;
+  use pact_models::provider_states::ProviderState;
+  use pact_models::v4::http_parts::HttpRequest;
+  use regex::Regex;
+  use serde_json::json;
+
+  use super::*;
+
+  fn state(name: &str) -> ProviderState {
+    ProviderState::default(name)
+  }
+
+  fn state_with_params(name: &str, params: Value) -> ProviderState {
+    ProviderState {
+      name: name.to_string(),
+      params: params.as_object().unwrap().iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+  }
+
+  fn selector() -> ProviderStateSelector {
+    ProviderStateSelector::new(Some("X-Provider-State".to_string()), Some("providerState".to_string()),
+      Some("provider-state".to_string()))
+  }
+
+  #[test]
+  fn regex_filter() {
+    let filter = ProviderStateFilter::from(Regex::new("order").unwrap());
+    expect!(filter.matches(&[state("an order exists")], false)).to(be_true());
+    expect!(filter.matches(&[state("a user exists")], false)).to(be_false());
+    expect!(filter.matches(&[], false)).to(be_false());
+    expect!(filter.matches(&[], true)).to(be_true());
+  }
+
+  #[test]
+  fn exact_filter_with_all_states_and_params() {
+    let filter = selector().parse(r#"{
+      "states": ["an order exists", { "name": "a user exists", "params": { "id": 10 } }],
+      "match": "exact",
+      "mode": "all"
+    }"#).unwrap();
+    let user = state_with_params("a user exists", json!({ "id": 10, "name": "bob" }));
+    expect!(filter.matches(&[state("an order exists"), user.clone()], false)).to(be_true());
+    expect!(filter.matches(&[state("an order exists")], false)).to(be_false());
+    expect!(filter.matches(&[state("an order exists"), state_with_params("a user exists", json!({ "id": 11 }))], false))
+      .to(be_false());
+    expect!(filter.matches(&[state("an order exists with items"), user], false)).to(be_false());
+  }
+
+  #[test]
+  fn any_of_the_states() {
+    let filter = selector().parse(r#"["an order exists", "a user exists"]"#).unwrap();
+    expect!(filter.matches(&[state("a user exists")], false)).to(be_true());
+    expect!(filter.matches(&[state("no orders exist")], false)).to(be_false());
+    expect!(filter.to_string()).to(be_equal_to("any of regex 'an order exists', regex 'a user exists'"));
+  }
+
+  #[test]
+  fn invalid_selections_are_errors() {
+    let selector = selector();
+    expect!(selector.parse("an order (exists")).to(be_err());
+    expect!(selector.parse("{ not json")).to(be_err());
+    expect!(selector.parse(r#"{ "states": [] }"#)).to(be_err());
+    expect!(selector.parse(r#"{ "states": ["a"], "match": "fuzzy" }"#)).to(be_err());
+    expect!(selector.parse(r#"{ "states": ["a"], "mode": "some" }"#)).to(be_err());
+    expect!(selector.parse(r#"[{ "params": {} }]"#)).to(be_err());
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-provider-state", HeaderValue::from_bytes(b"caf\xe9").unwrap());
+    expect!(selector.select(&headers, None)).to(be_err());
+  }
+
+  #[test]
+  fn select_from_the_header_query_parameter_or_cookie() {
+    let selector = selector();
+    let selected = |headers: &HeaderMap, query: Option<&str>| selector.select(headers, query)
+      .map(|filter| filter.map(|filter| filter.to_string()));
+
+    let mut headers = HeaderMap::new();
+    expect!(selected(&headers, None)).to(be_ok().value(None));
+    expect!(selected(&headers, Some("providerState=an%20order%20exists"))).to(be_ok().value(Some("regex 'an order exists'".to_string())));
+    headers.insert("cookie", HeaderValue::from_static("a=b; provider-state=a%20user%20exists"));
+    expect!(selected(&headers, None)).to(be_ok().value(Some("regex 'a user exists'".to_string())));
+    headers.insert("x-provider-state", HeaderValue::from_static("no orders"));
+    expect!(selected(&headers, Some("providerState=an%20order%20exists"))).to(be_ok().value(Some("regex 'no orders'".to_string())));
+  }
+
+  #[test]
+  fn regexes_are_cached() {
+    let selector = selector();
+    selector.parse("an order exists").unwrap();
+    selector.parse("an order exists").unwrap();
+    selector.parse("a user exists").unwrap();
+    expect!(selector.regexes.lock().unwrap().len()).to(be_equal_to(2));
+  }
+
+  #[test]
+  fn remove_query_param() {
+    let selector = selector();
+    let mut request = HttpRequest {
+      query: Some(hashmap! {
+        "providerState".to_string() => vec!["a".to_string()],
+        "page".to_string() => vec!["1".to_string()]
+      }),
+      .. HttpRequest::default()
+    };
+    selector.remove_query_param(&mut request);
+    expect!(request.query.clone()).to(be_some().value(hashmap! { "page".to_string() => vec!["1".to_string()] }));
+    request.query = Some(hashmap! { "providerState".to_string() => vec!["a".to_string()] });
+    selector.remove_query_param(&mut request);
+    expect!(request.query).to(be_none());
+  }
+}
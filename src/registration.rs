@@ -0,0 +1,553 @@
+//! Functions relating to interactions that are registered, replaced and deleted at runtime through
+//! the admin API, in addition to the interactions loaded from the pact files
+
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use http::{HeaderMap, Method, StatusCode};
+use hyper::{Body, Response as HyperResponse};
+use pact_models::pact::load_pact_from_json;
+use pact_models::prelude::*;
+use pact_models::prelude::v4::*;
+use pact_models::query_strings::parse_query_string;
+use pact_models::v4::interaction::V4Interaction;
+use pact_models::v4::V4InteractionType;
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::sessions::{Session, Sessions};
+
+/// Base path of the admin endpoints used to manage the registered interactions
+pub const INTERACTIONS_PATH: &str = "/__admin/interactions";
+
+/// Default priority of registered interactions. Interactions loaded from pact files have a priority of 0,
+/// and when more than one interaction matches a request, the one with the highest priority is used.
+pub const DEFAULT_REGISTERED_PRIORITY: i32 = 1;
+
+/// Consumer and provider name used for interactions that are not registered as part of a pact
+const REGISTERED_PACT_NAME: &str = "registered";
+
+/// Interaction registered through the admin API
+#[derive(Debug, Clone)]
+pub struct RegisteredInteraction {
+  /// ID used to replace or delete the interaction (the interaction key if it has one)
+  pub id: String,
+  /// The interaction
+  pub interaction: SynchronousHttp,
+  /// Pact the interaction was registered with
+  pub pact: V4Pact,
+  /// Priority over the other interactions that match a request
+  pub priority: i32,
+  owner: Option<String>,
+  session: Option<(String, Weak<Session>)>,
+  expires: Option<Instant>
+}
+
+impl RegisteredInteraction {
+  // Expired interactions, and those for a session that no longer exists, are removed
+  fn is_active(&self) -> bool {
+    self.expires.map(|expires| expires > Instant::now()).unwrap_or(true)
+      && self.session.as_ref().map(|(_, session)| session.strong_count() > 0).unwrap_or(true)
+  }
+
+  // Interactions registered for a session are only used for the requests in that session
+  fn applies_to(&self, session: Option<&Arc<Session>>) -> bool {
+    match (&self.session, session) {
+      (None, _) => true,
+      (Some((_, registered)), Some(session)) => registered.upgrade()
+        .map(|registered| Arc::ptr_eq(&registered, session))
+        .unwrap_or(false),
+      (Some(_), None) => false
+    }
+  }
+
+  fn matches_filter(&self, filter: &Filter) -> bool {
+    filter.owner.as_ref().map(|owner| self.owner.as_ref() == Some(owner)).unwrap_or(true)
+      && filter.session.as_ref().map(|session| self.session_id() == Some(session.as_str())).unwrap_or(true)
+  }
+
+  fn session_id(&self) -> Option<&str> {
+    self.session.as_ref().map(|(id, _)| id.as_str())
+  }
+
+  fn to_json(&self) -> Value {
+    json!({
+      "id": self.id,
+      "description": self.interaction.description,
+      "consumer": self.pact.consumer.name,
+      "provider": self.pact.provider.name,
+      "owner": self.owner,
+      "session": self.session_id(),
+      "priority": self.priority,
+      "expiresInSeconds": self.expires.map(|expires| expires.saturating_duration_since(Instant::now()).as_secs()),
+      "interaction": self.interaction.to_json()
+    })
+  }
+}
+
+// Owner and session query parameters used to filter the registered interactions
+#[derive(Debug, Default)]
+struct Filter {
+  owner: Option<String>,
+  session: Option<String>
+}
+
+// Options from the query parameters of a registration request
+#[derive(Debug, Default)]
+struct RegistrationOptions {
+  filter: Filter,
+  ttl: Option<Duration>,
+  priority: Option<i32>
+}
+
+impl RegistrationOptions {
+  fn from_query(query: Option<&str>) -> Result<RegistrationOptions, String> {
+    let params = query.and_then(parse_query_string).unwrap_or_default();
+    let param = |name: &str| params.get(name)
+      .and_then(|values| values.first())
+      .filter(|value| !value.is_empty())
+      .cloned();
+    Ok(RegistrationOptions {
+      filter: Filter {
+        owner: param("owner"),
+        session: param("session")
+      },
+      ttl: param("ttl")
+        .map(|ttl| ttl.parse::<u64>().map(Duration::from_secs)
+          .map_err(|_| format!("ttl must be a number of seconds, got '{}'", ttl)))
+        .transpose()?,
+      priority: param("priority")
+        .map(|priority| priority.parse::<i32>()
+          .map_err(|_| format!("priority must be an integer, got '{}'", priority)))
+        .transpose()?
+    })
+  }
+}
+
+/// Registry of the interactions registered at runtime. All clones share the same interactions.
+#[derive(Debug, Clone)]
+pub struct RegisteredInteractions {
+  interactions: Arc<Mutex<Vec<RegisteredInteraction>>>,
+  default_priority: i32,
+  next_id: Arc<AtomicUsize>,
+  // Incremented each time the active interactions change
+  generation: Arc<AtomicUsize>
+}
+
+impl RegisteredInteractions {
+  /// Creates an empty registry. Interactions are registered with the default priority unless the
+  /// registration request sets one.
+  pub fn new(default_priority: i32) -> RegisteredInteractions {
+    RegisteredInteractions {
+      interactions: Arc::default(),
+      default_priority,
+      next_id: Arc::new(AtomicUsize::new(1)),
+      generation: Arc::default()
+    }
+  }
+
+  /// Returns a number that changes each time the active interactions change (because interactions were
+  /// registered, replaced or removed, or have expired), so anything built from them can be cached until then
+  pub fn generation(&self) -> usize {
+    let _interactions = self.active_interactions();
+    self.generation.load(Ordering::SeqCst)
+  }
+
+  /// Returns the registered interactions that apply to requests in the session (or requests not in
+  /// a session, if None)
+  pub fn for_session(&self, session: Option<&Arc<Session>>) -> Vec<RegisteredInteraction> {
+    self.active_interactions().iter()
+      .filter(|registered| registered.applies_to(session))
+      .cloned()
+      .collect()
+  }
+
+  // Locks the registered interactions, removing any that are no longer active
+  fn active_interactions(&self) -> MutexGuard<'_, Vec<RegisteredInteraction>> {
+    let mut interactions = self.interactions.lock().unwrap_or_else(|err| err.into_inner());
+    let count = interactions.len();
+    interactions.retain(|registered| registered.is_active());
+    if interactions.len() != count {
+      self.changed();
+    }
+    interactions
+  }
+
+  fn changed(&self) {
+    self.generation.fetch_add(1, Ordering::SeqCst);
+  }
+
+  fn register(
+    &self,
+    interactions: Vec<(SynchronousHttp, V4Pact)>,
+    options: &RegistrationOptions,
+    session: Option<&Arc<Session>>
+  ) -> Result<Vec<RegisteredInteraction>, String> {
+    let mut registered = self.active_interactions();
+    let new_interactions = interactions.into_iter()
+      .map(|(interaction, pact)| RegisteredInteraction {
+        id: interaction.key.clone()
+          .unwrap_or_else(|| format!("interaction-{}", self.next_id.fetch_add(1, Ordering::SeqCst))),
+        interaction,
+        pact,
+        priority: options.priority.unwrap_or(self.default_priority),
+        owner: options.filter.owner.clone(),
+        session: session.map(|session| (session.id().to_string(), Arc::downgrade(session))),
+        expires: options.ttl.map(|ttl| Instant::now() + ttl)
+      })
+      .collect::<Vec<_>>();
+    for interaction in &new_interactions {
+      if registered.iter().any(|existing| existing.id == interaction.id) ||
+        new_interactions.iter().filter(|other| other.id == interaction.id).count() > 1 {
+        return Err(format!("An interaction with ID '{}' is already registered", interaction.id));
+      }
+    }
+    for interaction in &new_interactions {
+      info!("Registered interaction '{}' ({})", interaction.interaction.description, interaction.id);
+    }
+    registered.extend(new_interactions.iter().cloned());
+    self.changed();
+    Ok(new_interactions)
+  }
+
+  fn replace(
+    &self,
+    id: &str,
+    interaction: SynchronousHttp,
+    pact: V4Pact,
+    options: &RegistrationOptions
+  ) -> Option<RegisteredInteraction> {
+    let mut registered = self.active_interactions();
+    let existing = registered.iter_mut().find(|registered| registered.id == id)?;
+    existing.interaction = interaction;
+    existing.pact = pact;
+    if let Some(priority) = options.priority {
+      existing.priority = priority;
+    }
+    if let Some(owner) = &options.filter.owner {
+      existing.owner = Some(owner.clone());
+    }
+    if let Some(ttl) = options.ttl {
+      existing.expires = Some(Instant::now() + ttl);
+    }
+    info!("Replaced interaction '{}' ({})", existing.interaction.description, id);
+    let replaced = existing.clone();
+    self.changed();
+    Some(replaced)
+  }
+
+  fn remove(&self, filter: impl Fn(&RegisteredInteraction) -> bool) -> usize {
+    let mut registered = self.active_interactions();
+    let count = registered.len();
+    registered.retain(|registered| !filter(registered));
+    if registered.len() != count {
+      self.changed();
+    }
+    count - registered.len()
+  }
+
+  /// Returns the response for a request to one of the interaction admin endpoints, or None if the request
+  /// is not for an interaction endpoint. Interactions are registered for the session given by the `session`
+  /// query parameter, or the session selected by the request headers.
+  ///
+  /// * `POST /__admin/interactions` registers the V4 interaction (or all the HTTP interactions of the V4 pact) in the body
+  /// * `GET /__admin/interactions` lists the registered interactions
+  /// * `DELETE /__admin/interactions` removes the registered interactions (filtered by the `owner` or `session` query parameters)
+  /// * `GET /__admin/interactions/{id}` returns the registered interaction
+  /// * `PUT /__admin/interactions/{id}` replaces the registered interaction
+  /// * `DELETE /__admin/interactions/{id}` removes the registered interaction
+  pub fn handle_request(
+    &self,
+    method: &Method,
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    body: &[u8],
+    sessions: &Sessions
+  ) -> Option<Result<HyperResponse<Body>, http::Error>> {
+    let endpoint = path.strip_prefix(INTERACTIONS_PATH)?;
+    let id = match endpoint {
+      "" | "/" => None,
+      _ => Some(endpoint.strip_prefix('/')?.trim_end_matches('/'))
+    };
+    let options = match RegistrationOptions::from_query(query) {
+      Ok(options) => options,
+      Err(err) => return Some(error_response(StatusCode::BAD_REQUEST, &err))
+    };
+    let response = match (method, id) {
+      (&Method::GET, None) => json_response(StatusCode::OK, json!({
+        "interactions": self.active_interactions().iter()
+          .filter(|registered| registered.matches_filter(&options.filter))
+          .map(|registered| registered.to_json())
+          .collect::<Vec<_>>()
+      })),
+      (&Method::POST, None) => {
+        let session_id = options.filter.session.clone().or_else(|| sessions.session_id(headers));
+        let session = match session_id.as_ref().map(|id| (id, sessions.get(id))) {
+          Some((id, None)) => return Some(error_response(StatusCode::BAD_REQUEST,
+            &format!("Session '{}' does not exist or has expired", id))),
+          Some((_, session)) => session,
+          None => None
+        };
+        match parse_interactions(body) {
+          Ok(interactions) => match self.register(interactions, &options, session.as_ref()) {
+            Ok(registered) => json_response(StatusCode::CREATED, json!({
+              "interactions": registered.iter().map(|registered| registered.to_json()).collect::<Vec<_>>()
+            })),
+            Err(err) => error_response(StatusCode::CONFLICT, &err)
+          },
+          Err(err) => error_response(StatusCode::BAD_REQUEST, &err)
+        }
+      },
+      (&Method::DELETE, None) => {
+        let removed = self.remove(|registered| registered.matches_filter(&options.filter));
+        json_response(StatusCode::OK, json!({ "removed": removed }))
+      },
+      (&Method::GET, Some(id)) => match self.active_interactions().iter().find(|registered| registered.id == id) {
+        Some(registered) => json_response(StatusCode::OK, registered.to_json()),
+        None => not_found_response(id)
+      },
+      (&Method::PUT, Some(id)) => match parse_interactions(body) {
+        Ok(interactions) if interactions.len() == 1 => {
+          let (interaction, pact) = interactions[0].clone();
+          match self.replace(id, interaction, pact, &options) {
+            Some(registered) => json_response(StatusCode::OK, registered.to_json()),
+            None => not_found_response(id)
+          }
+        },
+        Ok(_) => error_response(StatusCode::BAD_REQUEST, "Only one interaction can be used to replace a registered interaction"),
+        Err(err) => error_response(StatusCode::BAD_REQUEST, &err)
+      },
+      (&Method::DELETE, Some(id)) => if self.remove(|registered| registered.id == id) > 0 {
+        info!("Removed registered interaction {}", id);
+        HyperResponse::builder().status(StatusCode::NO_CONTENT).body(Body::empty())
+      } else {
+        not_found_response(id)
+      },
+      _ => error_response(StatusCode::METHOD_NOT_ALLOWED, &format!("{} is not supported for {}", method, path))
+    };
+    Some(response)
+  }
+}
+
+impl Default for RegisteredInteractions {
+  fn default() -> Self {
+    RegisteredInteractions::new(DEFAULT_REGISTERED_PRIORITY)
+  }
+}
+
+// Parses the V4 interaction in the body, or all the HTTP interactions if the body is a V4 pact
+fn parse_interactions(body: &[u8]) -> Result<Vec<(SynchronousHttp, V4Pact)>, String> {
+  let json: Value = serde_json::from_slice(body)
+    .map_err(|err| format!("Interaction body is not valid JSON: {}", err))?;
+  if json.get("interactions").is_some() {
+    let pact = load_pact_from_json(INTERACTIONS_PATH, &json)
+      .and_then(|pact| pact.as_v4_pact())
+      .map_err(|err| format!("Failed to load the pact: {}", err))?;
+    let interactions = pact.filter_interactions(V4InteractionType::Synchronous_HTTP).iter()
+      .filter_map(|interaction| interaction.as_v4_http())
+      .collect::<Vec<_>>();
+    if interactions.is_empty() {
+      return Err("The pact does not contain any synchronous HTTP interactions".to_string());
+    }
+    let pact = V4Pact { interactions: vec![], .. pact };
+    Ok(interactions.into_iter().map(|interaction| (interaction, pact.clone())).collect())
+  } else {
+    match json.get("type").and_then(|value| value.as_str()) {
+      Some(interaction_type) if interaction_type != "Synchronous/HTTP" =>
+        Err(format!("Only synchronous HTTP interactions can be registered, got '{}'", interaction_type)),
+      _ => {
+        let interaction = SynchronousHttp::from_json(&json, 0)
+          .map_err(|err| format!("Failed to load the interaction: {}", err))?;
+        Ok(vec![(interaction, registered_pact())])
+      }
+    }
+  }
+}
+
+fn registered_pact() -> V4Pact {
+  V4Pact {
+    consumer: Consumer { name: REGISTERED_PACT_NAME.to_string() },
+    provider: Provider { name: REGISTERED_PACT_NAME.to_string() },
+    .. V4Pact::default()
+  }
+}
+
+fn json_response(status: StatusCode, body: Value) -> Result<HyperResponse<Body>, http::Error> {
+  HyperResponse::builder()
+    .status(status)
+    .header("Content-Type", "application/json")
+    .body(Body::from(body.to_string()))
+}
+
+fn error_response(status: StatusCode, error: &str) -> Result<HyperResponse<Body>, http::Error> {
+  json_response(status, json!({ "error": error }))
+}
+
+fn not_found_response(id: &str) -> Result<HyperResponse<Body>, http::Error> {
+  error_response(StatusCode::NOT_FOUND, &format!("Interaction '{}' is not registered", id))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use expectest::prelude::*;
+  use http::{HeaderMap, HeaderValue, Method, StatusCode};
+  use serde_json::{json, Value};
+
+  use crate::sessions::Sessions;
+
+  use super::*;
+
+  fn interaction_json(description: &str) -> Vec<u8> {
+    json!({
+      "type": "Synchronous/HTTP",
+      "description": description,
+      "request": { "method": "GET", "path": "/orders/1" },
+      "response": { "status": 500 }
+    }).to_string().into_bytes()
+  }
+
+  async fn body_json(response: HyperResponse<Body>) -> Value {
+    serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap()
+  }
+
+  fn request(
+    registered: &RegisteredInteractions,
+    method: Method,
+    path: &str,
+    query: Option<&str>,
+    body: &[u8],
+    sessions: &Sessions
+  ) -> HyperResponse<Body> {
+    registered.handle_request(&method, path, query, &HeaderMap::new(), body, sessions).unwrap().unwrap()
+  }
+
+  #[test]
+  fn parse_interactions_from_an_interaction_or_pact() {
+    let interactions = parse_interactions(&interaction_json("an error")).unwrap();
+    expect!(interactions.len()).to(be_equal_to(1));
+    expect!(interactions[0].0.description.as_str()).to(be_equal_to("an error"));
+    expect!(interactions[0].1.consumer.name.as_str()).to(be_equal_to(REGISTERED_PACT_NAME));
+
+    let pact = json!({
+      "consumer": { "name": "web" },
+      "provider": { "name": "orders" },
+      "interactions": [
+        { "type": "Synchronous/HTTP", "description": "a", "request": { "method": "GET", "path": "/a" }, "response": { "status": 200 } },
+        { "type": "Synchronous/HTTP", "description": "b", "request": { "method": "GET", "path": "/b" }, "response": { "status": 200 } }
+      ],
+      "metadata": { "pactSpecification": { "version": "4.0" } }
+    });
+    let interactions = parse_interactions(pact.to_string().as_bytes()).unwrap();
+    expect!(interactions.iter().map(|(i, _)| i.description.as_str()).collect::<Vec<_>>()).to(be_equal_to(vec!["a", "b"]));
+    expect!(interactions[0].1.consumer.name.as_str()).to(be_equal_to("web"));
+
+    expect!(parse_interactions(br#"{"type": "Asynchronous/Messages", "description": "a"}"#)).to(be_err());
+    expect!(parse_interactions(b"not json")).to(be_err());
+  }
+
+  #[tokio::test]
+  async fn admin_endpoints_register_replace_and_remove_interactions() {
+    let registered = RegisteredInteractions::default();
+    let sessions = Sessions::default();
+    expect!(registered.handle_request(&Method::GET, "/orders", None, &HeaderMap::new(), b"", &sessions)).to(be_none());
+
+    let response = request(&registered, Method::POST, INTERACTIONS_PATH, Some("owner=test-1&priority=5"),
+      &interaction_json("an error"), &sessions);
+    expect!(response.status()).to(be_equal_to(StatusCode::CREATED));
+    let json = body_json(response).await;
+    let id = json["interactions"][0]["id"].as_str().unwrap().to_string();
+    expect!(json["interactions"][0]["owner"].clone()).to(be_equal_to(json!("test-1")));
+    expect!(json["interactions"][0]["priority"].clone()).to(be_equal_to(json!(5)));
+
+    let response = request(&registered, Method::PUT, &format!("{}/{}", INTERACTIONS_PATH, id), None,
+      &interaction_json("a timeout"), &sessions);
+    expect!(response.status()).to(be_equal_to(StatusCode::OK));
+    expect!(registered.for_session(None)[0].interaction.description.as_str()).to(be_equal_to("a timeout"));
+
+    let response = request(&registered, Method::DELETE, &format!("{}/{}", INTERACTIONS_PATH, id), None, b"", &sessions);
+    expect!(response.status()).to(be_equal_to(StatusCode::NO_CONTENT));
+    expect!(registered.for_session(None).is_empty()).to(be_true());
+    let response = request(&registered, Method::DELETE, &format!("{}/{}", INTERACTIONS_PATH, id), None, b"", &sessions);
+    expect!(response.status()).to(be_equal_to(StatusCode::NOT_FOUND));
+  }
+
+  #[tokio::test]
+  async fn remove_interactions_by_owner() {
+    let registered = RegisteredInteractions::default();
+    let sessions = Sessions::default();
+    request(&registered, Method::POST, INTERACTIONS_PATH, Some("owner=a"), &interaction_json("1"), &sessions);
+    request(&registered, Method::POST, INTERACTIONS_PATH, Some("owner=b"), &interaction_json("2"), &sessions);
+    let response = request(&registered, Method::DELETE, INTERACTIONS_PATH, Some("owner=a"), b"", &sessions);
+    expect!(body_json(response).await).to(be_equal_to(json!({ "removed": 1 })));
+    expect!(registered.for_session(None).iter().map(|r| r.owner.clone()).collect::<Vec<_>>())
+      .to(be_equal_to(vec![Some("b".to_string())]));
+  }
+
+  #[test]
+  fn duplicate_keys_are_rejected() {
+    let registered = RegisteredInteractions::default();
+    let interaction = SynchronousHttp { key: Some("order-error".to_string()), .. SynchronousHttp::default() };
+    let options = RegistrationOptions::default();
+    expect!(registered.register(vec![(interaction.clone(), registered_pact())], &options, None)).to(be_ok());
+    expect!(registered.register(vec![(interaction, registered_pact())], &options, None)).to(be_err());
+  }
+
+  #[test]
+  fn interactions_expire_after_the_ttl() {
+    let registered = RegisteredInteractions::default();
+    let options = RegistrationOptions { ttl: Some(Duration::from_millis(20)), .. RegistrationOptions::default() };
+    registered.register(vec![(SynchronousHttp::default(), registered_pact())], &options, None).unwrap();
+    expect!(registered.for_session(None).len()).to(be_equal_to(1));
+    let generation = registered.generation();
+    std::thread::sleep(Duration::from_millis(30));
+    expect!(registered.generation()).to_not(be_equal_to(generation));
+    expect!(registered.for_session(None).is_empty()).to(be_true());
+  }
+
+  #[test]
+  fn generation_changes_when_the_interactions_change() {
+    let registered = RegisteredInteractions::default();
+    let options = RegistrationOptions::default();
+    let interaction = SynchronousHttp { key: Some("order-error".to_string()), .. SynchronousHttp::default() };
+    let initial = registered.generation();
+    expect!(registered.generation()).to(be_equal_to(initial));
+
+    registered.register(vec![(interaction.clone(), registered_pact())], &options, None).unwrap();
+    let registered_generation = registered.generation();
+    expect!(registered_generation).to_not(be_equal_to(initial));
+
+    registered.replace("order-error", interaction, registered_pact(), &options).unwrap();
+    let replaced_generation = registered.generation();
+    expect!(replaced_generation).to_not(be_equal_to(registered_generation));
+
+    expect!(registered.remove(|_| false)).to(be_equal_to(0));
+    expect!(registered.generation()).to(be_equal_to(replaced_generation));
+    expect!(registered.remove(|_| true)).to(be_equal_to(1));
+    expect!(registered.generation()).to_not(be_equal_to(replaced_generation));
+  }
+
+  #[test]
+  fn session_interactions_only_apply_to_the_session() {
+    let registered = RegisteredInteractions::default();
+    let sessions = Sessions::default();
+    let session = sessions.create(Some("shard-1".to_string()), None).unwrap();
+    let other = sessions.create(Some("shard-2".to_string()), None).unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert("x-stub-session", HeaderValue::from_static("shard-1"));
+    let response = registered.handle_request(&Method::POST, INTERACTIONS_PATH, None, &headers,
+      &interaction_json("an error"), &sessions).unwrap().unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::CREATED));
+
+    expect!(registered.for_session(Some(&session)).len()).to(be_equal_to(1));
+    expect!(registered.for_session(Some(&other)).is_empty()).to(be_true());
+    expect!(registered.for_session(None).is_empty()).to(be_true());
+
+    drop(session);
+    sessions.remove("shard-1");
+    expect!(registered.active_interactions().is_empty()).to(be_true());
+  }
+}
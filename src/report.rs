@@ -0,0 +1,211 @@
+//! Functions relating to the machine-readable startup report (port file and ready JSON)
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use itertools::Itertools;
+use pact_models::prelude::v4::*;
+use pact_models::v4::V4InteractionType;
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use crate::PactSource;
+use crate::server::BoundAddress;
+
+/// Files to write once the server has been bound to its addresses
+#[derive(Debug, Clone, Default)]
+pub struct StartupReport {
+  /// File to write the bound port to
+  pub port_file: Option<PathBuf>,
+  /// File to write the JSON startup report to
+  pub ready_json: Option<PathBuf>,
+  /// Summary of the loaded pacts to include in the JSON report
+  pub summary: Value
+}
+
+impl StartupReport {
+  /// Creates the startup report, summarising the loaded pacts. `served_types` are the interaction types
+  /// that the running server will respond with, all other interactions are reported as skipped.
+  pub fn new(
+    port_file: Option<PathBuf>,
+    ready_json: Option<PathBuf>,
+    pacts: &[(V4Pact, PactSource)],
+    served_types: &[V4InteractionType]
+  ) -> Self {
+    StartupReport {
+      port_file,
+      ready_json,
+      summary: pact_summary(pacts, served_types)
+    }
+  }
+
+  /// Writes the port file and the JSON report for the bound addresses
+  pub fn write(&self, bound: &[BoundAddress]) -> anyhow::Result<()> {
+    let port = bound_port(bound);
+    if let Some(port_file) = &self.port_file {
+      match port {
+        Some(port) => write_file(port_file, &format!("{}\n", port))?,
+        None => warn!("Not writing the port file {} as the server is not listening on a TCP port",
+          port_file.display())
+      }
+    }
+    if let Some(ready_json) = &self.ready_json {
+      let mut report = json!({
+        "addresses": bound.iter().map(|addr| addr.to_string()).collect::<Vec<_>>(),
+        "port": port
+      });
+      if let (Some(report), Some(summary)) = (report.as_object_mut(), self.summary.as_object()) {
+        report.extend(summary.clone());
+      }
+      write_file(ready_json, &format!("{:#}\n", report))?;
+    }
+    Ok(())
+  }
+}
+
+// Port of the first TCP address that the server is bound to
+fn bound_port(bound: &[BoundAddress]) -> Option<u16> {
+  bound.iter().find_map(|addr| match addr {
+    BoundAddress::Tcp(addr) => Some(addr.port()),
+    _ => None
+  })
+}
+
+// Writes the file via a temporary file, so that anything watching for the file never reads a partial one
+fn write_file(path: &Path, contents: &str) -> anyhow::Result<()> {
+  let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+  tmp_name.push(".tmp");
+  let tmp_path = path.with_file_name(tmp_name);
+  fs::write(&tmp_path, contents)
+    .with_context(|| format!("Failed to write '{}'", tmp_path.display()))?;
+  fs::rename(&tmp_path, path)
+    .with_context(|| format!("Failed to write '{}'", path.display()))?;
+  debug!("Wrote startup report file {}", path.display());
+  Ok(())
+}
+
+fn pact_summary(pacts: &[(V4Pact, PactSource)], served_types: &[V4InteractionType]) -> Value {
+  let counts = pacts.iter()
+    .map(|(pact, _)| ((pact.consumer.name.clone(), pact.provider.name.clone()), pact.interactions.len()))
+    .into_grouping_map()
+    .sum();
+  let skipped = pacts.iter()
+    .flat_map(|(pact, _)| pact.interactions.iter().map(move |interaction| (pact, interaction)))
+    .filter(|(_, interaction)| !served_types.contains(&interaction.v4_type()))
+    .map(|(pact, interaction)| json!({
+      "consumer": pact.consumer.name,
+      "provider": pact.provider.name,
+      "description": interaction.description(),
+      "type": interaction.v4_type().to_string(),
+      "reason": format!("{} interactions are not served by this stub server configuration", interaction.v4_type())
+    }))
+    .collect::<Vec<_>>();
+  json!({
+    "pactCount": pacts.len(),
+    "interactionCount": pacts.iter().map(|(pact, _)| pact.interactions.len()).sum::<usize>(),
+    "pacts": counts.iter()
+      .sorted()
+      .map(|((consumer, provider), count)| json!({
+        "consumer": consumer,
+        "provider": provider,
+        "interactionCount": count
+      }))
+      .collect::<Vec<_>>(),
+    "skippedInteractions": skipped
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use pact_models::prelude::*;
+  use pact_models::prelude::v4::*;
+  use pact_models::v4::async_message::AsynchronousMessage;
+  use pact_models::v4::interaction::V4Interaction;
+  use serde_json::json;
+
+  use crate::server::BoundAddress;
+
+  use super::*;
+
+  fn pact(consumer: &str, provider: &str, interactions: Vec<Box<dyn V4Interaction + Send + Sync + std::panic::RefUnwindSafe>>) -> (V4Pact, PactSource) {
+    (V4Pact {
+      consumer: Consumer { name: consumer.to_string() },
+      provider: Provider { name: provider.to_string() },
+      interactions,
+      .. V4Pact::default()
+    }, PactSource::Unknown)
+  }
+
+  fn http(description: &str) -> SynchronousHttp {
+    SynchronousHttp { description: description.to_string(), .. SynchronousHttp::default() }
+  }
+
+  #[test]
+  fn pact_summary_counts_the_interactions_per_consumer_and_provider() {
+    let pacts = vec![
+      pact("web", "orders", vec![http("a").boxed_v4(), http("b").boxed_v4()]),
+      pact("mobile", "orders", vec![http("c").boxed_v4()]),
+      pact("web", "orders", vec![
+        http("d").boxed_v4(),
+        AsynchronousMessage { description: "order created".to_string(), .. AsynchronousMessage::default() }.boxed_v4()
+      ])
+    ];
+    let summary = pact_summary(&pacts, &[V4InteractionType::Synchronous_HTTP]);
+    expect!(summary).to(be_equal_to(json!({
+      "pactCount": 3,
+      "interactionCount": 5,
+      "pacts": [
+        { "consumer": "mobile", "provider": "orders", "interactionCount": 1 },
+        { "consumer": "web", "provider": "orders", "interactionCount": 4 }
+      ],
+      "skippedInteractions": [
+        {
+          "consumer": "web",
+          "provider": "orders",
+          "description": "order created",
+          "type": "Asynchronous/Messages",
+          "reason": "Asynchronous/Messages interactions are not served by this stub server configuration"
+        }
+      ]
+    })));
+  }
+
+  #[test]
+  fn write_creates_the_port_file_and_ready_json() {
+    let dir = std::env::temp_dir().join(format!("pact-stub-server-report-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let report = StartupReport::new(Some(dir.join("port")), Some(dir.join("ready.json")),
+      &[pact("web", "orders", vec![http("a").boxed_v4()])], &[V4InteractionType::Synchronous_HTTP]);
+    let bound = vec![
+      BoundAddress::Tcp("127.0.0.1:1234".parse().unwrap()),
+      BoundAddress::Tcp("[::1]:1234".parse().unwrap())
+    ];
+
+    expect!(report.write(&bound)).to(be_ok());
+    expect!(fs::read_to_string(dir.join("port")).unwrap()).to(be_equal_to("1234\n"));
+    let json: Value = serde_json::from_str(&fs::read_to_string(dir.join("ready.json")).unwrap()).unwrap();
+    expect!(json).to(be_equal_to(json!({
+      "addresses": ["127.0.0.1:1234", "[::1]:1234"],
+      "port": 1234,
+      "pactCount": 1,
+      "interactionCount": 1,
+      "pacts": [{ "consumer": "web", "provider": "orders", "interactionCount": 1 }],
+      "skippedInteractions": []
+    })));
+    expect!(dir.join("ready.json.tmp").exists()).to(be_false());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn write_does_not_create_the_port_file_without_a_tcp_address() {
+    let dir = std::env::temp_dir().join(format!("pact-stub-server-report-unix-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let report = StartupReport::new(Some(dir.join("port")), None, &[], &[]);
+
+    expect!(report.write(&[BoundAddress::Unix(dir.join("stub.sock"))])).to(be_ok());
+    expect!(dir.join("port").exists()).to(be_false());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
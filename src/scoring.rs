@@ -0,0 +1,215 @@
+//! Functions relating to ranking the interactions that match a request. Each matching interaction is
+//! given a score from the parts of the request it matched (literal values score higher than values
+//! matched with matching rules), the mismatches that were tolerated (weighted by where they are), and
+//! how many of its provider states were selected.
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+use pact_matching::Mismatch;
+use pact_models::matchingrules::MatchingRules;
+use pact_models::prelude::v4::*;
+
+use crate::provider_states::ProviderStateFilter;
+
+/// Score for each part of the request that matched a literal value of the interaction
+pub const LITERAL_MATCH_WEIGHT: i64 = 3;
+/// Score for each part of the request that matched the interaction using a matching rule
+pub const MATCHER_MATCH_WEIGHT: i64 = 1;
+/// Score for each interaction provider state selected by the provider state filter
+pub const PROVIDER_STATE_WEIGHT: i64 = 4;
+/// Penalty for each header mismatch
+pub const HEADER_MISMATCH_WEIGHT: i64 = 10;
+/// Penalty for each body mismatch
+pub const BODY_MISMATCH_WEIGHT: i64 = 20;
+/// Penalty for each query parameter mismatch
+pub const QUERY_MISMATCH_WEIGHT: i64 = 30;
+/// Penalty for any other mismatch
+pub const OTHER_MISMATCH_WEIGHT: i64 = 5;
+
+/// Score of an interaction that matched a request. Higher scores are better matches.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MatchScore {
+  /// Number of parts of the request that matched literal values
+  pub literal_matches: usize,
+  /// Number of parts of the request that matched using matching rules
+  pub matcher_matches: usize,
+  /// Number of interaction provider states selected by the provider state filter
+  pub provider_states: usize,
+  /// Number of header mismatches
+  pub header_mismatches: usize,
+  /// Number of body mismatches
+  pub body_mismatches: usize,
+  /// Number of query parameter mismatches
+  pub query_mismatches: usize,
+  /// Number of other mismatches
+  pub other_mismatches: usize
+}
+
+impl MatchScore {
+  /// Calculates the score of the interaction from the mismatches with the request
+  pub fn calculate(
+    interaction: &SynchronousHttp,
+    mismatches: &[Mismatch],
+    provider_state: Option<&ProviderStateFilter>
+  ) -> MatchScore {
+    let mut score = MatchScore::default();
+    let mut mismatched_headers = HashSet::new();
+    let mut mismatched_query = HashSet::new();
+    let mut body_mismatched = false;
+    for mismatch in mismatches {
+      match mismatch {
+        Mismatch::HeaderMismatch { key, .. } => {
+          score.header_mismatches += 1;
+          mismatched_headers.insert(key.to_lowercase());
+        },
+        Mismatch::QueryMismatch { parameter, .. } => {
+          score.query_mismatches += 1;
+          mismatched_query.insert(parameter.as_str());
+        },
+        Mismatch::BodyMismatch { .. } | Mismatch::BodyTypeMismatch { .. } => {
+          score.body_mismatches += 1;
+          body_mismatched = true;
+        },
+        _ => score.other_mismatches += 1
+      }
+    }
+
+    let request = &interaction.request;
+    let rules = &request.matching_rules;
+    score.add_match(has_rules(rules, "path"));
+    if let Some(query) = &request.query {
+      for name in query.keys().filter(|name| !mismatched_query.contains(name.as_str())) {
+        score.add_match(has_field_rule(rules, "query", name));
+      }
+    }
+    if let Some(headers) = &request.headers {
+      for name in headers.keys().filter(|name| !mismatched_headers.contains(&name.to_lowercase())) {
+        score.add_match(has_field_rule(rules, "header", name));
+      }
+    }
+    if request.body.is_present() && !body_mismatched {
+      score.add_match(has_rules(rules, "body"));
+    }
+
+    score.provider_states = provider_state
+      .map(|filter| filter.matching_states(&interaction.provider_states))
+      .unwrap_or_default();
+    score
+  }
+
+  fn add_match(&mut self, with_matcher: bool) {
+    if with_matcher {
+      self.matcher_matches += 1;
+    } else {
+      self.literal_matches += 1;
+    }
+  }
+
+  /// Total score using the match and mismatch weights
+  pub fn total(&self) -> i64 {
+    self.literal_matches as i64 * LITERAL_MATCH_WEIGHT
+      + self.matcher_matches as i64 * MATCHER_MATCH_WEIGHT
+      + self.provider_states as i64 * PROVIDER_STATE_WEIGHT
+      - self.header_mismatches as i64 * HEADER_MISMATCH_WEIGHT
+      - self.body_mismatches as i64 * BODY_MISMATCH_WEIGHT
+      - self.query_mismatches as i64 * QUERY_MISMATCH_WEIGHT
+      - self.other_mismatches as i64 * OTHER_MISMATCH_WEIGHT
+  }
+}
+
+impl Display for MatchScore {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} ({} literal matches, {} matcher matches, {} provider states, {} header mismatches, \
+      {} body mismatches, {} query mismatches, {} other mismatches)", self.total(), self.literal_matches,
+      self.matcher_matches, self.provider_states, self.header_mismatches, self.body_mismatches,
+      self.query_mismatches, self.other_mismatches)
+  }
+}
+
+fn has_rules(rules: &MatchingRules, category: &str) -> bool {
+  rules.rules_for_category(category)
+    .map(|category| category.is_not_empty())
+    .unwrap_or(false)
+}
+
+fn has_field_rule(rules: &MatchingRules, category: &str, name: &str) -> bool {
+  rules.rules_for_category(category)
+    .map(|category| category.rules.keys().any(|path| {
+      path.first_field().map(|field| field.eq_ignore_ascii_case(name)).unwrap_or(false)
+    }))
+    .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use maplit::hashmap;
+  use pact_matching::Mismatch;
+  use pact_models::matchingrules;
+  use pact_models::matchingrules::MatchingRule;
+  use pact_models::prelude::ProviderState;
+  use pact_models::v4::http_parts::HttpRequest;
+  use regex::Regex;
+
+  use super::*;
+
+  fn interaction() -> SynchronousHttp {
+    SynchronousHttp {
+      request: HttpRequest {
+        path: "/orders".to_string(),
+        query: Some(hashmap! { "page".to_string() => vec!["1".to_string()] }),
+        headers: Some(hashmap! {
+          "Accept".to_string() => vec!["application/json".to_string()],
+          "X-Request-Id".to_string() => vec!["1234".to_string()]
+        }),
+        matching_rules: matchingrules! {
+          "header" => { "X-Request-Id" => [ MatchingRule::Type ] }
+        },
+        .. HttpRequest::default()
+      },
+      .. SynchronousHttp::default()
+    }
+  }
+
+  #[test]
+  fn literal_matches_score_higher_than_matcher_matches() {
+    let score = MatchScore::calculate(&interaction(), &[], None);
+    expect!(score.literal_matches).to(be_equal_to(3));
+    expect!(score.matcher_matches).to(be_equal_to(1));
+    expect!(score.total()).to(be_equal_to(3 * LITERAL_MATCH_WEIGHT + MATCHER_MATCH_WEIGHT));
+  }
+
+  #[test]
+  fn mismatches_are_weighted() {
+    let mismatches = vec![
+      Mismatch::HeaderMismatch {
+        key: "accept".to_string(),
+        expected: "application/json".to_string(),
+        actual: "text/html".to_string(),
+        mismatch: String::default()
+      },
+      Mismatch::BodyMismatch { path: "$".to_string(), expected: None, actual: None, mismatch: String::default() }
+    ];
+    let score = MatchScore::calculate(&interaction(), &mismatches, None);
+    expect!(score.literal_matches).to(be_equal_to(2));
+    expect!(score.header_mismatches).to(be_equal_to(1));
+    expect!(score.body_mismatches).to(be_equal_to(1));
+    expect!(score.total()).to(be_equal_to(2 * LITERAL_MATCH_WEIGHT + MATCHER_MATCH_WEIGHT
+      - HEADER_MISMATCH_WEIGHT - BODY_MISMATCH_WEIGHT));
+  }
+
+  #[test]
+  fn selected_provider_states_increase_the_score() {
+    let interaction = SynchronousHttp {
+      provider_states: vec![ProviderState::default("an order exists"), ProviderState::default("a user exists")],
+      .. interaction()
+    };
+    let filter = ProviderStateFilter::from(Regex::new("exists").unwrap());
+    let score = MatchScore::calculate(&interaction, &[], Some(&filter));
+    expect!(score.provider_states).to(be_equal_to(2));
+    let filter = ProviderStateFilter::from(Regex::new("order").unwrap());
+    let score = MatchScore::calculate(&interaction, &[], Some(&filter));
+    expect!(score.provider_states).to(be_equal_to(1));
+  }
+}
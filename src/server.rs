@@ -1,42 +1,62 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::future::{Ready, ready};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
 use futures::executor::block_on;
 use futures::future::{Future, FutureExt};
 use futures::stream::{StreamExt, FuturesUnordered};
 use futures::task::{Context, Poll};
-use http::{Error, StatusCode};
+use http::{Error, Method, StatusCode};
 use hyper::{Body, Request as HyperRequest, Response as HyperResponse, Server};
-use hyper::server::conn::AddrStream;
+use hyper::server::{accept, Builder};
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use itertools::Itertools;
 use maplit::hashmap;
 use pact_matching::{CoreMatchingContext, DiffConfig, Mismatch};
-use pact_models::generators::GeneratorTestMode;
+use pact_models::content_types::JSON;
+use pact_models::http_parts::HttpPart;
 use pact_models::prelude::*;
 use pact_models::prelude::v4::*;
 use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
 use pact_models::v4::V4InteractionType;
 use regex::Regex;
+use serde_json::{json, Value};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
 use tower::ServiceBuilder;
 use tower_http::classify::{ServerErrorsAsFailures, SharedClassifier};
-use tower_http::trace::{DefaultMakeSpan, Trace, TraceLayer};
+use tower_http::trace::{Trace, TraceLayer};
 use tower_service::Service;
 use tracing::{debug, error, info, warn};
 
-use crate::{pact_support, PactSource};
-
-// Structure representing an indexed interaction for faster lookup
-struct IndexedInteraction {
-  interaction: SynchronousHttp,
-  pact: V4Pact,
-  method: String,
-  path: String,
-  path_context: CoreMatchingContext,
-  provider_states: Vec<String>,
-}
+use crate::{compression, coverage, logging, messages, metrics, pact_support, plugins, provider_states, redaction, sessions, tls, PactSource};
+use crate::compression::ResponseCompression;
+use crate::cors::CorsPolicy;
+use crate::health::HealthCheck;
+use crate::logging::{AccessLog, AccessLogEntry};
+use crate::matching::MatchSettings;
+use crate::messages::MessageTarget;
+use crate::metrics::RequestResult;
+use crate::negotiation::NegotiationResult;
+use crate::paths::PathNormalisation;
+use crate::provider_states::{ProviderStateFilter, ProviderStateSelector};
+use crate::registration::{RegisteredInteraction, RegisteredInteractions};
+use crate::report::StartupReport;
+use crate::scoring::MatchScore;
+use crate::sessions::{JournalEntry, Session, Sessions};
+use crate::shutdown::{self, Shutdown, DEFAULT_DRAIN_TIMEOUT};
+use crate::telemetry::{self, RequestSpan};
+
+/// Default request header used to select the interaction to respond with
+pub const DEFAULT_INTERACTION_HEADER: &str = "X-Pact-Interaction";
 
 // Structure to store method+path indexes for quick lookup
 #[derive(Clone)]
@@ -45,12 +65,25 @@ struct InteractionIndex {
   method_path_index: HashMap<String, Vec<usize>>,
   // All interactions in a flat array for efficient access
   all_interactions: Vec<SynchronousHttp>,
+  // Normalised path of each interaction, used to look up and match the interactions. The interactions keep
+  // their original paths, as these identify them in the coverage report and metrics.
+  normalised_paths: Vec<String>,
   // All pacts in a flat array, corresponding to the interaction index
   pacts: Vec<V4Pact>,
   // Provider states for each interaction
-  provider_states: Vec<Vec<String>>,
+  provider_states: Vec<Vec<ProviderState>>,
   // Precomputed path matching contexts
   path_contexts: Vec<CoreMatchingContext>,
+  // Priority of each interaction (0 for the interactions loaded from pact files)
+  priorities: Vec<i32>,
+  // Interactions from this index onwards were registered at runtime
+  registered_start: usize,
+  // Normalisation applied to the interaction paths, and to the paths of requests matched against them
+  path_normalisation: PathNormalisation,
+  // Normalisation applied instead for the interactions with path matching rules
+  matcher_path_normalisation: PathNormalisation,
+  // If each interaction has path matching rules
+  path_matchers: Vec<bool>,
 }
 
 impl InteractionIndex {
@@ -58,64 +91,115 @@ impl InteractionIndex {
     InteractionIndex {
       method_path_index: HashMap::new(),
       all_interactions: Vec::new(),
+      normalised_paths: Vec::new(),
       pacts: Vec::new(),
       provider_states: Vec::new(),
       path_contexts: Vec::new(),
+      priorities: Vec::new(),
+      registered_start: 0,
+      path_normalisation: PathNormalisation::default(),
+      matcher_path_normalisation: PathNormalisation::default(),
+      path_matchers: Vec::new(),
     }
   }
 
   fn build_from_sources(sources: &[(V4Pact, PactSource)]) -> Self {
-    let mut index = InteractionIndex::new();
+    InteractionIndex::build_with_path_normalisation(sources, PathNormalisation::default())
+  }
+
+  fn build_with_path_normalisation(sources: &[(V4Pact, PactSource)], path_normalisation: PathNormalisation) -> Self {
+    let mut index = InteractionIndex {
+      matcher_path_normalisation: path_normalisation.for_path_matchers(),
+      path_normalisation,
+      .. InteractionIndex::new()
+    };
     
     for (pact, _) in sources {
       for interaction in pact.filter_interactions(V4InteractionType::Synchronous_HTTP) {
         if let Some(http_interaction) = interaction.as_v4_http() {
-          let interaction_idx = index.all_interactions.len();
-          
-          // Add to main interaction list
-          index.all_interactions.push(http_interaction.clone());
-          index.pacts.push(pact.clone());
-          
-          // Create a method+path key for fast lookups
-          let key = format!("{}:{}", http_interaction.request.method.to_uppercase(), 
-                          http_interaction.request.path);
-          
-          // Add to the method_path index
-          index.method_path_index
-            .entry(key)
-            .or_insert_with(Vec::new)
-            .push(interaction_idx);
-          
-          // Extract provider states for faster filtering
-          let provider_state_names = http_interaction.provider_states
-            .iter()
-            .map(|ps| ps.name.clone())
-            .collect::<Vec<_>>();
-          index.provider_states.push(provider_state_names);
-          
-          // Precompute path matching context
-          let path_context = CoreMatchingContext::new(
-            DiffConfig::NoUnexpectedKeys,
-            &http_interaction.request.matching_rules.rules_for_category("path").unwrap_or_default(),
-            &hashmap! {}
-          );
-          index.path_contexts.push(path_context);
+          index.add_interaction(http_interaction, pact.clone(), 0);
         }
       }
     }
+    index.registered_start = index.all_interactions.len();
     
     index
   }
+
+  // Returns a copy of the index with the interactions registered at runtime added
+  fn with_registered(&self, registered: &[RegisteredInteraction]) -> Self {
+    let mut index = self.clone();
+    for registered in registered {
+      index.add_interaction(registered.interaction.clone(), registered.pact.clone(), registered.priority);
+    }
+    index
+  }
+
+  fn add_interaction(&mut self, http_interaction: SynchronousHttp, pact: V4Pact, priority: i32) {
+    let interaction_idx = self.all_interactions.len();
+    let path_rules = http_interaction.request.matching_rules.rules_for_category("path").unwrap_or_default();
+    self.path_matchers.push(!path_rules.is_empty());
+    let normalised_path = self.normalisation_for(interaction_idx).normalise(&http_interaction.request.path);
+    
+    // Create a method+path key for fast lookups
+    let key = format!("{}:{}", http_interaction.request.method.to_uppercase(), normalised_path);
+    
+    // Add to the method_path index
+    self.method_path_index
+      .entry(key)
+      .or_default()
+      .push(interaction_idx);
+    
+    // Extract provider states for faster filtering
+    self.provider_states.push(http_interaction.provider_states.clone());
+    
+    // Precompute path matching context
+    let path_context = CoreMatchingContext::new(DiffConfig::NoUnexpectedKeys, &path_rules, &hashmap! {});
+    self.path_contexts.push(path_context);
+    self.priorities.push(priority);
+    
+    // Add to main interaction list
+    self.all_interactions.push(http_interaction);
+    self.normalised_paths.push(normalised_path);
+    self.pacts.push(pact);
+  }
   
+  // Path normalisation for the interaction. The path matching rules of an interaction are applied to the
+  // request path, so they can not match a path that was given a base path or lower-cased.
+  fn normalisation_for(&self, idx: usize) -> &PathNormalisation {
+    if self.path_matchers[idx] {
+      &self.matcher_path_normalisation
+    } else {
+      &self.path_normalisation
+    }
+  }
+
+  // Normalises the path of a request that is matched against the interaction
+  fn request_path<'a>(&self, idx: usize, path: &'a str) -> Cow<'a, str> {
+    if self.path_normalisation.is_enabled() {
+      Cow::Owned(self.normalisation_for(idx).normalise(path))
+    } else {
+      Cow::Borrowed(path)
+    }
+  }
+
   // Get candidate interactions by method and path
   fn get_candidates_by_method_path(&self, method: &str, path: &str) -> Vec<usize> {
-    let key = format!("{}:{}", method.to_uppercase(), path);
+    let key = format!("{}:{}", method.to_uppercase(), self.path_normalisation.normalise(path));
     match self.method_path_index.get(&key) {
       Some(idx_list) => idx_list.clone(),
       None => Vec::new() // No exact matches
     }
   }
   
+  // Get the interactions with path matching rules that match the request method and path, other than the
+  // ones already tried
+  fn get_path_matcher_candidates(&self, request: &HttpRequest, tried: &[usize]) -> Vec<usize> {
+    (0..self.all_interactions.len())
+      .filter(|idx| self.path_matchers[*idx] && !tried.contains(idx) && self.quick_check_path_match(*idx, request))
+      .collect()
+  }
+  
   // Quick check if a candidate interaction matches the request method and path
   fn quick_check_path_match(&self, idx: usize, request: &HttpRequest) -> bool {
     let interaction = &self.all_interactions[idx];
@@ -126,28 +210,39 @@ impl InteractionIndex {
     }
     
     // Path check with precomputed context
-    if pact_matching::match_path(&interaction.request.path, &request.path, &self.path_contexts[idx]).is_err() {
+    let path = self.request_path(idx, &request.path);
+    if pact_matching::match_path(&self.normalised_paths[idx], &path, &self.path_contexts[idx]).is_err() {
       return false;
     }
     
     true
   }
   
+  // Get the methods of the interactions with a path that matches, for the Allow header. HEAD is allowed for
+  // any path with a GET interaction, as HEAD requests are answered from the GET interaction.
+  fn allowed_methods(&self, path: &str) -> Vec<String> {
+    let mut methods = (0..self.all_interactions.len())
+      .filter(|&idx| pact_matching::match_path(&self.normalised_paths[idx], &self.request_path(idx, path),
+        &self.path_contexts[idx]).is_ok())
+      .map(|idx| self.all_interactions[idx].request.method.to_uppercase())
+      .collect::<Vec<_>>();
+    if methods.iter().any(|method| method == "GET") {
+      methods.push("HEAD".to_string());
+    }
+    methods.sort();
+    methods.dedup();
+    methods
+  }
+
   // Get all candidate interactions that match the provider state filter
   fn filter_by_provider_state(&self, indices: &[usize], 
-                              provider_state: &Option<Regex>, 
+                              provider_state: &Option<ProviderStateFilter>, 
                               empty_provider_states: bool) -> Vec<usize> {
     let mut filtered = Vec::new();
     
     for &idx in indices {
-      let provider_states = &self.provider_states[idx];
       let matches = match provider_state {
-        Some(regex) => {
-          empty_provider_states && provider_states.is_empty() ||
-            provider_states.iter().any(|state| {
-              empty_provider_states && state.is_empty() || regex.is_match(state)
-            })
-        },
+        Some(filter) => filter.matches(&self.provider_states[idx], empty_provider_states),
         None => true
       };
       
@@ -159,23 +254,187 @@ impl InteractionIndex {
     filtered
   }
   
+  // Get the interactions selected by key, description or index (in that order)
+  fn select_interactions(&self, selection: &str) -> Vec<usize> {
+    let by_key = (0..self.all_interactions.len())
+      .filter(|&idx| self.all_interactions[idx].key.as_deref() == Some(selection))
+      .collect::<Vec<_>>();
+    if !by_key.is_empty() {
+      return by_key;
+    }
+    let by_description = (0..self.all_interactions.len())
+      .filter(|&idx| self.all_interactions[idx].description == selection)
+      .collect::<Vec<_>>();
+    if !by_description.is_empty() {
+      return by_description;
+    }
+    selection.parse::<usize>().ok()
+      .filter(|&idx| idx < self.all_interactions.len())
+      .into_iter()
+      .collect()
+  }
+  
   // Get interaction and pact by index
   fn get_interaction_and_pact(&self, idx: usize) -> (SynchronousHttp, V4Pact) {
     (self.all_interactions[idx].clone(), self.pacts[idx].clone())
   }
 }
 
+// Indexes with the interactions registered at runtime added, for each session (or requests not in a session).
+// They are cached until the registered interactions change, so they are not rebuilt for every request.
+#[derive(Clone, Default)]
+struct RegisteredIndexes {
+  cache: Arc<Mutex<CachedIndexes>>
+}
+
+#[derive(Default)]
+struct CachedIndexes {
+  // Generation of the registered interactions the indexes were built from
+  generation: usize,
+  // Indexes keyed by session ID
+  indexes: HashMap<Option<String>, Arc<InteractionIndex>>
+}
+
+impl RegisteredIndexes {
+  // Returns the index with the registered interactions that apply to requests in the session added
+  fn index_for_session(
+    &self,
+    index: &Arc<InteractionIndex>,
+    registered: &RegisteredInteractions,
+    session: Option<&Arc<Session>>
+  ) -> Arc<InteractionIndex> {
+    let generation = registered.generation();
+    let mut cache = self.cache.lock().unwrap_or_else(|err| err.into_inner());
+    if cache.generation != generation {
+      cache.indexes.clear();
+      cache.generation = generation;
+    }
+
+    cache.indexes.entry(session.map(|session| session.id().to_string()))
+      .or_insert_with(|| {
+        let registered_interactions = registered.for_session(session);
+        if registered_interactions.is_empty() {
+          index.clone()
+        } else {
+          debug!("Building the interaction index with {} registered interactions", registered_interactions.len());
+          Arc::new(index.with_registered(&registered_interactions))
+        }
+      })
+      .clone()
+  }
+}
+
+/// HTTP protocol versions that the server will accept
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpProtocol {
+  /// Accept both HTTP/1 and HTTP/2 (via ALPN with TLS, or prior knowledge h2c without)
+  Auto,
+  /// Only accept HTTP/1
+  Http1Only,
+  /// Only accept HTTP/2
+  Http2Only
+}
+
+/// Addresses that the server will listen on
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenAddresses {
+  /// IP addresses to bind to. All addresses will use the same port.
+  pub hosts: Vec<IpAddr>,
+  /// Port to bind to (0 will use a random port assigned by the OS)
+  pub port: u16,
+  /// Path of a Unix domain socket to listen on
+  pub unix_socket: Option<PathBuf>
+}
+
+/// Address that the server has been bound to
+#[derive(Debug, Clone, PartialEq)]
+pub enum BoundAddress {
+  /// TCP socket address
+  Tcp(SocketAddr),
+  /// Path of a Unix domain socket
+  Unix(PathBuf)
+}
+
+impl Display for BoundAddress {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BoundAddress::Tcp(addr) => write!(f, "{}", addr),
+      BoundAddress::Unix(path) => write!(f, "unix:{}", path.display())
+    }
+  }
+}
+
+type ServerFuture = Pin<Box<dyn Future<Output = Result<(), ExitCode>> + Send>>;
+
+// Binds a TCP listener to the address. IPv6 listeners only accept IPv6 connections, so that the
+// same port can also be bound on an IPv4 address.
+pub(crate) fn tcp_listener(addr: SocketAddr) -> std::io::Result<AddrIncoming> {
+  let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM,
+    Some(socket2::Protocol::TCP))?;
+  if addr.is_ipv6() {
+    socket.set_only_v6(true)?;
+  }
+  #[cfg(unix)] socket.set_reuse_address(true)?;
+  socket.set_nonblocking(true)?;
+  socket.bind(&addr.into())?;
+  socket.listen(1024)?;
+  let listener = tokio::net::TcpListener::from_std(socket.into())?;
+  AddrIncoming::from_listener(listener).map_err(std::io::Error::other)
+}
+
+#[cfg(unix)]
+fn unix_listener(path: &PathBuf) -> std::io::Result<tokio::net::UnixListener> {
+  use std::os::unix::fs::FileTypeExt;
+
+  // Remove any socket file left behind by a previous run, but not one that another server is listening on
+  if let Ok(metadata) = std::fs::metadata(path) {
+    if metadata.file_type().is_socket() {
+      match std::os::unix::net::UnixStream::connect(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => std::fs::remove_file(path)?,
+        _ => return Err(std::io::Error::new(std::io::ErrorKind::AddrInUse,
+          format!("address in use, another process is listening on {}", path.display())))
+      }
+    }
+  }
+  tokio::net::UnixListener::bind(path)
+}
+
+fn with_protocol<I>(builder: Builder<I>, protocol: HttpProtocol) -> Builder<I> {
+  match protocol {
+    HttpProtocol::Auto => builder,
+    HttpProtocol::Http1Only => builder.http1_only(true),
+    HttpProtocol::Http2Only => builder.http2_only(true)
+  }
+}
+
 #[derive(Clone)]
 pub struct ServerHandler {
   sources: Vec<(V4Pact, PactSource)>,
-  interaction_index: InteractionIndex,
-  auto_cors: bool,
-  cors_referer: bool,
-  provider_state: Option<Regex>,
-  provider_state_header_name: Option<String>,
-  empty_provider_states: bool
+  interaction_index: Arc<InteractionIndex>,
+  registered_indexes: RegisteredIndexes,
+  cors: CorsPolicy,
+  provider_state: Option<ProviderStateFilter>,
+  provider_state_selector: ProviderStateSelector,
+  empty_provider_states: bool,
+  match_settings: MatchSettings,
+  message_target: Option<MessageTarget>,
+  shutdown: Shutdown,
+  shutdown_endpoint: bool,
+  drain_timeout: Duration,
+  health: HealthCheck,
+  metrics_path: String,
+  access_log: Option<AccessLog>,
+  client_address: Option<SocketAddr>,
+  sessions: Sessions,
+  admin_api: bool,
+  registered: RegisteredInteractions,
+  interaction_header: String,
+  response_compression: Option<ResponseCompression>,
+  max_decoded_body_size: usize
 }
 
+type TracedHandler = Trace<ServerHandler, SharedClassifier<ServerErrorsAsFailures>, RequestSpan>;
+
 #[derive(Clone)]
 struct ServerHandlerFactory {
   inner: ServerHandler
@@ -189,8 +448,24 @@ impl ServerHandlerFactory {
   }
 }
 
+impl ServerHandlerFactory {
+  fn make_service(&self, remote_addr: Option<SocketAddr>) -> TracedHandler {
+    match remote_addr {
+      Some(addr) => debug!("Accepting a new connection from {}", addr),
+      None => debug!("Accepting a new connection from a Unix domain socket")
+    }
+    ServiceBuilder::new()
+      .layer(TraceLayer::new_for_http()
+        .make_span_with(RequestSpan))
+      .service(ServerHandler {
+        client_address: remote_addr,
+        .. self.inner.clone()
+      })
+  }
+}
+
 impl Service<&AddrStream> for ServerHandlerFactory {
-  type Response = Trace<ServerHandler, SharedClassifier<ServerErrorsAsFailures>>;
+  type Response = TracedHandler;
   type Error = anyhow::Error;
   type Future = Ready<Result<Self::Response, Self::Error>>;
 
@@ -199,12 +474,36 @@ impl Service<&AddrStream> for ServerHandlerFactory {
   }
 
   fn call(&mut self, req: &AddrStream) -> Self::Future {
-    debug!("Accepting a new connection from {}", req.remote_addr());
-    let service = ServiceBuilder::new()
-      .layer(TraceLayer::new_for_http()
-        .make_span_with(DefaultMakeSpan::new().include_headers(true)))
-      .service(self.inner.clone());
-    ready(Ok(service))
+    ready(Ok(self.make_service(Some(req.remote_addr()))))
+  }
+}
+
+impl Service<&TlsStream<AddrStream>> for ServerHandlerFactory {
+  type Response = TracedHandler;
+  type Error = anyhow::Error;
+  type Future = Ready<Result<Self::Response, Self::Error>>;
+
+  fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, req: &TlsStream<AddrStream>) -> Self::Future {
+    ready(Ok(self.make_service(Some(req.get_ref().0.remote_addr()))))
+  }
+}
+
+#[cfg(unix)]
+impl Service<&tokio::net::UnixStream> for ServerHandlerFactory {
+  type Response = TracedHandler;
+  type Error = anyhow::Error;
+  type Future = Ready<Result<Self::Response, Self::Error>>;
+
+  fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, _req: &tokio::net::UnixStream) -> Self::Future {
+    ready(Ok(self.make_service(None)))
   }
 }
 
@@ -215,39 +514,256 @@ impl ServerHandler {
     cors_referer: bool,
     provider_state: Option<Regex>,
     provider_state_header_name: Option<String>,
-    empty_provider_states: bool
+    empty_provider_states: bool,
+    message_target: Option<MessageTarget>
   ) -> ServerHandler {
     // Build the interaction index during initialization
     let interaction_index = InteractionIndex::build_from_sources(&sources);
     
     ServerHandler {
       sources,
-      interaction_index,
-      auto_cors,
-      cors_referer,
-      provider_state,
-      provider_state_header_name,
-      empty_provider_states
-    }
-  }
-
-  pub fn start_server(self, port: u16) -> Result<(), ExitCode> {
-    let addr = ([0, 0, 0, 0], port).into();
-    match Server::try_bind(&addr) {
-      Ok(builder) => {
-        let server = builder.serve(ServerHandlerFactory::new(self));
-        info!("Server started on port {}", server.local_addr().port());
-        block_on(server).map_err(|err| {
-          error!("error occurred scheduling server future on Tokio runtime: {}", err);
-          ExitCode::from(2)
+      interaction_index: Arc::new(interaction_index),
+      registered_indexes: RegisteredIndexes::default(),
+      cors: CorsPolicy::new(auto_cors, cors_referer),
+      provider_state: provider_state.map(ProviderStateFilter::from),
+      provider_state_selector: ProviderStateSelector::new(provider_state_header_name, None, None),
+      empty_provider_states,
+      match_settings: MatchSettings::default(),
+      message_target,
+      shutdown: Shutdown::new(),
+      shutdown_endpoint: false,
+      drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+      health: HealthCheck::default(),
+      metrics_path: metrics::DEFAULT_METRICS_PATH.to_string(),
+      access_log: None,
+      client_address: None,
+      sessions: Sessions::default(),
+      admin_api: false,
+      registered: RegisteredInteractions::default(),
+      interaction_header: DEFAULT_INTERACTION_HEADER.to_string(),
+      response_compression: None,
+      max_decoded_body_size: compression::DEFAULT_MAX_DECODED_BODY_SIZE
+    }
+  }
+
+  /// Sets the CORS policy applied to responses and un-matched OPTIONS requests
+  pub fn with_cors_policy(self, cors: CorsPolicy) -> ServerHandler {
+    ServerHandler {
+      cors,
+      .. self
+    }
+  }
+
+  /// Sets the normalisation applied to request paths and the paths of the interactions
+  pub fn with_path_normalisation(self, path_normalisation: PathNormalisation) -> ServerHandler {
+    ServerHandler {
+      interaction_index: Arc::new(InteractionIndex::build_with_path_normalisation(&self.sources, path_normalisation)),
+      registered_indexes: RegisteredIndexes::default(),
+      .. self
+    }
+  }
+
+  /// Sets the compression of response bodies, using the encoding the request prefers
+  pub fn with_response_compression(self, response_compression: Option<ResponseCompression>) -> ServerHandler {
+    ServerHandler {
+      response_compression,
+      .. self
+    }
+  }
+
+  /// Sets the maximum size (in bytes) of a compressed request body once it is decoded
+  pub fn with_max_decoded_body_size(self, max_decoded_body_size: usize) -> ServerHandler {
+    ServerHandler {
+      max_decoded_body_size,
+      .. self
+    }
+  }
+
+  /// Sets the request header used to select the interaction to respond with (by key, description or index)
+  pub fn with_interaction_header(self, interaction_header: &str) -> ServerHandler {
+    ServerHandler {
+      interaction_header: interaction_header.to_string(),
+      .. self
+    }
+  }
+
+  /// Sets the registry of interactions registered at runtime through the admin API
+  pub fn with_registered_interactions(self, registered: RegisteredInteractions) -> ServerHandler {
+    ServerHandler {
+      registered,
+      .. self
+    }
+  }
+
+  /// Sets which mismatches with a request reject a candidate interaction
+  pub fn with_match_settings(self, match_settings: MatchSettings) -> ServerHandler {
+    ServerHandler {
+      match_settings,
+      .. self
+    }
+  }
+
+  /// Sets where the provider state selection is read from in a request
+  pub fn with_provider_state_selector(self, provider_state_selector: ProviderStateSelector) -> ServerHandler {
+    ServerHandler {
+      provider_state_selector,
+      .. self
+    }
+  }
+
+  /// Sets the registry of sessions, and how the session is selected for a request
+  pub fn with_sessions(self, sessions: Sessions) -> ServerHandler {
+    ServerHandler {
+      sessions,
+      .. self
+    }
+  }
+
+  /// Enables the admin endpoints that manage the sessions and the interactions registered at runtime
+  pub fn with_admin_api(self, admin_api: bool) -> ServerHandler {
+    ServerHandler {
+      admin_api,
+      .. self
+    }
+  }
+
+  /// Sets the access log that an entry is written to for each request
+  pub fn with_access_log(self, access_log: Option<AccessLog>) -> ServerHandler {
+    ServerHandler {
+      access_log,
+      .. self
+    }
+  }
+
+  /// Sets the path of the metrics endpoint
+  pub fn with_metrics_path(self, metrics_path: &str) -> ServerHandler {
+    ServerHandler {
+      metrics_path: metrics_path.to_string(),
+      .. self
+    }
+  }
+
+  /// Sets the state used to respond to the health endpoints
+  pub fn with_health_check(self, health: HealthCheck) -> ServerHandler {
+    ServerHandler {
+      health,
+      .. self
+    }
+  }
+
+  /// Sets the signal used to gracefully shut down the server, and the time to wait for in-flight requests
+  /// to complete. If `shutdown_endpoint` is true, the server can also be shut down with a request to the
+  /// admin shutdown endpoint.
+  pub fn with_graceful_shutdown(self, shutdown: Shutdown, drain_timeout: Duration, shutdown_endpoint: bool) -> ServerHandler {
+    ServerHandler {
+      shutdown,
+      drain_timeout,
+      shutdown_endpoint,
+      .. self
+    }
+  }
+
+  pub fn start_server(
+    self,
+    addresses: &ListenAddresses,
+    protocol: HttpProtocol,
+    tls_config: Option<Arc<ServerConfig>>,
+    report: &StartupReport
+  ) -> Result<(), ExitCode> {
+    let shutdown = self.shutdown.clone();
+    let drain_timeout = self.drain_timeout;
+    let health = self.health.clone();
+    let (bound, server) = self.bind(addresses, protocol, tls_config)?;
+    for addr in &bound {
+      info!("Server listening on {}", addr);
+    }
+    if let Some(BoundAddress::Tcp(addr)) = bound.iter().find(|addr| matches!(addr, BoundAddress::Tcp(_))) {
+      info!("Server started on port {}", addr.port());
+    }
+    if let Err(err) = report.write(&bound) {
+      error!("Failed to write the startup report: {:#}", err);
+      return Err(ExitCode::FAILURE);
+    }
+    health.set_ready(true);
+    let result = block_on(shutdown::run_until_drained(server, &shutdown, drain_timeout));
+    if let Some(path) = &addresses.unix_socket {
+      if let Err(err) = std::fs::remove_file(path) {
+        warn!("Failed to remove the Unix socket {}: {}", path.display(), err);
+      }
+    }
+    result
+  }
+
+  // Binds the server to all the addresses, and returns the bound addresses and the future that runs the servers
+  fn bind(
+    self,
+    addresses: &ListenAddresses,
+    protocol: HttpProtocol,
+    tls_config: Option<Arc<ServerConfig>>
+  ) -> Result<(Vec<BoundAddress>, ServerFuture), ExitCode> {
+    let shutdown = self.shutdown.clone();
+    let factory = ServerHandlerFactory::new(self);
+    let mut bound = vec![];
+    let mut servers = vec![];
+
+    let mut port = addresses.port;
+    for host in &addresses.hosts {
+      let addr = SocketAddr::new(*host, port);
+      let incoming = tcp_listener(addr).map_err(|err| {
+        error!("could not start server on {}: {}", addr, err);
+        ExitCode::FAILURE
+      })?;
+      let local_addr = incoming.local_addr();
+      // Any random port assigned by the OS is used for the remaining addresses
+      port = local_addr.port();
+      let server = match &tls_config {
+        Some(config) => with_protocol(Server::builder(accept::from_stream(tls::tls_connections(incoming, config.clone()))), protocol)
+          .serve(factory.clone())
+          .with_graceful_shutdown(shutdown.wait())
+          .boxed(),
+        None => with_protocol(Server::builder(incoming), protocol)
+          .serve(factory.clone())
+          .with_graceful_shutdown(shutdown.wait())
+          .boxed()
+      };
+      servers.push(server);
+      bound.push(BoundAddress::Tcp(local_addr));
+    }
+
+    if let Some(path) = &addresses.unix_socket {
+      #[cfg(unix)]
+      {
+        let listener = unix_listener(path).map_err(|err| {
+          error!("could not start server on Unix socket {}: {}", path.display(), err);
+          ExitCode::FAILURE
         })?;
-        Ok(())
-      },
-      Err(err) => {
-        error!("could not start server: {}", err);
-        Err(ExitCode::FAILURE)
+        let incoming = futures::stream::poll_fn(move |cx| listener.poll_accept(cx)
+          .map(|result| Some(result.map(|(stream, _)| stream))));
+        servers.push(with_protocol(Server::builder(accept::from_stream(incoming)), protocol)
+          .serve(factory.clone())
+          .with_graceful_shutdown(shutdown.wait())
+          .boxed());
+        bound.push(BoundAddress::Unix(path.clone()));
       }
+      #[cfg(not(unix))]
+      {
+        error!("could not start server on Unix socket {}: Unix domain sockets are not supported on this platform",
+          path.display());
+        return Err(ExitCode::FAILURE);
+      }
+    }
+
+    if bound.is_empty() {
+      error!("could not start server: no addresses to listen on");
+      return Err(ExitCode::FAILURE);
     }
+
+    let server = futures::future::try_join_all(servers)
+      .map(|result| result.map(|_| ()).map_err(|err| {
+        error!("error occurred scheduling server future on Tokio runtime: {}", err);
+        ExitCode::from(2)
+      }));
+    Ok((bound, server.boxed()))
   }
 }
 
@@ -261,29 +777,114 @@ impl Service<HyperRequest<Body>> for ServerHandler {
   }
 
   fn call(&mut self, req: HyperRequest<Body>) -> Self::Future {
-    let auto_cors = self.auto_cors;
-    let cors_referer = self.cors_referer;
+    let cors = self.cors.clone();
     let sources = self.sources.clone();
-    let provider_state = self.provider_state.clone();
-    let provider_state_header_name = self.provider_state_header_name.clone();
+    let sessions = self.sessions.clone();
+    let session = sessions.session_for_request(req.headers());
+    // The provider state of the session takes precedence over the one set on the command line
+    let provider_state = session.as_ref().ok()
+      .and_then(|session| session.as_ref())
+      .and_then(|session| session.provider_state())
+      .map(ProviderStateFilter::from)
+      .or_else(|| self.provider_state.clone());
+    let provider_state_selector = self.provider_state_selector.clone();
     let empty_provider_states = self.empty_provider_states;
-    let interaction_index = self.interaction_index.clone();
+    let match_settings = self.match_settings.clone();
+    let registered = self.registered.clone();
+    // Interactions registered at runtime are added to the index for the request
+    let interaction_index = self.registered_indexes.index_for_session(&self.interaction_index, &registered,
+      session.as_ref().ok().and_then(|session| session.as_ref()));
+    let message_target = self.message_target.clone();
+    let interaction_header = self.interaction_header.clone();
+    let response_compression = self.response_compression;
+    let max_decoded_body_size = self.max_decoded_body_size;
+    let shutdown = self.shutdown_endpoint.then(|| self.shutdown.clone());
+    let admin_api = self.admin_api;
+    let health = self.health.clone();
+    let metrics_path = self.metrics_path.clone();
+    let shutting_down = self.shutdown.is_triggered();
+    let access_log = self.access_log.clone()
+      .map(|access_log| (access_log, AccessLogEntry::new(&req, self.client_address)));
+    let (method, path) = (req.method().to_string(), req.uri().path().to_string());
+    let request_session = session.clone();
+
+    let response = async move {
+      let (mut parts, body) = req.into_parts();
+
+      if let Some(response) = health.handle_request(&parts.method, parts.uri.path(), &sources, shutting_down) {
+        return response;
+      }
+      if parts.method == Method::GET && parts.uri.path() == metrics_path {
+        return metrics::metrics_response();
+      }
+      if parts.method == Method::GET && parts.uri.path() == coverage::COVERAGE_PATH {
+        return coverage::handle_coverage_request(parts.uri.query(),
+          parts.headers.get(http::header::ACCEPT).and_then(|h| h.to_str().ok()), &sources);
+      }
 
-    Box::pin(async move {
-      let (parts, body) = req.into_parts();
-      let provider_state = match provider_state_header_name {
-        Some(name) => {
-          let parts_value = &parts;
-          let provider_state_header = parts_value.headers.get(name);
-          match provider_state_header {
-            Some(header) => Some(Regex::new(header.to_str().unwrap()).unwrap()),
-            None => provider_state
+      let bytes = match hyper::body::to_bytes(body).await {
+        // Compressed bodies are decoded so they can be matched against the interactions
+        Ok(contents) => match compression::decode_request_body(&mut parts.headers, contents, max_decoded_body_size) {
+          Ok(decoded) => Ok(decoded),
+          Err(err) => {
+            warn!("{}", err);
+            let request = HttpRequest {
+              method: parts.method.to_string(),
+              path: parts.uri.path().to_string(),
+              headers: pact_support::extract_headers(&parts.headers),
+              .. HttpRequest::default()
+            };
+            let mut response = compression::decode_error_response(&err);
+            cors.add_headers(&request, &mut response);
+            return pact_support::pact_response_to_hyper_response(&response);
           }
         },
-        None => provider_state
+        Err(err) => Err(err)
+      };
+
+      if let Some(shutdown) = &shutdown {
+        if parts.method == Method::POST && parts.uri.path() == shutdown::SHUTDOWN_PATH {
+          info!("Shutdown requested via {}", shutdown::SHUTDOWN_PATH);
+          shutdown.trigger();
+          return HyperResponse::builder()
+            .status(StatusCode::ACCEPTED)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json!({ "status": "shutting down" }).to_string()));
+        }
+      }
+
+      if admin_api {
+        if let Some(response) = sessions.handle_request(&parts.method, parts.uri.path(), bytes.as_deref().unwrap_or_default()) {
+          return response;
+        }
+        if let Some(response) = registered.handle_request(&parts.method, parts.uri.path(), parts.uri.query(),
+          &parts.headers, bytes.as_deref().unwrap_or_default(), &sessions) {
+          return response;
+        }
+        if let Some(target) = &message_target {
+          if parts.method == Method::POST && parts.uri.path() == messages::PUBLISH_PATH {
+            return messages::handle_publish_request(&bytes.unwrap_or_default(), &sources, target).await;
+          }
+        }
+      }
+      match &request_session {
+        Ok(Some(session)) => debug!("Handling the request in session '{}'", session.id()),
+        Ok(None) => {},
+        Err(id) => {
+          warn!("Request selected session '{}', which does not exist or has expired", id);
+          return sessions::unknown_session_response(id);
+        }
+      }
+
+      // The provider state selected by the request takes precedence over the session and command line
+      let provider_state = match provider_state_selector.select(&parts.headers, parts.uri.query()) {
+        Ok(selected) => selected.or(provider_state),
+        Err(err) => {
+          warn!("{}, sending {}", err, StatusCode::BAD_REQUEST);
+          return provider_states::invalid_selection_response(&err);
+        }
       };
 
-      let bytes = hyper::body::to_bytes(body).await;
       let body = match bytes {
         Ok(contents) => if contents.is_empty() {
           OptionalBody::Empty
@@ -295,71 +896,113 @@ impl Service<HyperRequest<Body>> for ServerHandler {
           OptionalBody::Empty
         }
       };
-      let request = pact_support::hyper_request_to_pact_request(parts, body);
+      let selection = parts.headers.get(interaction_header.as_str())
+        .map(|value| value.to_str().map(|value| value.to_string()).unwrap_or_default());
+      let accept_encoding = parts.headers.get(http::header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+      let mut request = pact_support::hyper_request_to_pact_request(parts, body);
+      provider_state_selector.remove_query_param(&mut request);
+
+      if let Some(selection) = selection {
+        let (mut response, result) = match find_selected_interaction(&request, &interaction_index, &selection, &match_settings).await {
+          Ok(response) => (response, RequestResult::Match),
+          Err(error) => {
+            warn!("{}, sending {}", error["error"].as_str().unwrap_or_default(), StatusCode::BAD_REQUEST);
+            (selection_error_response(error), RequestResult::Miss)
+          }
+        };
+        cors.add_headers(&request, &mut response);
+        metrics::record_request(response.status, result);
+        return send_response(&response, response_compression.as_ref(), accept_encoding.as_deref()).await;
+      }
       
       // Use our optimized request matching with the interaction index
-      let response = optimized_find_matching_request(&request, auto_cors, cors_referer,
-        &interaction_index, provider_state.clone(), empty_provider_states).await;
+      let response = optimized_find_matching_request(&request, &interaction_index, provider_state.clone(),
+        empty_provider_states, &match_settings).await;
+      // HEAD requests that no HEAD interaction matches are answered from the GET interaction that matches
+      let response = match response {
+        Err(err) if request.method.eq_ignore_ascii_case("HEAD") && !err.is::<NotAcceptable>() => {
+          let get_request = HttpRequest { method: "GET".to_string(), .. request.clone() };
+          optimized_find_matching_request(&get_request, &interaction_index, provider_state.clone(),
+            empty_provider_states, &match_settings).await
+            .map(head_response)
+        },
+        response => response
+      };
       
-      match response {
-        Ok(resp) => pact_support::pact_response_to_hyper_response(&resp),
+      let (mut response, result) = match response {
+        Ok(resp) => (resp, RequestResult::Match),
+        Err(err) if err.is::<NotAcceptable>() => (not_acceptable_response(), RequestResult::Miss),
         Err(_) => {
           // Fall back to the original implementation if the optimized version fails
-          let response = handle_request(request, auto_cors, cors_referer,
-            sources, provider_state, empty_provider_states).await;
-          pact_support::pact_response_to_hyper_response(&response)
+          handle_request(&request, &cors, &interaction_index, sources, provider_state, empty_provider_states,
+            &match_settings).await
         }
+      };
+      cors.add_headers(&request, &mut response);
+      metrics::record_request(response.status, result);
+      send_response(&response, response_compression.as_ref(), accept_encoding.as_deref()).await
+    };
+    Box::pin(async move {
+      let started = Instant::now();
+      let (result, interaction) = logging::capture_matched_interaction(response).await;
+      if let Some((access_log, entry)) = access_log {
+        access_log.log_response(entry, &result, started.elapsed(), interaction.clone());
       }
+      if let Ok(Some(session)) = session {
+        session.record(JournalEntry {
+          method,
+          path,
+          status: result.as_ref().map(|response| response.status().as_u16()).unwrap_or(500),
+          interaction
+        });
+      }
+      result
     })
   }
 }
 
-fn method_supports_payload(request: &HttpRequest) -> bool {
-  matches!(request.method.to_uppercase().as_str(), "POST" | "PUT" | "PATCH")
+// Converts the response, compressing the body if response compression is enabled
+async fn send_response(
+  response: &HttpResponse,
+  compression: Option<&ResponseCompression>,
+  accept_encoding: Option<&str>
+) -> Result<HyperResponse<Body>, Error> {
+  let response = pact_support::pact_response_to_hyper_response(response)?;
+  match compression {
+    Some(compression) => Ok(compression.compress(accept_encoding, response).await),
+    None => Ok(response)
+  }
 }
 
 // New optimized function that uses the interaction index
 async fn optimized_find_matching_request(
   request: &HttpRequest,
-  auto_cors: bool,
-  cors_referer: bool,
   index: &InteractionIndex,
-  provider_state: Option<Regex>,
-  empty_provider_states: bool
+  provider_state: Option<ProviderStateFilter>,
+  empty_provider_states: bool,
+  settings: &MatchSettings
 ) -> anyhow::Result<HttpResponse> {
-  match &provider_state {
-    Some(state) => info!("Filtering interactions by provider state regex '{}'", state),
-    None => ()
-  }
-
-  // Try to match OPTIONS requests for CORS early
-  if auto_cors && request.method.to_uppercase() == "OPTIONS" {
-    let origin = if cors_referer {
-      match request.headers {
-        Some(ref h) => h.iter()
-          .find(|kv| kv.0.to_lowercase() == "referer")
-          .map(|kv| kv.1.clone().join(", ")).unwrap_or_else(|| "*".to_string()),
-        None => "*".to_string()
-      }
-    } else { "*".to_string() };
-    return Ok(HttpResponse {
-      headers: Some(hashmap!{
-        "Access-Control-Allow-Headers".to_string() => vec!["*".to_string()],
-        "Access-Control-Allow-Methods".to_string() => vec!["GET, HEAD, POST, PUT, DELETE, CONNECT, OPTIONS, TRACE, PATCH".to_string()],
-        "Access-Control-Allow-Origin".to_string() => vec![origin]
-      }),
-      .. HttpResponse::default()
-    });
+  if let Some(state) = &provider_state {
+    info!("Filtering interactions by provider state {}", state);
   }
 
   // Get candidate interactions by method and path (fast path)
   let mut candidates = index.get_candidates_by_method_path(&request.method, &request.path);
+  let exact_path = !candidates.is_empty();
   
   // If no exact matches, check all interactions with path matching
   if candidates.is_empty() {
     candidates = (0..index.all_interactions.len())
       .filter(|&idx| index.quick_check_path_match(idx, request))
       .collect();
+  } else {
+    // Registered interactions take part even when a pact file interaction matches the path exactly
+    let registered = (index.registered_start..index.all_interactions.len())
+      .filter(|idx| !candidates.contains(idx) && index.quick_check_path_match(*idx, request))
+      .collect::<Vec<_>>();
+    candidates.extend(registered);
   }
   
   // Filter by provider state if specified
@@ -367,84 +1010,260 @@ async fn optimized_find_matching_request(
     candidates = index.filter_by_provider_state(&candidates, &provider_state, empty_provider_states);
   }
   
-  if candidates.is_empty() {
+  // Process candidates in parallel to find the best match
+  let mut match_results = if candidates.is_empty() {
+    vec![]
+  } else {
+    match_candidates(request, index, candidates.clone(), provider_state.as_ref(), settings).await.0
+  };
+
+  // If all the interactions with the exact path are rejected, the interactions with path matching rules are tried
+  if match_results.is_empty() && exact_path {
+    let mut path_matchers = index.get_path_matcher_candidates(request, &candidates);
+    if provider_state.is_some() {
+      path_matchers = index.filter_by_provider_state(&path_matchers, &provider_state, empty_provider_states);
+    }
+    if !path_matchers.is_empty() {
+      match_results = match_candidates(request, index, path_matchers, provider_state.as_ref(), settings).await.0;
+    }
+  }
+
+  if match_results.is_empty() {
     return Err(anyhow!("No matching request found for path {}", request.path));
   }
+
+  // With strict negotiation, interactions the request does not accept are never used
+  let negotiation = settings.negotiation();
+  if negotiation.is_strict() && !match_results.is_empty() && negotiation.applies_to(request) {
+    match_results.retain(|matched| matched.negotiation.acceptable);
+    if match_results.is_empty() {
+      warn!("None of the interactions for method {} and path '{}' are acceptable to the request, sending {}",
+        request.method, request.path, StatusCode::NOT_ACCEPTABLE);
+      return Err(NotAcceptable.into());
+    }
+  }
   
-  // Process candidates in parallel to find the best match
+  if match_results.len() > 1 {
+    warn!("Found more than one pact request for method {} and path '{}', using the one with the highest priority and match score",
+          request.method, request.path);
+  }
+  
+  // Generate response from the best match
+  match best_match(match_results) {
+    Some(matched) => Ok(matched_response(&matched, provider_state.as_ref()).await),
+    None => Err(anyhow!("No matching request found for path {}", request.path))
+  }
+}
+
+// Interaction that matched a request, with its position in the index (which is the load order)
+struct CandidateMatch {
+  idx: usize,
+  interaction: SynchronousHttp,
+  pact: V4Pact,
+  priority: i32,
+  score: MatchScore,
+  negotiation: NegotiationResult
+}
+
+// Error returned when strict negotiation rejects all the interactions that match a request
+#[derive(Debug)]
+struct NotAcceptable;
+
+impl Display for NotAcceptable {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "None of the matching interactions are acceptable to the request")
+  }
+}
+
+impl std::error::Error for NotAcceptable {}
+
+// Converts the response to a GET request into the response to a HEAD request, with the headers of the
+// response (including the length and type of the body) but no body
+fn head_response(mut response: HttpResponse) -> HttpResponse {
+  if let OptionalBody::Present(body, content_type, _) = &response.body {
+    let length = body.len().to_string();
+    let content_type = content_type.clone().or_else(|| response.content_type());
+    if !response.has_header("Content-Type") {
+      if let Some(content_type) = content_type {
+        response.add_header("Content-Type", vec![content_type.to_string().as_str()]);
+      }
+    }
+    if !response.has_header("Content-Length") {
+      response.add_header("Content-Length", vec![length.as_str()]);
+    }
+  }
+  response.body = OptionalBody::Missing;
+  response
+}
+
+// Returns a 405 Method Not Allowed response (with the allowed methods) if the path of the request only
+// matches interactions with other methods
+fn method_not_allowed_response(index: &InteractionIndex, request: &HttpRequest) -> Option<HttpResponse> {
+  let allowed = index.allowed_methods(&request.path);
+  if allowed.is_empty() || allowed.contains(&request.method.to_uppercase()) {
+    return None;
+  }
+  Some(HttpResponse {
+    status: StatusCode::METHOD_NOT_ALLOWED.as_u16(),
+    headers: Some(hashmap!{ "Allow".to_string() => vec![allowed.join(", ")] }),
+    .. HttpResponse::default()
+  })
+}
+
+fn not_acceptable_response() -> HttpResponse {
+  HttpResponse {
+    status: StatusCode::NOT_ACCEPTABLE.as_u16(),
+    .. HttpResponse::default()
+  }
+}
+
+// Returns the match with the highest priority, then the ones the request accepts with the highest score,
+// then the highest quality. The quality only decides between equally scored representations, as an
+// interaction without a content type (such as one without a body) has no quality for a wildcard Accept
+// header. Ties are resolved by the order the interactions were loaded in, so the same interaction is always
+// selected.
+fn best_match(mut match_results: Vec<CandidateMatch>) -> Option<CandidateMatch> {
+  match_results.sort_by(|a, b| Ord::cmp(&b.priority, &a.priority)
+    .then_with(|| Ord::cmp(&b.negotiation.acceptable, &a.negotiation.acceptable))
+    .then_with(|| Ord::cmp(&b.score.total(), &a.score.total()))
+    .then_with(|| Ord::cmp(&b.negotiation.quality, &a.negotiation.quality))
+    .then_with(|| Ord::cmp(&a.idx, &b.idx)));
+  for matched in &match_results {
+    debug!("Interaction '{}' (priority {}) is {} and has a match score of {}", matched.interaction.description,
+      matched.priority, matched.negotiation, matched.score);
+  }
+  match_results.into_iter().next()
+}
+
+// Records the matched interaction (with the provider state filter it was served under) and generates the
+// response for it
+async fn matched_response(matched: &CandidateMatch, provider_state: Option<&ProviderStateFilter>) -> HttpResponse {
+  let CandidateMatch { interaction, pact, score, .. } = matched;
+  metrics::record_interaction_hit(pact, interaction);
+  coverage::record_hit(pact, interaction, provider_state);
+  telemetry::record_matched_interaction(pact, interaction);
+  telemetry::record_score(score);
+  logging::record_matched_interaction(pact, interaction, Some(score.total()));
+  plugins::generate_response(interaction, pact).await
+}
+
+// Matches the request against the interactions selected with the interaction header (by key, description or
+// index), ignoring any provider state filter. Returns an error describing why if no interaction is selected
+// or the selected interaction does not match the request.
+async fn find_selected_interaction(
+  request: &HttpRequest,
+  index: &InteractionIndex,
+  selection: &str,
+  settings: &MatchSettings
+) -> Result<HttpResponse, Value> {
+  let candidates = index.select_interactions(selection);
+  if candidates.is_empty() {
+    return Err(json!({
+      "error": format!("No interaction has the key, description or index '{}'", selection)
+    }));
+  }
+
+  let (match_results, rejected) = match_candidates(request, index, candidates, None, settings).await;
+  match best_match(match_results) {
+    Some(matched) => Ok(matched_response(&matched, None).await),
+    None => {
+      let redaction = redaction::redaction();
+      Err(json!({
+        "error": format!("The selected interaction '{}' does not match the request", selection),
+        "mismatches": rejected.iter()
+          .flat_map(|(description, mismatches)| mismatches.iter().map(move |mismatch| json!({
+            "interaction": description,
+            "mismatch": redaction.mismatch_description(mismatch)
+          })))
+          .collect::<Vec<_>>()
+      }))
+    }
+  }
+}
+
+fn selection_error_response(error: Value) -> HttpResponse {
+  HttpResponse {
+    status: StatusCode::BAD_REQUEST.as_u16(),
+    headers: Some(hashmap!{ "Content-Type".to_string() => vec!["application/json".to_string()] }),
+    body: OptionalBody::Present(error.to_string().into(), Some(JSON.clone()), None),
+    .. HttpResponse::default()
+  }
+}
+
+// Matches the request against the candidate interactions in parallel. Returns the interactions that match
+// (with their priority and match score), and the descriptions and mismatches of the ones that do not.
+async fn match_candidates(
+  request: &HttpRequest,
+  index: &InteractionIndex,
+  candidates: Vec<usize>,
+  provider_state: Option<&ProviderStateFilter>,
+  settings: &MatchSettings
+) -> (Vec<CandidateMatch>, Vec<(String, Vec<Mismatch>)>) {
+  let match_started = Instant::now();
+  let candidate_count = candidates.len();
+  telemetry::record_candidates(candidate_count);
   let mut futures = FuturesUnordered::new();
   
   for idx in candidates {
     let (interaction, pact) = index.get_interaction_and_pact(idx);
-    let request_clone = request.clone();
+    let priority = index.priorities[idx];
     let pact_clone = pact.clone();
     let interaction_clone = interaction.clone();
+    // The normalised request path is matched against the normalised path of the interaction
+    let expected = HttpRequest { path: index.normalised_paths[idx].clone(), .. interaction.request.clone() };
+    let request_clone = HttpRequest { path: index.request_path(idx, &request.path).into_owned(), .. request.clone() };
     
     // Use spawn_local to avoid blocking
     futures.push(async move {
       let result = pact_matching::match_request(
-        interaction.request.clone(), 
-        request_clone, 
+        expected.clone(), 
+        request_clone.clone(), 
         &pact_clone.boxed(), 
         &interaction_clone.boxed()
       ).await;
       
       let mismatches = result.mismatches();
-      let all_matched = mismatches.iter().all(|mismatch| {
-        match mismatch {
-          Mismatch::MethodMismatch { .. } => false,
-          Mismatch::PathMismatch { .. } => false,
-          Mismatch::QueryMismatch { .. } => false,
-          Mismatch::BodyMismatch { .. } => !(method_supports_payload(request) && request.body.is_present()),
-          _ => true
-        }
-      });
+      let rejecting = settings.rejecting_mismatches(&expected, &request_clone, &mismatches);
       
-      if all_matched {
-        Some((interaction_clone, mismatches))
+      if rejecting.is_empty() {
+        let score = MatchScore::calculate(&interaction_clone, &mismatches, provider_state);
+        let negotiation = settings.negotiation().negotiate(request, &interaction_clone);
+        Ok(CandidateMatch { idx, interaction: interaction_clone, pact, priority, score, negotiation })
       } else {
-        None
+        // Include any rejecting mismatches that the matching did not report (such as an unexpected body)
+        let mut all_mismatches = mismatches.clone();
+        all_mismatches.extend(rejecting.into_iter().filter(|mismatch| !mismatches.contains(mismatch)));
+        Err((interaction_clone.description, all_mismatches))
       }
     }.boxed());
   }
   
   // Collect results
-  let mut match_results = Vec::new();
-  while let Some(result) = futures.next().await {
-    if let Some(match_result) = result {
-      match_results.push(match_result);
-    }
-  }
-  
-  // Sort by number of mismatches to find the best match
-  match_results.sort_by(|a, b| Ord::cmp(&a.1.len(), &b.1.len()));
-  
-  if match_results.len() > 1 {
-    warn!("Found more than one pact request for method {} and path '{}', using the first one with the least number of mismatches",
-          request.method, request.path);
-  }
-  
-  // Generate response from the best match
-  match match_results.first() {
-    Some((interaction, _)) => {
-      Ok(pact_matching::generate_response(&interaction.response, &GeneratorTestMode::Provider, &hashmap!{}).await)
-    },
-    None => Err(anyhow!("No matching request found for path {}", request.path))
+  let mut match_results = Vec::new();
+  let mut rejected = Vec::new();
+  while let Some(result) = futures.next().await {
+    match result {
+      Ok(match_result) => match_results.push(match_result),
+      Err(rejected_result) => rejected.push(rejected_result)
+    }
   }
+  telemetry::record_mismatches(&rejected);
+  
+  metrics::record_match(candidate_count, match_started.elapsed());
+  
+  (match_results, rejected)
 }
 
 // Keep the original function for fallback and tests
 async fn find_matching_request(
   request: &HttpRequest,
-  auto_cors: bool,
-  cors_referer: bool,
   sources: Vec<(V4Pact, PactSource)>,
-  provider_state: Option<Regex>,
-  empty_provider_states: bool
+  provider_state: Option<ProviderStateFilter>,
+  empty_provider_states: bool,
+  settings: &MatchSettings
 ) -> anyhow::Result<HttpResponse> {
-  match &provider_state {
-    Some(state) => info!("Filtering interactions by provider state regex '{}'", state),
-    None => ()
+  if let Some(state) = &provider_state {
+    info!("Filtering interactions by provider state {}", state);
   }
 
   // Get a subset of all interactions across all pacts that match the method and path
@@ -464,11 +1283,8 @@ async fn find_matching_request(
         pact_matching::match_path(&http.request.path, &request.path, &path_context).is_ok()
     })
     .filter(|(i, _)| {
-      let ps = &i.provider_states;
       match provider_state {
-        Some(ref regex) => empty_provider_states && ps.is_empty() ||
-          ps.iter().any(|state|
-            empty_provider_states && state.name.is_empty() || regex.is_match(state.name.as_str())),
+        Some(ref filter) => filter.matches(&i.provider_states, empty_provider_states),
         None => true
       }
     });
@@ -478,17 +1294,8 @@ async fn find_matching_request(
     .filter_map(|(i, pact)| async move {
       let result = pact_matching::match_request(i.request.clone(), request.clone(), &pact.boxed(), &i.boxed()).await;
       let mismatches = result.mismatches();
-      let all_matched = mismatches.iter().all(|mismatch|{
-        match mismatch {
-          Mismatch::MethodMismatch { .. } => false,
-          Mismatch::PathMismatch { .. } => false,
-          Mismatch::QueryMismatch { .. } => false,
-          Mismatch::BodyMismatch { .. } => !(method_supports_payload(request) && request.body.is_present()),
-          _ => true
-        }
-      });
-      if all_matched {
-        Some((i.clone(), mismatches.clone()))
+      if settings.rejecting_mismatches(&i.request, request, &mismatches).is_empty() {
+        Some((i.clone(), pact.clone(), mismatches.clone()))
       } else {
         None
       }
@@ -498,9 +1305,9 @@ async fn find_matching_request(
 
   // Find the result with the least number of mismatches
   let match_results = results.iter()
-    .sorted_by(|a, b| Ord::cmp(&a.1.len(), &b.1.len()))
+    .sorted_by(|a, b| Ord::cmp(&a.2.len(), &b.2.len()))
     .cloned()
-    .collect::<Vec<(SynchronousHttp, Vec<Mismatch>)>>();
+    .collect::<Vec<(SynchronousHttp, V4Pact, Vec<Mismatch>)>>();
 
   if match_results.len() > 1 {
     warn!("Found more than one pact request for method {} and path '{}', using the first one with the least number of mismatches",
@@ -508,57 +1315,44 @@ async fn find_matching_request(
   }
 
   match match_results.first() {
-    Some((interaction, _)) => Ok(pact_matching::generate_response(&interaction.response, &GeneratorTestMode::Provider, &hashmap!{}).await),
-    None => {
-      if auto_cors && request.method.to_uppercase() == "OPTIONS" {
-        let origin = if cors_referer {
-          match request.headers {
-            Some(ref h) => h.iter()
-              .find(|kv| kv.0.to_lowercase() == "referer")
-              .map(|kv| kv.1.clone().join(", ")).unwrap_or_else(|| "*".to_string()),
-            None => "*".to_string()
-          }
-        } else { "*".to_string() };
-        Ok(HttpResponse {
-          headers: Some(hashmap!{
-            "Access-Control-Allow-Headers".to_string() => vec!["*".to_string()],
-            "Access-Control-Allow-Methods".to_string() => vec!["GET, HEAD, POST, PUT, DELETE, CONNECT, OPTIONS, TRACE, PATCH".to_string()],
-            "Access-Control-Allow-Origin".to_string() => vec![origin]
-          }),
-          .. HttpResponse::default()
-        })
-      } else {
-        Err(anyhow!("No matching request found for path {}", request.path))
-      }
-    }
+    Some((interaction, pact, _)) => {
+      metrics::record_interaction_hit(pact, interaction);
+      coverage::record_hit(pact, interaction, provider_state.as_ref());
+      telemetry::record_matched_interaction(pact, interaction);
+      logging::record_matched_interaction(pact, interaction, None);
+      Ok(plugins::generate_response(interaction, pact).await)
+    },
+    None => Err(anyhow!("No matching request found for path {}", request.path))
   }
 }
 
 async fn handle_request(
-  request: HttpRequest,
-  auto_cors: bool,
-  cors_referrer: bool,
+  request: &HttpRequest,
+  cors: &CorsPolicy,
+  index: &InteractionIndex,
   sources: Vec<(V4Pact, PactSource)>,
-  provider_state: Option<Regex>,
-  empty_provider_states: bool
-) -> HttpResponse {
-  info! ("===> Received {}", request);
-  debug!("     body: '{}'", request.body.display_string());
+  provider_state: Option<ProviderStateFilter>,
+  empty_provider_states: bool,
+  settings: &MatchSettings
+) -> (HttpResponse, RequestResult) {
+  let redacted = redaction::redact_request(request);
+  info! ("===> Received {}", redacted);
+  debug!("     body: '{}'", redacted.body.display_string());
   debug!("     matching_rules: {:?}", request.matching_rules);
   debug!("     generators: {:?}", request.generators);
-  match find_matching_request(&request, auto_cors, cors_referrer, sources, provider_state,
-                            empty_provider_states).await {
-    Ok(response) => response,
-    Err(msg) => {
+  match find_matching_request(request, sources, provider_state, empty_provider_states, settings).await {
+    Ok(response) => (response, RequestResult::Match),
+    Err(msg) => if let Some(response) = cors.preflight_response(request) {
+      (response, RequestResult::Preflight)
+    } else if let Some(response) = method_not_allowed_response(index, request) {
+      warn!("{}, but the path matches interactions with other methods, sending {}", msg, StatusCode::METHOD_NOT_ALLOWED);
+      (response, RequestResult::Miss)
+    } else {
       warn!("{}, sending {}", msg, StatusCode::NOT_FOUND);
-      let mut response = HttpResponse {
+      (HttpResponse {
         status: StatusCode::NOT_FOUND.as_u16(),
         .. HttpResponse::default()
-      };
-      if auto_cors {
-        response.headers = Some(hashmap!{ "Access-Control-Allow-Origin".to_string() => vec!["*".to_string()] })
-      }
-      response
+      }, RequestResult::Miss)
     }
   }
 }
@@ -567,14 +1361,29 @@ async fn handle_request(
 mod test {
   use expectest::prelude::*;
   use maplit::*;
+  use pact_models::http_parts::HttpPart;
   use pact_models::matchingrules;
   use pact_models::matchingrules::MatchingRule;
   use pact_models::prelude::*;
   use pact_models::prelude::v4::*;
   use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+  use pact_models::v4::interaction::V4Interaction;
   use regex::Regex;
+  use serde_json::json;
 
   use crate::PactSource;
+  use crate::coverage;
+  use crate::cors::CorsPolicy;
+  use crate::matching::MatchSettings;
+  use crate::metrics::RequestResult;
+  use crate::negotiation::Negotiation;
+  use crate::paths::{BasePath, PathNormalisation};
+  use crate::registration::{RegisteredInteractions, INTERACTIONS_PATH};
+  use crate::sessions::Sessions;
+
+  use std::net::IpAddr;
+
+  use super::{BoundAddress, HttpProtocol, ListenAddresses, ServerHandler};
 
   #[tokio::test]
   async fn match_request_finds_the_most_appropriate_response() {
@@ -587,7 +1396,7 @@ mod test {
 
     let request1 = HttpRequest::default();
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, vec![(pact, PactSource::Unknown)], None, false, &MatchSettings::default()).await)
       .to(be_ok().value(interaction1.response));
   }
 
@@ -603,7 +1412,7 @@ mod test {
 
     let request1 = HttpRequest { method: "POST".to_string(), .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, vec![(pact, PactSource::Unknown)], None, false, &MatchSettings::default()).await)
       .to(be_err());
   }
 
@@ -623,7 +1432,7 @@ mod test {
 
     let request1 = HttpRequest { path: "/two".to_string(), .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, vec![(pact, PactSource::Unknown)], None, false, &MatchSettings::default()).await)
       .to(be_err());
   }
 
@@ -642,7 +1451,7 @@ mod test {
         query: Some(hashmap!{ "A".to_string() => vec![ "C".to_string() ] }),
         .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, vec![(pact, PactSource::Unknown)], None, false, &MatchSettings::default()).await)
       .to(be_err());
   }
 
@@ -681,10 +1490,10 @@ mod test {
     let request4 = HttpRequest { method: "PUT".to_string(), headers: Some(hashmap!{ "Content-Type".to_string() => vec!["application/json".to_string()] }),
         .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await).to(be_ok());
-    expect!(super::find_matching_request(&request2, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await).to(be_err());
-    expect!(super::find_matching_request(&request3, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await).to(be_ok());
-    expect!(super::find_matching_request(&request4, false, false, vec![(pact, PactSource::Unknown)], None, false).await).to(be_ok());
+    expect!(super::find_matching_request(&request1, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchSettings::default()).await).to(be_ok());
+    expect!(super::find_matching_request(&request2, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchSettings::default()).await).to(be_err());
+    expect!(super::find_matching_request(&request3, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchSettings::default()).await).to(be_ok());
+    expect!(super::find_matching_request(&request4, vec![(pact, PactSource::Unknown)], None, false, &MatchSettings::default()).await).to(be_ok());
   }
 
   #[tokio::test]
@@ -714,7 +1523,7 @@ mod test {
         body: OptionalBody::Present("{\"a\": 1, \"b\": 4, \"c\": 6}".as_bytes().into(), None, None),
         .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact1, PactSource::Unknown), (pact2, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, vec![(pact1, PactSource::Unknown), (pact2, PactSource::Unknown)], None, false, &MatchSettings::default()).await)
       .to(be_ok().value(interaction2.response));
   }
 
@@ -730,10 +1539,16 @@ mod test {
         method: "OPTIONS".to_string(),
         .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, true, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await)
-      .to(be_ok());
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false).await)
-      .to(be_err());
+    let index = super::InteractionIndex::build_from_sources(&[(pact.clone(), PactSource::Unknown)]);
+    let (response, result) = super::handle_request(&request1, &CorsPolicy::new(true, false), &index,
+      vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchSettings::default()).await;
+    expect!(response.status).to(be_equal_to(200));
+    expect!(result).to(be_equal_to(RequestResult::Preflight));
+    let (response, result) = super::handle_request(&request1, &CorsPolicy::default(), &index,
+      vec![(pact, PactSource::Unknown)], None, false, &MatchSettings::default()).await;
+    expect!(response.status).to(be_equal_to(405));
+    expect!(result).to(be_equal_to(RequestResult::Miss));
+    expect!(response.headers.unwrap().get("Allow").cloned()).to(be_some().value(vec!["GET, HEAD".to_string()]));
   }
 
   #[tokio::test]
@@ -772,7 +1587,7 @@ mod test {
         query: Some(hashmap!{ "page".to_string() => vec![ "3".to_string() ] }),
         .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, vec![(pact, PactSource::Unknown)], None, false, &MatchSettings::default()).await)
       .to(be_ok());
   }
 
@@ -843,15 +1658,15 @@ mod test {
       ] }),
       .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchSettings::default()).await)
       .to(be_err());
-    expect!(super::find_matching_request(&request2, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request2, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchSettings::default()).await)
       .to(be_ok());
-    expect!(super::find_matching_request(&request3, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request3, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchSettings::default()).await)
       .to(be_ok());
-    expect!(super::find_matching_request(&request4, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request4, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchSettings::default()).await)
       .to(be_ok());
-    expect!(super::find_matching_request(&request5, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request5, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchSettings::default()).await)
       .to(be_ok());
   }
 
@@ -890,16 +1705,16 @@ mod test {
 
     let request = HttpRequest::default();
 
-    expect!(super::find_matching_request(&request, false, false, vec![(pact.clone(), PactSource::Unknown)],
-      Some(Regex::new("state one").unwrap()), false).await).to(be_ok().value(response1.clone()));
-    expect!(super::find_matching_request(&request, false, false, vec![(pact.clone(), PactSource::Unknown)],
-      Some(Regex::new("state two").unwrap()), false).await).to(be_ok().value(response2.clone()));
-    expect!(super::find_matching_request(&request, false, false, vec![(pact.clone(), PactSource::Unknown)],
-      Some(Regex::new("state three").unwrap()), false).await).to(be_ok().value(response3.clone()));
-    expect!(super::find_matching_request(&request, false, false, vec![(pact.clone(), PactSource::Unknown)],
-      Some(Regex::new("state four").unwrap()), false).await).to(be_err());
-    expect!(super::find_matching_request(&request, false, false, vec![(pact.clone(), PactSource::Unknown)],
-      Some(Regex::new("state .*").unwrap()), false).await).to(be_ok().value(response1.clone()));
+    expect!(super::find_matching_request(&request, vec![(pact.clone(), PactSource::Unknown)],
+      Some(Regex::new("state one").unwrap().into()), false, &MatchSettings::default()).await).to(be_ok().value(response1.clone()));
+    expect!(super::find_matching_request(&request, vec![(pact.clone(), PactSource::Unknown)],
+      Some(Regex::new("state two").unwrap().into()), false, &MatchSettings::default()).await).to(be_ok().value(response2.clone()));
+    expect!(super::find_matching_request(&request, vec![(pact.clone(), PactSource::Unknown)],
+      Some(Regex::new("state three").unwrap().into()), false, &MatchSettings::default()).await).to(be_ok().value(response3.clone()));
+    expect!(super::find_matching_request(&request, vec![(pact.clone(), PactSource::Unknown)],
+      Some(Regex::new("state four").unwrap().into()), false, &MatchSettings::default()).await).to(be_err());
+    expect!(super::find_matching_request(&request, vec![(pact.clone(), PactSource::Unknown)],
+      Some(Regex::new("state .*").unwrap().into()), false, &MatchSettings::default()).await).to(be_ok().value(response1.clone()));
   }
 
   #[tokio::test]
@@ -935,11 +1750,11 @@ mod test {
 
     let request = HttpRequest::default();
 
-    expect!(super::find_matching_request(&request, false, false, vec![(pact1, PactSource::Unknown)],
-      Some(Regex::new("any state").unwrap()), true).await).to(be_ok().value(response2.clone()));
+    expect!(super::find_matching_request(&request, vec![(pact1, PactSource::Unknown)],
+      Some(Regex::new("any state").unwrap().into()), true, &MatchSettings::default()).await).to(be_ok().value(response2.clone()));
 
-    expect!(super::find_matching_request(&request, false, false, vec![(pact2, PactSource::Unknown)],
-      Some(Regex::new("any state").unwrap()), true).await).to(be_ok().value(response3.clone()));
+    expect!(super::find_matching_request(&request, vec![(pact2, PactSource::Unknown)],
+      Some(Regex::new("any state").unwrap().into()), true, &MatchSettings::default()).await).to(be_ok().value(response3.clone()));
   }
 
   #[tokio::test]
@@ -955,7 +1770,7 @@ mod test {
 
     let request = HttpRequest { headers: Some(hashmap!{ "TEST-X".to_string() => vec!["X, Y".to_string()] }), .. HttpRequest::default() };
 
-    let result = super::find_matching_request(&request, false, false, vec![(pact, PactSource::Unknown)], None, false).await;
+    let result = super::find_matching_request(&request, vec![(pact, PactSource::Unknown)], None, false, &MatchSettings::default()).await;
     expect!(result).to(be_ok().value(interaction.response));
   }
 
@@ -972,7 +1787,722 @@ mod test {
     let request1 = HttpRequest::default();
     let index = super::InteractionIndex::build_from_sources(&[(pact, PactSource::Unknown)]);
 
-    expect!(super::optimized_find_matching_request(&request1, false, false, &index, None, false).await)
+    expect!(super::optimized_find_matching_request(&request1, &index, None, false, &MatchSettings::default()).await)
+      .to(be_ok());
+  }
+
+  #[tokio::test]
+  async fn optimized_find_matching_request_prefers_registered_interactions_with_a_higher_priority() {
+    let interaction = SynchronousHttp {
+      request: HttpRequest { path: "/orders/1".to_string(), .. HttpRequest::default() },
+      response: HttpResponse { status: 200, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![ interaction.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let index = super::InteractionIndex::build_from_sources(&[(pact, PactSource::Unknown)]);
+
+    let registered = RegisteredInteractions::default();
+    let body = json!({
+      "type": "Synchronous/HTTP",
+      "description": "an order error",
+      "request": { "method": "GET", "path": "/orders/1" },
+      "response": { "status": 500 }
+    });
+    registered.handle_request(&http::Method::POST, INTERACTIONS_PATH, None, &http::HeaderMap::new(),
+      body.to_string().as_bytes(), &Sessions::default());
+    let registered_index = index.with_registered(&registered.for_session(None));
+    let request = HttpRequest { path: "/orders/1".to_string(), .. HttpRequest::default() };
+
+    let response = super::optimized_find_matching_request(&request, &index, None, false, &MatchSettings::default()).await;
+    expect!(response.map(|response| response.status)).to(be_ok().value(200));
+    let response = super::optimized_find_matching_request(&request, &registered_index, None, false, &MatchSettings::default()).await;
+    expect!(response.map(|response| response.status)).to(be_ok().value(500));
+
+    let registered = RegisteredInteractions::new(-1);
+    registered.handle_request(&http::Method::POST, INTERACTIONS_PATH, None, &http::HeaderMap::new(),
+      body.to_string().as_bytes(), &Sessions::default());
+    let registered_index = index.with_registered(&registered.for_session(None));
+    let response = super::optimized_find_matching_request(&request, &registered_index, None, false, &MatchSettings::default()).await;
+    expect!(response.map(|response| response.status)).to(be_ok().value(200));
+  }
+
+  #[test]
+  fn registered_indexes_are_cached_until_the_registered_interactions_change() {
+    use std::sync::Arc;
+
+    let index = Arc::new(super::InteractionIndex::build_from_sources(&[]));
+    let registered = RegisteredInteractions::default();
+    let sessions = Sessions::default();
+    let indexes = super::RegisteredIndexes::default();
+    expect!(Arc::ptr_eq(&indexes.index_for_session(&index, &registered, None), &index)).to(be_true());
+
+    let body = json!({
+      "type": "Synchronous/HTTP",
+      "description": "an order error",
+      "request": { "method": "GET", "path": "/orders/1" },
+      "response": { "status": 500 }
+    });
+    registered.handle_request(&http::Method::POST, INTERACTIONS_PATH, None, &http::HeaderMap::new(),
+      body.to_string().as_bytes(), &sessions);
+    let registered_index = indexes.index_for_session(&index, &registered, None);
+    expect!(registered_index.all_interactions.len()).to(be_equal_to(1));
+    expect!(Arc::ptr_eq(&indexes.index_for_session(&index, &registered, None), &registered_index)).to(be_true());
+
+    let session = sessions.create(Some("shard-1".to_string()), None).unwrap();
+    let session_index = indexes.index_for_session(&index, &registered, Some(&session));
+    expect!(session_index.all_interactions.len()).to(be_equal_to(1));
+    expect!(Arc::ptr_eq(&indexes.index_for_session(&index, &registered, Some(&session)), &session_index)).to(be_true());
+
+    registered.handle_request(&http::Method::DELETE, INTERACTIONS_PATH, None, &http::HeaderMap::new(), b"", &sessions);
+    expect!(Arc::ptr_eq(&indexes.index_for_session(&index, &registered, None), &index)).to(be_true());
+  }
+
+  #[tokio::test]
+  async fn find_selected_interaction_pins_matching_to_the_selected_interaction() {
+    let interaction = |description: &str, key: Option<&str>, status: u16| SynchronousHttp {
+      description: description.to_string(),
+      key: key.map(|key| key.to_string()),
+      request: HttpRequest { path: "/orders".to_string(), .. HttpRequest::default() },
+      response: HttpResponse { status, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![
+        interaction("a request for orders", None, 200).boxed_v4(),
+        interaction("a request for orders when there are none", Some("no-orders"), 204).boxed_v4(),
+        interaction("a request for orders that fails", None, 500).boxed_v4()
+      ],
+      .. V4Pact::default()
+    };
+    let index = super::InteractionIndex::build_from_sources(&[(pact, PactSource::Unknown)]);
+    let request = HttpRequest { path: "/orders".to_string(), .. HttpRequest::default() };
+
+    let status = |selection: &'static str| {
+      let (request, index) = (request.clone(), index.clone());
+      async move {
+        super::find_selected_interaction(&request, &index, selection, &MatchSettings::default()).await.map(|response| response.status)
+      }
+    };
+    expect!(status("a request for orders that fails").await).to(be_ok().value(500));
+    expect!(status("no-orders").await).to(be_ok().value(204));
+    expect!(status("0").await).to(be_ok().value(200));
+    expect!(status("3").await).to(be_err());
+    expect!(status("a request for invoices").await).to(be_err());
+
+    let request = HttpRequest { path: "/invoices".to_string(), .. request };
+    let error = super::find_selected_interaction(&request, &index, "no-orders", &MatchSettings::default()).await.unwrap_err();
+    expect!(error["error"].clone()).to(be_equal_to(json!("The selected interaction 'no-orders' does not match the request")));
+    expect!(error["mismatches"][0]["interaction"].clone()).to(be_equal_to(json!("a request for orders when there are none")));
+  }
+
+  #[tokio::test]
+  async fn optimized_find_matching_request_ranks_by_score_and_then_load_order() {
+    let interaction = |description: &str, status: u16| SynchronousHttp {
+      description: description.to_string(),
+      request: HttpRequest { path: "/orders/1".to_string(), .. HttpRequest::default() },
+      response: HttpResponse { status, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    let with_matcher = SynchronousHttp {
+      request: HttpRequest {
+        path: "/orders/1".to_string(),
+        matching_rules: matchingrules! { "path" => { "" => [ MatchingRule::Regex("/orders/\\d+".to_string()) ] } },
+        .. HttpRequest::default()
+      },
+      .. interaction("an order using a matcher", 202)
+    };
+    let pact = V4Pact {
+      interactions: vec![
+        with_matcher.boxed_v4(),
+        interaction("an order", 200).boxed_v4(),
+        interaction("the same order", 201).boxed_v4()
+      ],
+      .. V4Pact::default()
+    };
+    let index = super::InteractionIndex::build_from_sources(&[(pact, PactSource::Unknown)]);
+    let request = HttpRequest { path: "/orders/1".to_string(), .. HttpRequest::default() };
+
+    for _ in 0..10 {
+      let response = super::optimized_find_matching_request(&request, &index, None, false, &MatchSettings::default()).await;
+      expect!(response.map(|response| response.status)).to(be_ok().value(200));
+    }
+  }
+
+  #[tokio::test]
+  async fn optimized_find_matching_request_tries_path_matchers_when_the_exact_path_interactions_do_not_match() {
+    let exact = SynchronousHttp {
+      request: HttpRequest {
+        path: "/orders/1".to_string(),
+        query: Some(hashmap! { "a".to_string() => vec!["b".to_string()] }),
+        .. HttpRequest::default()
+      },
+      .. SynchronousHttp::default()
+    };
+    let with_matcher = SynchronousHttp {
+      request: HttpRequest {
+        path: "/orders/2".to_string(),
+        matching_rules: matchingrules! { "path" => { "" => [ MatchingRule::Regex("/orders/\\d+".to_string()) ] } },
+        .. HttpRequest::default()
+      },
+      response: HttpResponse { status: 202, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![ exact.boxed_v4(), with_matcher.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let index = super::InteractionIndex::build_from_sources(&[(pact, PactSource::Unknown)]);
+
+    let request = HttpRequest { path: "/orders/1".to_string(), .. HttpRequest::default() };
+    let response = super::optimized_find_matching_request(&request, &index, None, false, &MatchSettings::default()).await;
+    expect!(response.map(|response| response.status)).to(be_ok().value(202));
+    let request = HttpRequest {
+      query: Some(hashmap! { "a".to_string() => vec!["b".to_string()] }),
+      .. request
+    };
+    let response = super::optimized_find_matching_request(&request, &index, None, false, &MatchSettings::default()).await;
+    expect!(response.map(|response| response.status)).to(be_ok().value(200));
+  }
+
+  #[tokio::test]
+  async fn optimized_find_matching_request_enforces_headers_and_bodies_in_strict_mode() {
+    let interaction = SynchronousHttp {
+      request: HttpRequest {
+        path: "/orders".to_string(),
+        headers: Some(hashmap! { "Accept".to_string() => vec!["application/json".to_string()] }),
+        .. HttpRequest::default()
+      },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![ interaction.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let index = super::InteractionIndex::build_from_sources(&[(pact.clone(), PactSource::Unknown)]);
+    let with_header = HttpRequest {
+      path: "/orders".to_string(),
+      headers: Some(hashmap! { "Accept".to_string() => vec!["application/json".to_string()] }),
+      .. HttpRequest::default()
+    };
+    let without_header = HttpRequest { path: "/orders".to_string(), .. HttpRequest::default() };
+    let with_body = HttpRequest { body: OptionalBody::Present("{}".into(), None, None), .. with_header.clone() };
+
+    let strict = MatchSettings::strict();
+    expect!(super::optimized_find_matching_request(&without_header, &index, None, false, &MatchSettings::default()).await)
+      .to(be_ok());
+    expect!(super::optimized_find_matching_request(&with_header, &index, None, false, &strict).await).to(be_ok());
+    expect!(super::optimized_find_matching_request(&without_header, &index, None, false, &strict).await).to(be_err());
+    expect!(super::optimized_find_matching_request(&with_body, &index, None, false, &strict).await).to(be_err());
+    expect!(super::find_matching_request(&with_body, vec![(pact.clone(), PactSource::Unknown)], None, false,
+      &MatchSettings::default()).await).to(be_ok());
+    expect!(super::find_matching_request(&with_body, vec![(pact, PactSource::Unknown)], None, false,
+      &strict).await).to(be_err());
+  }
+
+  #[tokio::test]
+  async fn interaction_and_request_paths_are_normalised() {
+    let interaction = SynchronousHttp {
+      request: HttpRequest { path: "/Users/a%20b/".to_string(), .. HttpRequest::default() },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      consumer: Consumer { name: "path normalisation consumer".to_string() },
+      interactions: vec![ interaction.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let sources = vec![(pact, PactSource::Unknown)];
+    let normalisation = PathNormalisation::default()
+      .with_base_path(Some(BasePath::Strip("/v1".to_string())))
+      .with_trailing_slash_tolerance(true)
+      .with_percent_decoding(true)
+      .with_case_insensitive_paths(true);
+    let index = super::InteractionIndex::build_with_path_normalisation(&sources, normalisation);
+    expect!(index.get_candidates_by_method_path("GET", "/v1/Users/a%20b")).to(be_equal_to(vec![0]));
+    expect!(index.all_interactions[0].request.path.as_str()).to(be_equal_to("/Users/a%20b/"));
+
+    let request = HttpRequest { path: "/v1/users/A%20B".to_string(), .. HttpRequest::default() };
+    expect!(super::optimized_find_matching_request(&request, &index, None, false, &MatchSettings::default()).await)
+      .to(be_ok());
+    let request = HttpRequest { path: "/v2/users/A%20B".to_string(), .. HttpRequest::default() };
+    expect!(super::optimized_find_matching_request(&request, &index, None, false, &MatchSettings::default()).await)
+      .to(be_err());
+    // Hits are recorded against the interaction as it is in the pact file
+    expect!(coverage::coverage(&sources)[0].hits).to(be_equal_to(1));
+  }
+
+  #[tokio::test]
+  async fn paths_of_interactions_with_path_matchers_are_not_lower_cased_or_given_a_base_path() {
+    let interaction = SynchronousHttp {
+      request: HttpRequest {
+        path: "/Orders/1".to_string(),
+        matching_rules: matchingrules! { "path" => { "" => [ MatchingRule::Regex("^/Orders/\\d+$".to_string()) ] } },
+        .. HttpRequest::default()
+      },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![ interaction.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let normalisation = PathNormalisation::default()
+      .with_base_path(Some(BasePath::Add("/api".to_string())))
+      .with_trailing_slash_tolerance(true)
+      .with_case_insensitive_paths(true);
+    let index = super::InteractionIndex::build_with_path_normalisation(&[(pact, PactSource::Unknown)], normalisation);
+
+    let request = HttpRequest { path: "/Orders/12/".to_string(), .. HttpRequest::default() };
+    expect!(super::optimized_find_matching_request(&request, &index, None, false, &MatchSettings::default()).await)
+      .to(be_ok());
+    expect!(index.allowed_methods("/Orders/12")).to(be_equal_to(vec!["GET".to_string(), "HEAD".to_string()]));
+    let request = HttpRequest { path: "/orders/12".to_string(), .. HttpRequest::default() };
+    expect!(super::optimized_find_matching_request(&request, &index, None, false, &MatchSettings::default()).await)
+      .to(be_err());
+  }
+
+  #[test]
+  fn head_response_has_the_headers_of_the_get_response_without_the_body() {
+    let response = HttpResponse {
+      status: 200,
+      headers: Some(hashmap! { "X-Total".to_string() => vec!["2".to_string()] }),
+      body: OptionalBody::Present("{\"id\":1}".into(), None, None),
+      .. HttpResponse::default()
+    };
+    let head = super::head_response(response);
+    expect!(head.status).to(be_equal_to(200));
+    expect!(&head.body).to(be_equal_to(&OptionalBody::Missing));
+    expect!(head.lookup_header_value("Content-Length")).to(be_some().value("8"));
+    expect!(head.lookup_header_value("Content-Type")).to(be_some().value("application/json"));
+    expect!(head.lookup_header_value("X-Total")).to(be_some().value("2"));
+  }
+
+  #[test]
+  fn allowed_methods_are_the_methods_of_interactions_with_a_matching_path() {
+    let interaction = |method: &str, path: &str| SynchronousHttp {
+      request: HttpRequest { method: method.to_string(), path: path.to_string(), .. HttpRequest::default() },
+      .. SynchronousHttp::default()
+    };
+    let with_matcher = SynchronousHttp {
+      request: HttpRequest {
+        method: "DELETE".to_string(),
+        path: "/orders/1".to_string(),
+        matching_rules: matchingrules! { "path" => { "" => [ MatchingRule::Regex("/orders/\\d+".to_string()) ] } },
+        .. HttpRequest::default()
+      },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![
+        interaction("GET", "/orders/2").boxed_v4(),
+        interaction("PUT", "/orders/2").boxed_v4(),
+        interaction("post", "/orders").boxed_v4(),
+        with_matcher.boxed_v4()
+      ],
+      .. V4Pact::default()
+    };
+    let index = super::InteractionIndex::build_from_sources(&[(pact, PactSource::Unknown)]);
+    expect!(index.allowed_methods("/orders/2")).to(be_equal_to(vec!["DELETE".to_string(), "GET".to_string(),
+      "HEAD".to_string(), "PUT".to_string()]));
+    expect!(index.allowed_methods("/orders")).to(be_equal_to(vec!["POST".to_string()]));
+    expect!(index.allowed_methods("/customers")).to(be_equal_to(Vec::<String>::new()));
+
+    let request = HttpRequest { method: "PATCH".to_string(), path: "/orders".to_string(), .. HttpRequest::default() };
+    let response = super::method_not_allowed_response(&index, &request).unwrap();
+    expect!(response.status).to(be_equal_to(405));
+    expect!(response.lookup_header_value("Allow")).to(be_some().value("POST"));
+    let request = HttpRequest { method: "POST".to_string(), .. request };
+    expect!(super::method_not_allowed_response(&index, &request)).to(be_none());
+  }
+
+  #[tokio::test]
+  async fn negotiation_does_not_select_an_interaction_that_matches_less_well() {
+    let interaction = |id: &str, response: HttpResponse| SynchronousHttp {
+      request: HttpRequest {
+        path: "/orders".to_string(),
+        headers: Some(hashmap! { "X-Id".to_string() => vec![id.to_string()] }),
+        .. HttpRequest::default()
+      },
+      response,
+      .. SynchronousHttp::default()
+    };
+    let json = HttpResponse {
+      headers: Some(hashmap! { "Content-Type".to_string() => vec!["application/json".to_string()] }),
+      body: OptionalBody::Present("{}".into(), None, None),
+      .. HttpResponse::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![
+        interaction("1", json).boxed_v4(),
+        interaction("2", HttpResponse { status: 204, .. HttpResponse::default() }).boxed_v4()
+      ],
+      .. V4Pact::default()
+    };
+    let index = super::InteractionIndex::build_from_sources(&[(pact, PactSource::Unknown)]);
+
+    let request = HttpRequest {
+      path: "/orders".to_string(),
+      headers: Some(hashmap! { "X-Id".to_string() => vec!["2".to_string()] }),
+      .. HttpRequest::default()
+    };
+    let response = super::optimized_find_matching_request(&request, &index, None, false, &MatchSettings::default()).await;
+    expect!(response.map(|response| response.status)).to(be_ok().value(204));
+    let mut request = request;
+    request.headers.as_mut().unwrap().insert("Accept".to_string(), vec!["*/*".to_string()]);
+    let response = super::optimized_find_matching_request(&request, &index, None, false, &MatchSettings::default()).await;
+    expect!(response.map(|response| response.status)).to(be_ok().value(204));
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn optimized_find_matching_request_negotiates_between_representations() {
+    let interaction = |description: &str, content_type: &str, version: &str, status: u16| SynchronousHttp {
+      description: description.to_string(),
+      request: HttpRequest {
+        path: "/orders".to_string(),
+        headers: Some(hashmap! { "Accept-Version".to_string() => vec![version.to_string()] }),
+        .. HttpRequest::default()
+      },
+      response: HttpResponse {
+        status,
+        headers: Some(hashmap! { "Content-Type".to_string() => vec![content_type.to_string()] }),
+        .. HttpResponse::default()
+      },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![
+        interaction("orders as JSON", "application/json", "1", 200).boxed_v4(),
+        interaction("orders as CSV", "text/csv", "1", 201).boxed_v4(),
+        interaction("orders as JSON version 2", "application/json", "2", 202).boxed_v4()
+      ],
+      .. V4Pact::default()
+    };
+    let index = super::InteractionIndex::build_from_sources(&[(pact, PactSource::Unknown)]);
+    let request = |headers: &[(&str, &str)]| HttpRequest {
+      path: "/orders".to_string(),
+      headers: Some(headers.iter().map(|(name, value)| (name.to_string(), vec![value.to_string()])).collect()),
+      .. HttpRequest::default()
+    };
+    let settings = MatchSettings::default().with_negotiation(Negotiation::new(&["Accept-Version".to_string()], false));
+    let strict = MatchSettings::default().with_negotiation(Negotiation::new(&["Accept-Version".to_string()], true));
+
+    let csv = request(&[("Accept", "application/json;q=0.5, text/csv"), ("Accept-Version", "1")]);
+    expect!(super::optimized_find_matching_request(&csv, &index, None, false, &settings).await
+      .map(|response| response.status)).to(be_ok().value(201));
+    let version_2 = request(&[("Accept", "application/json"), ("Accept-Version", "2")]);
+    expect!(super::optimized_find_matching_request(&version_2, &index, None, false, &settings).await
+      .map(|response| response.status)).to(be_ok().value(202));
+
+    let xml = request(&[("Accept", "application/xml"), ("Accept-Version", "1")]);
+    expect!(super::optimized_find_matching_request(&xml, &index, None, false, &settings).await)
       .to(be_ok());
+    let result = super::optimized_find_matching_request(&xml, &index, None, false, &strict).await;
+    expect!(result.as_ref().map(|_| ())).to(be_err());
+    expect!(result.unwrap_err().is::<super::NotAcceptable>()).to(be_true());
+  }
+
+  fn test_server_handler() -> ServerHandler {
+    let interaction = SynchronousHttp {
+      request: HttpRequest { path: "/test".to_string(), .. HttpRequest::default() },
+      response: HttpResponse { status: 201, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![ interaction.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    ServerHandler::new(vec![(pact, PactSource::Unknown)], false, false, None, None, false, None)
+  }
+
+  fn start_test_server(protocol: HttpProtocol, tls: bool) -> u16 {
+    let tls_config = if tls {
+      let dir = env!("CARGO_MANIFEST_DIR");
+      Some(crate::tls::load_tls_config(&format!("{}/tests/tls/cert.pem", dir),
+        &format!("{}/tests/tls/key.pem", dir), protocol).unwrap())
+    } else {
+      None
+    };
+    let addresses = ListenAddresses {
+      hosts: vec![IpAddr::from([127, 0, 0, 1])],
+      port: 0,
+      unix_socket: None
+    };
+    let (bound, server) = test_server_handler().bind(&addresses, protocol, tls_config).unwrap();
+    tokio::spawn(server);
+    match bound.first() {
+      Some(BoundAddress::Tcp(addr)) => addr.port(),
+      _ => panic!("Expected the server to be bound to a TCP address")
+    }
+  }
+
+  async fn plain_request(port: u16, http2: bool) -> hyper::Result<hyper::Response<hyper::Body>> {
+    let client = hyper::Client::builder().http2_only(http2).build_http::<hyper::Body>();
+    client.get(format!("http://127.0.0.1:{}/test", port).parse().unwrap()).await
+  }
+
+  async fn tls_request(port: u16, alpn: Vec<Vec<u8>>) -> (Option<Vec<u8>>, hyper::Response<hyper::Body>) {
+    use std::io::BufReader;
+    use std::sync::Arc;
+    use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+
+    let cert_file = std::fs::File::open(format!("{}/tests/tls/cert.pem", env!("CARGO_MANIFEST_DIR"))).unwrap();
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(cert_file)).unwrap() {
+      roots.add(&Certificate(cert)).unwrap();
+    }
+    let mut config = ClientConfig::builder()
+      .with_safe_defaults()
+      .with_root_certificates(roots)
+      .with_no_client_auth();
+    config.alpn_protocols = alpn;
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    let stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let stream = connector.connect(ServerName::try_from("localhost").unwrap(), stream).await.unwrap();
+    let negotiated = stream.get_ref().1.alpn_protocol().map(|p| p.to_vec());
+
+    let (mut sender, connection) = hyper::client::conn::Builder::new()
+      .http2_only(negotiated == Some(b"h2".to_vec()))
+      .handshake(stream)
+      .await
+      .unwrap();
+    tokio::spawn(connection);
+    let request = hyper::Request::get(format!("https://localhost:{}/test", port))
+      .body(hyper::Body::empty())
+      .unwrap();
+    (negotiated, sender.send_request(request).await.unwrap())
+  }
+
+  #[tokio::test]
+  async fn server_matches_requests_over_http1_and_h2c() {
+    let port = start_test_server(HttpProtocol::Auto, false);
+
+    let response = plain_request(port, false).await.unwrap();
+    expect!(response.version()).to(be_equal_to(hyper::Version::HTTP_11));
+    expect!(response.status().as_u16()).to(be_equal_to(201));
+
+    let response = plain_request(port, true).await.unwrap();
+    expect!(response.version()).to(be_equal_to(hyper::Version::HTTP_2));
+    expect!(response.status().as_u16()).to(be_equal_to(201));
+  }
+
+  #[tokio::test]
+  async fn server_can_be_restricted_to_a_single_protocol() {
+    let port = start_test_server(HttpProtocol::Http1Only, false);
+    expect!(plain_request(port, false).await.map(|r| r.status().as_u16())).to(be_ok().value(201));
+    expect!(plain_request(port, true).await).to(be_err());
+
+    let port = start_test_server(HttpProtocol::Http2Only, false);
+    expect!(plain_request(port, true).await.map(|r| r.status().as_u16())).to(be_ok().value(201));
+    expect!(plain_request(port, false).await).to(be_err());
+  }
+
+  #[tokio::test]
+  async fn server_negotiates_the_protocol_with_alpn_over_tls() {
+    let port = start_test_server(HttpProtocol::Auto, true);
+
+    let (negotiated, response) = tls_request(port, vec![b"h2".to_vec(), b"http/1.1".to_vec()]).await;
+    expect!(negotiated).to(be_some().value(b"h2".to_vec()));
+    expect!(response.version()).to(be_equal_to(hyper::Version::HTTP_2));
+    expect!(response.status().as_u16()).to(be_equal_to(201));
+
+    let (negotiated, response) = tls_request(port, vec![b"http/1.1".to_vec()]).await;
+    expect!(negotiated).to(be_some().value(b"http/1.1".to_vec()));
+    expect!(response.version()).to(be_equal_to(hyper::Version::HTTP_11));
+    expect!(response.status().as_u16()).to(be_equal_to(201));
+  }
+
+  #[tokio::test]
+  async fn server_only_advertises_http1_over_tls_when_http2_is_disabled() {
+    let port = start_test_server(HttpProtocol::Http1Only, true);
+    let (negotiated, response) = tls_request(port, vec![b"h2".to_vec(), b"http/1.1".to_vec()]).await;
+    expect!(negotiated).to(be_some().value(b"http/1.1".to_vec()));
+    expect!(response.status().as_u16()).to(be_equal_to(201));
+  }
+
+  #[tokio::test]
+  async fn server_listens_on_ipv4_and_ipv6_addresses_with_the_same_port() {
+    let addresses = ListenAddresses {
+      hosts: vec![IpAddr::from([127, 0, 0, 1]), "::1".parse().unwrap()],
+      port: 0,
+      unix_socket: None
+    };
+    let (bound, server) = test_server_handler().bind(&addresses, HttpProtocol::Auto, None).unwrap();
+    tokio::spawn(server);
+
+    expect!(bound.len()).to(be_equal_to(2));
+    let port = match bound[0] {
+      BoundAddress::Tcp(addr) => addr.port(),
+      _ => panic!("Expected the server to be bound to a TCP address")
+    };
+    expect!(bound[1].to_string()).to(be_equal_to(format!("[::1]:{}", port)));
+
+    let client = hyper::Client::new();
+    for url in [format!("http://127.0.0.1:{}/test", port), format!("http://[::1]:{}/test", port)] {
+      let response = client.get(url.parse().unwrap()).await.unwrap();
+      expect!(response.status().as_u16()).to(be_equal_to(201));
+    }
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn server_listens_on_a_unix_domain_socket() {
+    use tokio::net::UnixStream;
+
+    let path = std::env::temp_dir().join(format!("pact-stub-server-{}.sock", std::process::id()));
+    let addresses = ListenAddresses {
+      hosts: vec![],
+      port: 0,
+      unix_socket: Some(path.clone())
+    };
+    let (bound, server) = test_server_handler().bind(&addresses, HttpProtocol::Auto, None).unwrap();
+    tokio::spawn(server);
+    expect!(bound).to(be_equal_to(vec![BoundAddress::Unix(path.clone())]));
+
+    let stream = UnixStream::connect(&path).await.unwrap();
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    let request = hyper::Request::get("/test")
+      .header("Host", "localhost")
+      .body(hyper::Body::empty())
+      .unwrap();
+    let response = sender.send_request(request).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(201));
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn unix_socket_is_only_replaced_if_no_server_is_listening_on_it() {
+    let path = std::env::temp_dir().join(format!("pact-stub-server-{}-in-use.sock", std::process::id()));
+    let listener = super::unix_listener(&path).unwrap();
+    expect!(super::unix_listener(&path).map_err(|err| err.kind())).to(be_err().value(std::io::ErrorKind::AddrInUse));
+
+    // A socket file left behind by a server that is no longer running is replaced
+    drop(listener);
+    expect!(path.exists()).to(be_true());
+    expect!(super::unix_listener(&path)).to(be_ok());
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn server_fails_to_start_with_no_addresses() {
+    let addresses = ListenAddresses { hosts: vec![], port: 0, unix_socket: None };
+    expect!(test_server_handler().bind(&addresses, HttpProtocol::Auto, None).map(|(bound, _)| bound)).to(be_err());
+  }
+
+  #[tokio::test]
+  async fn server_shuts_down_gracefully_via_the_admin_endpoint() {
+    let shutdown = crate::shutdown::Shutdown::new();
+    let handler = test_server_handler()
+      .with_graceful_shutdown(shutdown.clone(), std::time::Duration::from_secs(1), true);
+    let addresses = ListenAddresses { hosts: vec![IpAddr::from([127, 0, 0, 1])], port: 0, unix_socket: None };
+    let (bound, server) = handler.bind(&addresses, HttpProtocol::Auto, None).unwrap();
+    let drain_shutdown = shutdown.clone();
+    let server = tokio::spawn(async move {
+      crate::shutdown::run_until_drained(server, &drain_shutdown, std::time::Duration::from_secs(1)).await
+    });
+
+    let client = hyper::Client::new();
+    let request = hyper::Request::post(format!("http://{}/__admin/shutdown", bound[0]))
+      .body(hyper::Body::empty())
+      .unwrap();
+    let response = client.request(request).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(202));
+
+    let result = tokio::time::timeout(std::time::Duration::from_secs(5), server).await;
+    expect!(result.map(|r| r.unwrap())).to(be_ok().value(Ok(())));
+  }
+
+  #[tokio::test]
+  async fn admin_api_is_disabled_by_default() {
+    use tower_service::Service;
+
+    let create_session = || hyper::Request::post(crate::sessions::SESSIONS_PATH)
+      .body(hyper::Body::from(r#"{"id": "shard-1"}"#))
+      .unwrap();
+    let response = test_server_handler().call(create_session()).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(404));
+
+    let response = test_server_handler().with_admin_api(true).call(create_session()).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(201));
+
+    let list_interactions = || hyper::Request::get(INTERACTIONS_PATH).body(hyper::Body::empty()).unwrap();
+    let response = test_server_handler().call(list_interactions()).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(404));
+    let response = test_server_handler().with_admin_api(true).call(list_interactions()).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(200));
+  }
+
+  #[tokio::test]
+  async fn error_responses_have_cors_headers() {
+    use tower_service::Service;
+
+    let request = hyper::Request::post("/test")
+      .header("Content-Encoding", "zstd")
+      .body(hyper::Body::from("{}"))
+      .unwrap();
+    let response = test_server_handler().call(request).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(415));
+    expect!(response.headers().get("Access-Control-Allow-Origin").map(|value| value.to_str().unwrap().to_string()))
+      .to(be_some().value("*"));
+
+    let strict = MatchSettings::default().with_negotiation(Negotiation::new(&[], true));
+    let request = hyper::Request::get("/test")
+      .header("Accept", "application/xml")
+      .header("Origin", "https://app.example.com")
+      .body(hyper::Body::empty())
+      .unwrap();
+    let interaction = SynchronousHttp {
+      request: HttpRequest { path: "/test".to_string(), .. HttpRequest::default() },
+      response: HttpResponse {
+        headers: Some(hashmap! { "Content-Type".to_string() => vec!["application/json".to_string()] }),
+        .. HttpResponse::default()
+      },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![ interaction.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let mut handler = ServerHandler::new(vec![(pact, PactSource::Unknown)], false, false, None, None, false, None)
+      .with_match_settings(strict)
+      .with_cors_policy(CorsPolicy::default().with_credentials(true));
+    let response = handler.call(request).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(406));
+    expect!(response.headers().get("Access-Control-Allow-Origin").map(|value| value.to_str().unwrap().to_string()))
+      .to(be_some().value("https://app.example.com"));
+  }
+
+  #[tokio::test]
+  async fn messages_are_only_published_with_the_admin_api() {
+    use tower_service::Service;
+
+    let handler = || ServerHandler::new(vec![], false, false, None, Some("X-Provider-State".to_string()), false,
+      Some(crate::messages::MessageTarget::Tcp("127.0.0.1:1".to_string())));
+    let publish = || hyper::Request::post(crate::messages::PUBLISH_PATH)
+      .header("X-Provider-State", "{")
+      .body(hyper::Body::from(r#"{"description": "an order created event"}"#))
+      .unwrap();
+    // Without the admin API, the request is handled like any other, so the provider state selection is rejected
+    let response = handler().call(publish()).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(400));
+
+    // The publish endpoint is handled before the provider state selection
+    let response = handler().with_admin_api(true).call(publish()).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(404));
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    expect!(String::from_utf8_lossy(&body).contains("No matching message interaction was found")).to(be_true());
   }
-}
\ No newline at end of file
+
+  #[tokio::test]
+  async fn shutdown_endpoint_is_disabled_by_default() {
+    let port = start_test_server(HttpProtocol::Auto, false);
+    let client = hyper::Client::new();
+    let request = hyper::Request::post(format!("http://127.0.0.1:{}/__admin/shutdown", port))
+      .body(hyper::Body::empty())
+      .unwrap();
+    let response = client.request(request).await.unwrap();
+    expect!(response.status().as_u16()).to_not(be_equal_to(202));
+  }
+}
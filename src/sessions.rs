@@ -0,0 +1,432 @@
+//! Functions relating to sessions. A session isolates the state used by one test run (for instance,
+//! a UI test shard) from other test runs that share the same stub server.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use http::{HeaderMap, Method, StatusCode};
+use hyper::{Body, Response as HyperResponse};
+use regex::Regex;
+use serde_json::{json, Value};
+use tracing::{debug, info};
+
+use crate::logging::MatchedInteraction;
+
+/// Base path of the admin endpoints used to manage the sessions
+pub const SESSIONS_PATH: &str = "/__admin/sessions";
+
+/// Default header used to select the session for a request
+pub const DEFAULT_SESSION_HEADER: &str = "X-Stub-Session";
+
+/// Default cookie used to select the session for a request
+pub const DEFAULT_SESSION_COOKIE: &str = "stub-session";
+
+/// Default time a session can be idle for before it expires
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Maximum number of requests kept in the journal of a session
+const MAX_JOURNAL_ENTRIES: usize = 1000;
+
+/// Request recorded in the journal of a session
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+  pub method: String,
+  pub path: String,
+  pub status: u16,
+  pub interaction: Option<MatchedInteraction>
+}
+
+impl JournalEntry {
+  fn to_json(&self) -> Value {
+    json!({
+      "method": self.method,
+      "path": self.path,
+      "status": self.status,
+      "matched": self.interaction.is_some(),
+      "consumer": self.interaction.as_ref().map(|i| i.consumer.as_str()),
+      "provider": self.interaction.as_ref().map(|i| i.provider.as_str()),
+      "interaction": self.interaction.as_ref().map(|i| i.description.as_str()),
+      "score": self.interaction.as_ref().and_then(|i| i.score)
+    })
+  }
+}
+
+/// Isolated context for the requests that select it, with its own provider state selection and journal
+#[derive(Debug)]
+pub struct Session {
+  id: String,
+  provider_state: Mutex<Option<Regex>>,
+  journal: Mutex<Vec<JournalEntry>>,
+  last_used: Mutex<Instant>
+}
+
+impl Session {
+  fn new(id: &str, provider_state: Option<Regex>) -> Session {
+    Session {
+      id: id.to_string(),
+      provider_state: Mutex::new(provider_state),
+      journal: Mutex::new(vec![]),
+      last_used: Mutex::new(Instant::now())
+    }
+  }
+
+  /// Session ID
+  pub fn id(&self) -> &str {
+    self.id.as_str()
+  }
+
+  /// Provider state regex used to filter the interactions for the requests in this session
+  pub fn provider_state(&self) -> Option<Regex> {
+    self.provider_state.lock().unwrap_or_else(|err| err.into_inner()).clone()
+  }
+
+  /// Records a request in the journal of the session
+  pub fn record(&self, entry: JournalEntry) {
+    let mut journal = self.journal.lock().unwrap_or_else(|err| err.into_inner());
+    if journal.len() >= MAX_JOURNAL_ENTRIES {
+      journal.remove(0);
+    }
+    journal.push(entry);
+  }
+
+  /// Requests recorded in the journal of the session
+  pub fn journal(&self) -> Vec<JournalEntry> {
+    self.journal.lock().unwrap_or_else(|err| err.into_inner()).clone()
+  }
+
+  fn touch(&self) {
+    *self.last_used.lock().unwrap_or_else(|err| err.into_inner()) = Instant::now();
+  }
+
+  fn idle_time(&self) -> Duration {
+    self.last_used.lock().unwrap_or_else(|err| err.into_inner()).elapsed()
+  }
+
+  fn to_json(&self) -> Value {
+    json!({
+      "id": self.id,
+      "providerState": self.provider_state().map(|regex| regex.to_string()),
+      "idleSeconds": self.idle_time().as_secs(),
+      "journal": self.journal().iter().map(|entry| entry.to_json()).collect::<Vec<_>>()
+    })
+  }
+}
+
+/// Registry of the active sessions, and how the session is selected for a request. All clones share
+/// the same sessions.
+#[derive(Debug, Clone)]
+pub struct Sessions {
+  header: String,
+  cookie: String,
+  idle_timeout: Duration,
+  sessions: Arc<Mutex<HashMap<String, Arc<Session>>>>,
+  next_id: Arc<AtomicUsize>
+}
+
+impl Sessions {
+  /// Creates the session registry, with the header and cookie used to select a session. Sessions
+  /// that are idle for longer than the timeout expire.
+  pub fn new(header: &str, cookie: &str, idle_timeout: Duration) -> Sessions {
+    Sessions {
+      header: header.to_string(),
+      cookie: cookie.to_string(),
+      idle_timeout,
+      sessions: Arc::default(),
+      next_id: Arc::new(AtomicUsize::new(1))
+    }
+  }
+
+  /// Returns the session ID from the session header or cookie of a request
+  pub fn session_id(&self, headers: &HeaderMap) -> Option<String> {
+    headers.get(self.header.as_str())
+      .and_then(|value| value.to_str().ok())
+      .map(|value| value.trim().to_string())
+      .or_else(|| headers.get_all(http::header::COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == self.cookie)
+        .map(|(_, value)| value.trim().to_string()))
+      .filter(|id| !id.is_empty())
+  }
+
+  /// Returns the session selected by the request, if any. If the request selects a session that does
+  /// not exist (or has expired), the session ID is returned as an error.
+  pub fn session_for_request(&self, headers: &HeaderMap) -> Result<Option<Arc<Session>>, String> {
+    match self.session_id(headers) {
+      Some(id) => match self.get(&id) {
+        Some(session) => {
+          session.touch();
+          Ok(Some(session))
+        },
+        None => Err(id)
+      },
+      None => Ok(None)
+    }
+  }
+
+  /// Creates a new session. If no ID is given, one is generated. Returns an error if a session
+  /// with the ID already exists.
+  pub fn create(&self, id: Option<String>, provider_state: Option<Regex>) -> Result<Arc<Session>, String> {
+    let mut sessions = self.active_sessions();
+    let id = id.unwrap_or_else(|| loop {
+      let id = format!("session-{}", self.next_id.fetch_add(1, Ordering::SeqCst));
+      if !sessions.contains_key(&id) {
+        break id;
+      }
+    });
+    if sessions.contains_key(&id) {
+      return Err(format!("Session '{}' already exists", id));
+    }
+    let session = Arc::new(Session::new(&id, provider_state));
+    sessions.insert(id.clone(), session.clone());
+    info!("Created session '{}'", id);
+    Ok(session)
+  }
+
+  /// Returns the session with the ID, if it exists and has not expired
+  pub fn get(&self, id: &str) -> Option<Arc<Session>> {
+    self.active_sessions().get(id).cloned()
+  }
+
+  /// Removes the session with the ID, returning true if it existed
+  pub fn remove(&self, id: &str) -> bool {
+    let removed = self.active_sessions().remove(id).is_some();
+    if removed {
+      info!("Removed session '{}'", id);
+    }
+    removed
+  }
+
+  // Locks the sessions, removing any that have expired
+  fn active_sessions(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<Session>>> {
+    let mut sessions = self.sessions.lock().unwrap_or_else(|err| err.into_inner());
+    sessions.retain(|id, session| {
+      let active = session.idle_time() < self.idle_timeout;
+      if !active {
+        debug!("Session '{}' has expired", id);
+      }
+      active
+    });
+    sessions
+  }
+
+  /// Returns the response for a request to one of the session admin endpoints, or None if the request
+  /// is not for a session endpoint.
+  ///
+  /// * `POST /__admin/sessions` creates a session, with an optional `id` and `providerState` in the JSON body
+  /// * `GET /__admin/sessions` lists the sessions
+  /// * `GET /__admin/sessions/{id}` returns the session, including its journal
+  /// * `PUT /__admin/sessions/{id}` sets the `providerState` of the session
+  /// * `DELETE /__admin/sessions/{id}` removes the session
+  pub fn handle_request(&self, method: &Method, path: &str, body: &[u8]) -> Option<Result<HyperResponse<Body>, http::Error>> {
+    let endpoint = path.strip_prefix(SESSIONS_PATH)?;
+    let id = match endpoint {
+      "" | "/" => None,
+      _ => Some(endpoint.strip_prefix('/')?.trim_end_matches('/'))
+    };
+    let response = match (method, id) {
+      (&Method::GET, None) => json_response(StatusCode::OK, json!({
+        "sessions": self.active_sessions().values().map(|session| session.to_json()).collect::<Vec<_>>()
+      })),
+      (&Method::POST, None) => match parse_session_body(body) {
+        Ok((id, provider_state)) => match self.create(id, provider_state) {
+          Ok(session) => json_response(StatusCode::CREATED, session.to_json()),
+          Err(err) => error_response(StatusCode::CONFLICT, &err)
+        },
+        Err(err) => error_response(StatusCode::BAD_REQUEST, &err)
+      },
+      (&Method::GET, Some(id)) => match self.get(id) {
+        Some(session) => json_response(StatusCode::OK, session.to_json()),
+        None => not_found_response(id)
+      },
+      (&Method::PUT, Some(id)) => match (self.get(id), parse_session_body(body)) {
+        (Some(session), Ok((_, provider_state))) => {
+          *session.provider_state.lock().unwrap_or_else(|err| err.into_inner()) = provider_state;
+          session.touch();
+          json_response(StatusCode::OK, session.to_json())
+        },
+        (Some(_), Err(err)) => error_response(StatusCode::BAD_REQUEST, &err),
+        (None, _) => not_found_response(id)
+      },
+      (&Method::DELETE, Some(id)) => if self.remove(id) {
+        HyperResponse::builder().status(StatusCode::NO_CONTENT).body(Body::empty())
+      } else {
+        not_found_response(id)
+      },
+      _ => error_response(StatusCode::METHOD_NOT_ALLOWED, &format!("{} is not supported for {}", method, path))
+    };
+    Some(response)
+  }
+}
+
+impl Default for Sessions {
+  fn default() -> Self {
+    Sessions::new(DEFAULT_SESSION_HEADER, DEFAULT_SESSION_COOKIE, DEFAULT_SESSION_TIMEOUT)
+  }
+}
+
+// Parses the optional session ID and provider state regex from the body of a session admin request
+fn parse_session_body(body: &[u8]) -> Result<(Option<String>, Option<Regex>), String> {
+  if body.iter().all(|b| b.is_ascii_whitespace()) {
+    return Ok((None, None));
+  }
+  let json: Value = serde_json::from_slice(body)
+    .map_err(|err| format!("Session request body is not valid JSON: {}", err))?;
+  let id = match json.get("id") {
+    Some(Value::String(id)) if !id.trim().is_empty() => Some(id.trim().to_string()),
+    None | Some(Value::Null) => None,
+    Some(_) => return Err("Session id must be a non-empty string".to_string())
+  };
+  let provider_state = match json.get("providerState") {
+    Some(Value::String(state)) => Some(Regex::new(state)
+      .map_err(|err| format!("'{}' is not a valid regular expression: {}", state, err))?),
+    None | Some(Value::Null) => None,
+    Some(_) => return Err("Session providerState must be a string".to_string())
+  };
+  Ok((id, provider_state))
+}
+
+fn json_response(status: StatusCode, body: Value) -> Result<HyperResponse<Body>, http::Error> {
+  HyperResponse::builder()
+    .status(status)
+    .header("Content-Type", "application/json")
+    .body(Body::from(body.to_string()))
+}
+
+fn error_response(status: StatusCode, error: &str) -> Result<HyperResponse<Body>, http::Error> {
+  json_response(status, json!({ "error": error }))
+}
+
+fn not_found_response(id: &str) -> Result<HyperResponse<Body>, http::Error> {
+  error_response(StatusCode::NOT_FOUND, &format!("Session '{}' does not exist or has expired", id))
+}
+
+/// Response for a request that selects a session that does not exist (or has expired)
+pub fn unknown_session_response(id: &str) -> Result<HyperResponse<Body>, http::Error> {
+  error_response(StatusCode::BAD_REQUEST, &format!("Session '{}' does not exist or has expired", id))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use expectest::prelude::*;
+  use http::{HeaderMap, HeaderValue, Method, StatusCode};
+  use serde_json::{json, Value};
+
+  use super::*;
+
+  fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(name, HeaderValue::from_static(value));
+    headers
+  }
+
+  async fn body_json(response: HyperResponse<Body>) -> Value {
+    serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap()
+  }
+
+  #[test]
+  fn session_is_selected_by_the_header_or_cookie() {
+    let sessions = Sessions::default();
+    expect!(sessions.session_id(&headers("x-stub-session", "shard-1"))).to(be_some().value("shard-1"));
+    expect!(sessions.session_id(&headers("cookie", "theme=dark; stub-session=shard-2"))).to(be_some().value("shard-2"));
+    expect!(sessions.session_id(&headers("cookie", "theme=dark"))).to(be_none());
+    expect!(sessions.session_id(&HeaderMap::new())).to(be_none());
+  }
+
+  #[test]
+  fn session_for_request_returns_an_error_for_unknown_sessions() {
+    let sessions = Sessions::default();
+    expect!(sessions.session_for_request(&HeaderMap::new()).map(|s| s.is_some())).to(be_ok().value(false));
+    expect!(sessions.session_for_request(&headers("x-stub-session", "shard-1")).map(|s| s.is_some()))
+      .to(be_err().value("shard-1".to_string()));
+
+    sessions.create(Some("shard-1".to_string()), None).unwrap();
+    let session = sessions.session_for_request(&headers("x-stub-session", "shard-1")).unwrap().unwrap();
+    expect!(session.id()).to(be_equal_to("shard-1"));
+  }
+
+  #[test]
+  fn create_generates_an_id_and_rejects_duplicates() {
+    let sessions = Sessions::default();
+    let session = sessions.create(None, None).unwrap();
+    expect!(session.id()).to(be_equal_to("session-1"));
+    expect!(sessions.create(Some("session-1".to_string()), None)).to(be_err());
+  }
+
+  #[test]
+  fn sessions_expire_once_idle_for_the_timeout() {
+    let sessions = Sessions::new(DEFAULT_SESSION_HEADER, DEFAULT_SESSION_COOKIE, Duration::from_millis(20));
+    sessions.create(Some("shard-1".to_string()), None).unwrap();
+    expect!(sessions.get("shard-1")).to(be_some());
+    std::thread::sleep(Duration::from_millis(30));
+    expect!(sessions.get("shard-1")).to(be_none());
+  }
+
+  #[test]
+  fn journal_only_keeps_the_most_recent_requests() {
+    let session = Session::new("shard-1", None);
+    for status in 0..(MAX_JOURNAL_ENTRIES + 1) {
+      session.record(JournalEntry {
+        method: "GET".to_string(),
+        path: "/".to_string(),
+        status: status as u16,
+        interaction: None
+      });
+    }
+    let journal = session.journal();
+    expect!(journal.len()).to(be_equal_to(MAX_JOURNAL_ENTRIES));
+    expect!(journal[0].status).to(be_equal_to(1));
+  }
+
+  #[test]
+  fn sessions_can_be_used_after_a_panic_while_locked() {
+    let sessions = Arc::new(Sessions::default());
+    sessions.create(Some("shard-1".to_string()), None).unwrap();
+    let poisoned = sessions.clone();
+    let result = std::thread::spawn(move || {
+      let session = poisoned.get("shard-1").unwrap();
+      let _journal = session.journal.lock();
+      let _sessions = poisoned.active_sessions();
+      panic!("panic while holding the session locks");
+    }).join();
+    expect!(result.is_err()).to(be_true());
+
+    let session = sessions.get("shard-1");
+    expect!(session.as_ref().map(|session| session.journal().len())).to(be_some().value(0));
+    expect!(sessions.create(Some("shard-2".to_string()), None)).to(be_ok());
+  }
+
+  #[tokio::test]
+  async fn admin_endpoints_manage_the_sessions() {
+    let sessions = Sessions::default();
+    expect!(sessions.handle_request(&Method::GET, "/test", b"")).to(be_none());
+
+    let response = sessions.handle_request(&Method::POST, SESSIONS_PATH,
+      br#"{"id": "shard-1", "providerState": "an order exists"}"#).unwrap().unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::CREATED));
+    let json = body_json(response).await;
+    expect!(json["id"].clone()).to(be_equal_to(json!("shard-1")));
+    expect!(json["providerState"].clone()).to(be_equal_to(json!("an order exists")));
+
+    let response = sessions.handle_request(&Method::POST, SESSIONS_PATH, br#"{"providerState": "["}"#).unwrap().unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::BAD_REQUEST));
+
+    let response = sessions.handle_request(&Method::PUT, "/__admin/sessions/shard-1",
+      br#"{"providerState": "no orders"}"#).unwrap().unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::OK));
+    expect!(sessions.get("shard-1").unwrap().provider_state().map(|r| r.to_string())).to(be_some().value("no orders"));
+
+    let response = sessions.handle_request(&Method::GET, SESSIONS_PATH, b"").unwrap().unwrap();
+    expect!(body_json(response).await["sessions"].as_array().unwrap().len()).to(be_equal_to(1));
+
+    let response = sessions.handle_request(&Method::DELETE, "/__admin/sessions/shard-1", b"").unwrap().unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::NO_CONTENT));
+    let response = sessions.handle_request(&Method::GET, "/__admin/sessions/shard-1", b"").unwrap().unwrap();
+    expect!(response.status()).to(be_equal_to(StatusCode::NOT_FOUND));
+  }
+}
@@ -0,0 +1,158 @@
+//! Functions relating to the graceful shutdown of the stub server
+
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::Future;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// Path of the admin endpoint used to shut down the server
+pub const SHUTDOWN_PATH: &str = "/__admin/shutdown";
+
+/// Default time to wait for in-flight requests to complete when shutting down
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Exit code returned when in-flight requests did not complete within the drain timeout
+pub const DRAIN_TIMEOUT_EXIT_CODE: u8 = 4;
+
+/// Signal used to trigger a graceful shutdown of the servers. All clones share the same signal.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+  sender: Arc<watch::Sender<bool>>,
+  receiver: watch::Receiver<bool>
+}
+
+impl Shutdown {
+  /// Creates a new shutdown signal that has not been triggered
+  pub fn new() -> Self {
+    let (sender, receiver) = watch::channel(false);
+    Shutdown {
+      sender: Arc::new(sender),
+      receiver
+    }
+  }
+
+  /// Triggers the shutdown
+  pub fn trigger(&self) {
+    self.sender.send_replace(true);
+  }
+
+  /// If the shutdown has been triggered
+  pub fn is_triggered(&self) -> bool {
+    *self.receiver.borrow()
+  }
+
+  /// Returns a future that completes once the shutdown has been triggered
+  pub fn wait(&self) -> impl Future<Output = ()> + Send + 'static {
+    let mut receiver = self.receiver.clone();
+    async move {
+      while !*receiver.borrow_and_update() {
+        if receiver.changed().await.is_err() {
+          break;
+        }
+      }
+    }
+  }
+}
+
+impl Default for Shutdown {
+  fn default() -> Self {
+    Shutdown::new()
+  }
+}
+
+/// Triggers the shutdown when the process receives a SIGINT (Ctrl-C) or SIGTERM
+pub async fn shutdown_on_signal(shutdown: Shutdown) {
+  #[cfg(unix)]
+  let terminate = async {
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+      Ok(mut signal) => { signal.recv().await; },
+      Err(err) => {
+        warn!("Failed to install the SIGTERM handler: {}", err);
+        futures::future::pending::<()>().await
+      }
+    }
+  };
+  #[cfg(not(unix))]
+  let terminate = futures::future::pending::<()>();
+
+  tokio::select! {
+    result = tokio::signal::ctrl_c() => match result {
+      Ok(_) => info!("Received SIGINT, shutting down"),
+      Err(err) => {
+        warn!("Failed to install the SIGINT handler: {}", err);
+        return;
+      }
+    },
+    _ = terminate => info!("Received SIGTERM, shutting down"),
+    _ = shutdown.wait() => return
+  }
+  shutdown.trigger();
+}
+
+/// Runs the server until it completes. Once the shutdown is triggered, the server has until the drain
+/// timeout to complete any in-flight requests, otherwise an error with the drain timeout exit code is returned.
+pub async fn run_until_drained<F>(server: F, shutdown: &Shutdown, drain_timeout: Duration) -> Result<(), ExitCode>
+  where F: Future<Output = Result<(), ExitCode>> {
+  let shutdown = shutdown.wait();
+  let timeout = async move {
+    shutdown.await;
+    tokio::time::sleep(drain_timeout).await;
+  };
+  tokio::select! {
+    result = server => {
+      info!("Server has shut down");
+      result
+    },
+    _ = timeout => {
+      error!("In-flight requests did not complete within the drain timeout of {:?}", drain_timeout);
+      Err(ExitCode::from(DRAIN_TIMEOUT_EXIT_CODE))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use expectest::prelude::*;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn shutdown_is_shared_between_clones() {
+    let shutdown = Shutdown::new();
+    let clone = shutdown.clone();
+    expect!(tokio::time::timeout(Duration::from_millis(10), shutdown.wait()).await).to(be_err());
+
+    let wait = tokio::spawn(shutdown.wait());
+    clone.trigger();
+    expect!(tokio::time::timeout(Duration::from_secs(1), wait).await).to(be_ok());
+    expect!(tokio::time::timeout(Duration::from_secs(1), shutdown.wait()).await).to(be_ok());
+  }
+
+  #[test]
+  fn is_triggered_is_shared_between_clones() {
+    let shutdown = Shutdown::new();
+    let clone = shutdown.clone();
+    expect!(shutdown.is_triggered()).to(be_false());
+    clone.trigger();
+    expect!(shutdown.is_triggered()).to(be_true());
+  }
+
+  #[tokio::test]
+  async fn run_until_drained_returns_the_server_result() {
+    let shutdown = Shutdown::new();
+    expect!(run_until_drained(async { Ok(()) }, &shutdown, Duration::from_millis(10)).await).to(be_ok());
+  }
+
+  #[tokio::test]
+  async fn run_until_drained_returns_an_error_if_the_drain_times_out() {
+    let shutdown = Shutdown::new();
+    shutdown.trigger();
+    let result = run_until_drained(futures::future::pending(), &shutdown, Duration::from_millis(10)).await;
+    expect!(result).to(be_err().value(ExitCode::from(DRAIN_TIMEOUT_EXIT_CODE)));
+  }
+}
@@ -0,0 +1,240 @@
+//! Functions relating to exporting request traces with OpenTelemetry (OTLP)
+
+use http::{HeaderMap, Request};
+use opentelemetry::{global, KeyValue};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self, Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use pact_matching::Mismatch;
+use pact_models::prelude::v4::*;
+use tower_http::trace::MakeSpan;
+use tracing::{Level, Span, Subscriber};
+use tracing::field::Empty;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::redaction::{self, RedactedHeaders};
+use crate::scoring::MatchScore;
+
+/// Service name the spans are exported with
+const SERVICE_NAME: &str = "pact-stub-server";
+
+/// Maximum number of mismatches to record on a span
+const MAX_RECORDED_MISMATCHES: usize = 10;
+
+fn tracer_provider(endpoint: &str) -> anyhow::Result<TracerProvider> {
+  let exporter = opentelemetry_otlp::new_exporter()
+    .http()
+    .with_endpoint(endpoint)
+    .build_span_exporter()?;
+  Ok(TracerProvider::builder()
+    .with_batch_exporter(exporter, runtime::Tokio)
+    .with_config(trace::config().with_resource(Resource::new(vec![KeyValue::new("service.name", SERVICE_NAME)])))
+    .build())
+}
+
+// Only the spans and events from the stub server are exported
+fn layer_for_tracer<S>(tracer: Tracer) -> impl Layer<S>
+  where S: Subscriber + for<'a> LookupSpan<'a> {
+  tracing_opentelemetry::layer()
+    .with_tracer(tracer)
+    .with_filter(Targets::new().with_target("pact_stub_server", Level::DEBUG))
+}
+
+/// Creates the tracing layer that exports the request spans to the OTLP (HTTP) collector at the endpoint.
+/// The W3C trace context propagator is also installed, so incoming `traceparent` headers are continued.
+pub fn otlp_layer<S>(endpoint: &str) -> anyhow::Result<impl Layer<S>>
+  where S: Subscriber + for<'a> LookupSpan<'a> {
+  let provider = tracer_provider(endpoint)?;
+  let tracer = provider.tracer(SERVICE_NAME);
+  global::set_tracer_provider(provider);
+  global::set_text_map_propagator(TraceContextPropagator::new());
+  Ok(layer_for_tracer(tracer))
+}
+
+/// Flushes any remaining spans and shuts down the exporter
+pub fn shutdown() {
+  global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+  fn get(&self, key: &str) -> Option<&str> {
+    self.0.get(key).and_then(|value| value.to_str().ok())
+  }
+
+  fn keys(&self) -> Vec<&str> {
+    self.0.keys().map(|key| key.as_str()).collect()
+  }
+}
+
+/// Creates the span for each request, continuing any trace context from the request headers
+#[derive(Debug, Clone, Default)]
+pub struct RequestSpan;
+
+impl<B> MakeSpan<B> for RequestSpan {
+  fn make_span(&mut self, request: &Request<B>) -> Span {
+    let span = tracing::debug_span!("request",
+      method = %request.method(),
+      uri = %request.uri(),
+      version = ?request.version(),
+      headers = ?RedactedHeaders(request.headers()),
+      otel.name = format!("{} {}", request.method(), request.uri().path()),
+      otel.kind = "server",
+      pact.consumer = Empty,
+      pact.provider = Empty,
+      pact.interaction = Empty,
+      pact.candidates = Empty,
+      pact.score = Empty,
+      pact.mismatches = Empty
+    );
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(request.headers())));
+    span.set_parent(parent);
+    span
+  }
+}
+
+/// Records the number of candidate interactions on the current request span
+pub fn record_candidates(candidates: usize) {
+  Span::current().record("pact.candidates", candidates);
+}
+
+/// Records the interaction that matched the request on the current request span
+pub fn record_matched_interaction(pact: &V4Pact, interaction: &SynchronousHttp) {
+  let span = Span::current();
+  span.record("pact.consumer", pact.consumer.name.as_str());
+  span.record("pact.provider", pact.provider.name.as_str());
+  span.record("pact.interaction", interaction.description.as_str());
+}
+
+/// Records the score of the interaction that matched the request on the current request span
+pub fn record_score(score: &MatchScore) {
+  Span::current().record("pact.score", score.total());
+}
+
+/// Records a summary of the mismatches from the candidate interactions on the current request span
+pub fn record_mismatches(mismatches: &[(String, Vec<Mismatch>)]) {
+  if !mismatches.is_empty() {
+    Span::current().record("pact.mismatches", mismatch_summary(mismatches).as_str());
+  }
+}
+
+fn mismatch_summary(mismatches: &[(String, Vec<Mismatch>)]) -> String {
+  mismatches.iter()
+    .flat_map(|(description, mismatches)| mismatches.iter()
+      .map(move |mismatch| format!("'{}': {}", description, redaction::redaction().mismatch_description(mismatch))))
+    .take(MAX_RECORDED_MISMATCHES)
+    .collect::<Vec<_>>()
+    .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::convert::Infallible;
+  use std::sync::{Arc, Mutex};
+
+  use expectest::prelude::*;
+  use hyper::{Body, Response, Server};
+  use hyper::service::{make_service_fn, service_fn};
+  use pact_matching::Mismatch;
+  use pact_models::prelude::*;
+  use pact_models::prelude::v4::*;
+  use tracing_subscriber::layer::SubscriberExt;
+
+  use super::*;
+
+  // Path and body of the requests received by the collector
+  type Received = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+  // Starts a stand-in for an OTLP collector, returning the endpoint and the requests it receives
+  fn start_collector() -> (String, Received) {
+    let received = Arc::new(Mutex::new(vec![]));
+    let received_clone = received.clone();
+    let server = Server::bind(&([127, 0, 0, 1], 0).into())
+      .serve(make_service_fn(move |_| {
+        let received = received_clone.clone();
+        async move {
+          Ok::<_, Infallible>(service_fn(move |request: hyper::Request<Body>| {
+            let received = received.clone();
+            async move {
+              let path = request.uri().path().to_string();
+              let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+              received.lock().unwrap().push((path, body.to_vec()));
+              Ok::<_, Infallible>(Response::new(Body::empty()))
+            }
+          }))
+        }
+      }));
+    let endpoint = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    (endpoint, received)
+  }
+
+  #[test]
+  fn mismatch_summary_includes_the_interaction_descriptions() {
+    let mismatches = vec![
+      ("get an order".to_string(), vec![
+        Mismatch::MethodMismatch { expected: "GET".to_string(), actual: "POST".to_string() }
+      ]),
+      ("get all orders".to_string(), vec![
+        Mismatch::QueryMismatch {
+          parameter: "page".to_string(),
+          expected: "1".to_string(),
+          actual: "".to_string(),
+          mismatch: "Expected query parameter 'page'".to_string()
+        }
+      ])
+    ];
+    expect!(mismatch_summary(&mismatches)).to(be_equal_to(
+      "'get an order': expected GET but was POST; 'get all orders': Expected query parameter 'page'"));
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn exports_the_request_span_to_the_collector_continuing_the_trace_context() {
+    let (endpoint, received) = start_collector();
+    let provider = tracer_provider(&endpoint).unwrap();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let subscriber = tracing_subscriber::registry().with(layer_for_tracer(provider.tracer(SERVICE_NAME)));
+
+    let pact = V4Pact {
+      consumer: Consumer { name: "web".to_string() },
+      provider: Provider { name: "orders".to_string() },
+      .. V4Pact::default()
+    };
+    let interaction = SynchronousHttp { description: "get an order".to_string(), .. SynchronousHttp::default() };
+    tracing::subscriber::with_default(subscriber, || {
+      let request = http::Request::get("/orders/1")
+        .header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+        .body(())
+        .unwrap();
+      let span = RequestSpan.make_span(&request);
+      let _guard = span.enter();
+      record_candidates(2);
+      record_matched_interaction(&pact, &interaction);
+    });
+
+    tokio::task::spawn_blocking(move || {
+      for result in provider.force_flush() {
+        result.unwrap();
+      }
+    }).await.unwrap();
+
+    let received = received.lock().unwrap();
+    expect!(received.len()).to(be_equal_to(1));
+    let (path, body) = &received[0];
+    expect!(path.as_str()).to(be_equal_to("/v1/traces"));
+    let trace_id = [0x4b, 0xf9, 0x2f, 0x35, 0x77, 0xb3, 0x4d, 0xa6, 0xa3, 0xce, 0x92, 0x9d, 0x0e, 0x0e, 0x47, 0x36];
+    expect!(body.windows(trace_id.len()).any(|window| window == trace_id)).to(be_true());
+    let body = String::from_utf8_lossy(body);
+    expect!(body.contains("GET /orders/1")).to(be_true());
+    expect!(body.contains("pact.interaction")).to(be_true());
+    expect!(body.contains("get an order")).to(be_true());
+    expect!(body.contains("pact.candidates")).to(be_true());
+  }
+}
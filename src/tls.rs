@@ -0,0 +1,128 @@
+//! Functions relating to serving the stub server over TLS
+
+use std::fs::File;
+use std::io::BufReader;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use futures::future::ready;
+use futures::stream::{self, Stream, StreamExt};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, warn};
+
+use crate::server::HttpProtocol;
+
+/// Maximum number of TLS handshakes that will be processed concurrently
+const MAX_CONCURRENT_HANDSHAKES: usize = 64;
+
+fn load_certificates(path: &str) -> anyhow::Result<Vec<Certificate>> {
+  let file = File::open(path).with_context(|| format!("Failed to open certificate file '{}'", path))?;
+  let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+    .with_context(|| format!("Failed to parse certificate file '{}'", path))?;
+  if certs.is_empty() {
+    Err(anyhow!("No certificates were found in '{}'", path))
+  } else {
+    Ok(certs.into_iter().map(Certificate).collect())
+  }
+}
+
+fn load_private_key(path: &str) -> anyhow::Result<PrivateKey> {
+  let file = File::open(path).with_context(|| format!("Failed to open private key file '{}'", path))?;
+  let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+    .with_context(|| format!("Failed to parse private key file '{}'", path))?;
+  items.into_iter()
+    .find_map(|item| match item {
+      rustls_pemfile::Item::RSAKey(key) => Some(PrivateKey(key)),
+      rustls_pemfile::Item::PKCS8Key(key) => Some(PrivateKey(key)),
+      rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+      _ => None
+    })
+    .ok_or_else(|| anyhow!("No private key was found in '{}'", path))
+}
+
+/// ALPN protocols to advertise for the HTTP protocol setting
+pub fn alpn_protocols(protocol: HttpProtocol) -> Vec<Vec<u8>> {
+  match protocol {
+    HttpProtocol::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+    HttpProtocol::Http1Only => vec![b"http/1.1".to_vec()],
+    HttpProtocol::Http2Only => vec![b"h2".to_vec()]
+  }
+}
+
+/// Loads the TLS configuration from the PEM encoded certificate chain and private key files
+pub fn load_tls_config(cert_file: &str, key_file: &str, protocol: HttpProtocol) -> anyhow::Result<Arc<ServerConfig>> {
+  let certs = load_certificates(cert_file)?;
+  let key = load_private_key(key_file)?;
+  let mut config = ServerConfig::builder()
+    .with_safe_defaults()
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+  config.alpn_protocols = alpn_protocols(protocol);
+  Ok(Arc::new(config))
+}
+
+/// Wraps the incoming connections with a TLS handshake. Connections that fail the handshake are dropped.
+pub fn tls_connections(
+  mut incoming: AddrIncoming,
+  config: Arc<ServerConfig>
+) -> impl Stream<Item = Result<TlsStream<AddrStream>, std::io::Error>> {
+  let acceptor = TlsAcceptor::from(config);
+  stream::poll_fn(move |cx| Pin::new(&mut incoming).poll_accept(cx))
+    .filter_map(|conn| ready(conn.map_err(|err| warn!("Failed to accept connection: {}", err)).ok()))
+    .map(move |conn| {
+      let remote_addr = conn.remote_addr();
+      let acceptor = acceptor.clone();
+      async move {
+        let result = acceptor.accept(conn).await;
+        (remote_addr, result)
+      }
+    })
+    .buffer_unordered(MAX_CONCURRENT_HANDSHAKES)
+    .filter_map(|(remote_addr, result)| ready(match result {
+      Ok(stream) => {
+        debug!("Completed TLS handshake with {} (ALPN protocol {:?})", remote_addr,
+          stream.get_ref().1.alpn_protocol().map(String::from_utf8_lossy));
+        Some(Ok(stream))
+      },
+      Err(err) => {
+        warn!("TLS handshake with {} failed: {}", remote_addr, err);
+        None
+      }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+
+  use super::*;
+
+  #[test]
+  fn alpn_protocols_for_protocol_setting() {
+    expect!(alpn_protocols(HttpProtocol::Auto)).to(be_equal_to(vec![b"h2".to_vec(), b"http/1.1".to_vec()]));
+    expect!(alpn_protocols(HttpProtocol::Http1Only)).to(be_equal_to(vec![b"http/1.1".to_vec()]));
+    expect!(alpn_protocols(HttpProtocol::Http2Only)).to(be_equal_to(vec![b"h2".to_vec()]));
+  }
+
+  #[test]
+  fn load_tls_config_from_pem_files() {
+    let dir = env!("CARGO_MANIFEST_DIR");
+    let config = load_tls_config(&format!("{}/tests/tls/cert.pem", dir), &format!("{}/tests/tls/key.pem", dir),
+      HttpProtocol::Auto);
+    expect!(config.map(|c| c.alpn_protocols.clone())).to(be_ok().value(vec![b"h2".to_vec(), b"http/1.1".to_vec()]));
+  }
+
+  #[test]
+  fn load_tls_config_with_invalid_files() {
+    let dir = env!("CARGO_MANIFEST_DIR");
+    expect!(load_tls_config("does-not-exist.pem", &format!("{}/tests/tls/key.pem", dir), HttpProtocol::Auto))
+      .to(be_err());
+    expect!(load_tls_config(&format!("{}/tests/tls/cert.pem", dir), &format!("{}/tests/tls/cert.pem", dir), HttpProtocol::Auto))
+      .to(be_err());
+  }
+}